@@ -0,0 +1,36 @@
+#!/usr/bin/env bash
+
+# Writes a device's auth secret into its nvs partition, once, before or after flashing the
+# firmware. The same image then serves every device.
+#
+#   scripts/provision.sh <secret> [port]
+#
+# Replaces the whole nvs partition, so any settings stored on the device are reset to their
+# defaults. Needs esp-idf-nvs-partition-gen (pip) and espflash.
+
+set -e
+
+SECRET="$1"
+PORT="$2"
+if [ ${#SECRET} -lt 16 ]; then
+    echo "Usage: $0 <secret of at least 16 characters> [port]"
+    exit 1
+fi
+
+# Offset and size of the nvs partition in part.csv, the namespace of settings::SETTINGS_NAMESPACE.
+NVS_OFFSET=0x9000
+NVS_SIZE=0x5000
+NAMESPACE=atcontroller
+
+WORK=$(mktemp -d)
+trap 'rm -rf "$WORK"' EXIT
+
+# Stored as a blob, as settings::NvsStorage reads it.
+cat >"$WORK/nvs.csv" <<CSV
+key,type,encoding,value
+$NAMESPACE,namespace,,
+auth_secret,data,hex2bin,$(printf '%s' "$SECRET" | xxd -p | tr -d '\n')
+CSV
+
+python3 -m esp_idf_nvs_partition_gen generate "$WORK/nvs.csv" "$WORK/nvs.bin" $NVS_SIZE
+espflash write-bin ${PORT:+--port "$PORT"} $NVS_OFFSET "$WORK/nvs.bin"
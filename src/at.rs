@@ -1,44 +1,93 @@
 use std::sync::{Arc, Mutex};
 
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::gpio::{
     Gpio10, Gpio11, Gpio3, Gpio5, Gpio6, Gpio8, Gpio9, Output, PinDriver,
 };
-use esp_idf_svc::hal::reset::restart;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::uart::{AsyncUartDriver, UartDriver};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::units::Hertz;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::sys::EspError;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::{hal::peripherals::Peripherals, hal::uart, hal::uart::config};
 use log::*;
 
 use crate::atcommands::AtCommand;
 use crate::atcommands::Commander;
-use crate::atmodule::{ATMoudle, MouduleState, PublishState};
-use crate::atres::{ATResponse, ResponseHandler};
-use crate::controller::{ControllerState, RelayController};
+use crate::atmodule::{ATMoudle, MouduleState};
+use crate::atres::{ATResponse, ResponseHandler, ResponseHandlerResponse};
+use crate::clock;
+use crate::command::{self, ControlAction, ControlRequest};
+use crate::config::{ConfigOutcome, ConfigRequest};
+use crate::constants::AT_BOOT_DELAY;
+use crate::controller::{
+    self, CommandSource, ControllerState, RelayController, RelayPins, RelayRecord,
+};
 use crate::emon;
+use crate::framer::LineFramer;
+use crate::mqtt::{MqttConfig, MqttConfigError};
+use crate::network::NetworkStatus;
+use crate::offline::{Sample, SharedBacklog};
+use crate::protection::Protection;
+use crate::publish::{PublishResult, QoS};
+use crate::retry::Escalation;
+use crate::schedule::Scheduler;
+use crate::settings::{Settings, SettingsError, SharedStorage};
 use crate::subscribe::NextControlCommand;
+use crate::topics::Topics;
+use crate::transaction::Event;
+use crate::transport::AtTransport;
 
+#[cfg(target_os = "espidf")]
 const BAUDRATE: u32 = 115200;
+#[cfg(target_os = "espidf")]
 const PZEMBAUDRATE: u32 = 9600;
 
+/// The ESP32 UART the modem and the PZEM are connected to.
+#[cfg(target_os = "espidf")]
+pub type Uart<'a> = AsyncUartDriver<'a, UartDriver<'a>>;
+
 pub enum AtReplyTopic {
     START,
     STOP,
     STATUS,
     POWER,
+    HEALTH,
+    CONFIG,
+    BACKLOG,
+    FAULT,
+    SCHEDULE,
+    REJECTED,
 }
 
 impl AtReplyTopic {
-    pub fn topic(&self) -> &'static str {
+    pub fn name(&self) -> &'static str {
         match self {
-            AtReplyTopic::START => "RTONE/start",
-            AtReplyTopic::STOP => "RTONE/stop",
-            AtReplyTopic::STATUS => "RTONE/status",
-            AtReplyTopic::POWER => "RTONE/Power",
+            AtReplyTopic::START => "start",
+            AtReplyTopic::STOP => "stop",
+            AtReplyTopic::STATUS => "status",
+            AtReplyTopic::POWER => "Power",
+            AtReplyTopic::HEALTH => "health",
+            AtReplyTopic::CONFIG => "config",
+            AtReplyTopic::BACKLOG => "backlog",
+            AtReplyTopic::FAULT => "fault",
+            AtReplyTopic::SCHEDULE => "schedule",
+            AtReplyTopic::REJECTED => "rejected",
         }
     }
+
+    /// The reply topic rendered for this device, `RTONE/status` with the default template.
+    pub fn topic(&self, topics: &Topics) -> String {
+        topics.publish(self.name())
+    }
 }
 
+#[cfg(target_os = "espidf")]
 fn init_uart<'a>() -> Result<
     (
         AsyncUartDriver<'a, uart::UartDriver<'a>>,
@@ -95,100 +144,227 @@ fn init_uart<'a>() -> Result<
     Ok((async_uart_driver?, start, stop, serial?, at_restart))
 }
 
-pub struct AT<'a> {
-    uart: AsyncUartDriver<'a, UartDriver<'a>>,
+/// The AT/MQTT stack, talking to the modem over `T` and to the PZEM over `S`.
+pub struct AT<'a, T: AtTransport, S: AtTransport> {
+    uart: T,
     module: Arc<Mutex<ATMoudle>>,
     relaycontroller: Arc<Mutex<RelayController<'a>>>,
-    pzem: Arc<Mutex<emon::Pzem<'a>>>,
+    /// Held across the PZEM round trip, so an async mutex.
+    pzem: Arc<AsyncMutex<NoopRawMutex, emon::Pzem<S>>>,
+    protection: Arc<Mutex<Protection>>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    storage: SharedStorage,
+    backlog: SharedBacklog,
 }
 
-#[derive(Debug, Clone)]
-pub struct Messages {
-    pub message: String,
-    pub length: usize,
+#[cfg(target_os = "espidf")]
+impl<'a> AT<'a, Uart<'a>, Uart<'a>> {
+    pub fn new(storage: SharedStorage, backlog: SharedBacklog) -> Self {
+        let (uart, start, stop, serial, at_restart) = init_uart().unwrap();
+        let pins = RelayPins {
+            start: Box::new(start),
+            stop: Box::new(stop),
+            at_restart: Box::new(at_restart),
+        };
+        AT::with_transport(uart, pins, serial, storage, backlog)
+    }
 }
 
-impl AT<'_> {
-    pub fn new() -> Self {
-        let (uart, start, stop, serial, at_restart) = init_uart().unwrap();
-        let module = Arc::new(Mutex::new(ATMoudle::new()));
-        let pzem = emon::Pzem::new(serial).unwrap();
-        let relaycontroller = Arc::new(Mutex::new(RelayController::new(
+impl<'d, T: AtTransport, S: AtTransport> AT<'d, T, S> {
+    pub fn with_transport(
+        uart: T,
+        pins: RelayPins<'d>,
+        serial: S,
+        storage: SharedStorage,
+        backlog: SharedBacklog,
+    ) -> Self {
+        let (settings, record, scheduler, planned) = {
+            let mut storage = storage.lock().unwrap();
+            let planned = controller::take_planned_reboot(&mut *storage);
+            (
+                Settings::load(&*storage),
+                RelayRecord::load(&*storage),
+                Scheduler::load(&*storage),
+                planned,
+            )
+        };
+        let pzem = emon::Pzem::new(serial, settings.pzem_address).unwrap();
+        let mut relaycontroller = RelayController::new(
             ControllerState::OFF,
             None,
-            None,
-            start,
-            stop,
-            at_restart,
-        )));
-        let pzem = Arc::new(Mutex::new(pzem));
+            record.and_then(|x| x.updated_at),
+            settings.relay_change_delay,
+            settings.max_run,
+            pins,
+        );
+
+        // Applied before the MQTT bring-up, so the load does not wait for the broker.
+        let target = settings.relay_restore.target(record.as_ref(), planned);
+        info!(
+            "Relay restore: last {:?}, planned reboot {}, policy {}, starting {:?}",
+            record.map(|x| x.state),
+            planned,
+            settings.relay_restore.as_str(),
+            target
+        );
+        match relaycontroller.set_state(target) {
+            Ok(_) => {
+                relaycontroller.source = Some(CommandSource::Restore);
+                if target == ControllerState::ON {
+                    relaycontroller.schedule_verification(settings.verify_delay);
+                    let until = record
+                        .filter(|x| x.state == ControllerState::ON)
+                        .and_then(|x| x.run_until);
+                    if let Some(until) = until {
+                        relaycontroller.restore_deadline(until);
+                    }
+                }
+                if record.map_or(ControllerState::OFF, |x| x.state) != target {
+                    if let Some(record) = relaycontroller.record() {
+                        if let Err(e) = record.save(&mut *storage.lock().unwrap()) {
+                            info!("Error saving relay state: {}", e);
+                        }
+                    }
+                }
+            }
+            Err(e) => info!("Error restoring relay: {}", e),
+        }
+        let relaycontroller = Arc::new(Mutex::new(relaycontroller));
+        let mut module = ATMoudle::new();
+        module.publish_queue.overflow = settings.publish_overflow;
+        module.retry.policy = settings.retry.clone();
+        module
+            .router
+            .set_secret(settings.auth_secret.as_deref(), &storage);
+        module.settings = settings;
+        let module = Arc::new(Mutex::new(module));
+        let pzem = Arc::new(AsyncMutex::new(pzem));
         AT {
             uart,
             module,
             relaycontroller,
             pzem,
+            protection: Arc::new(Mutex::new(Protection::new())),
+            scheduler: Arc::new(Mutex::new(scheduler)),
+            storage,
+            backlog,
         }
     }
 
-    pub async fn publish<'a>(&self, message: &str, topic: AtReplyTopic) {
-        let command = format!(
-            "AT+QMTPUBEX=0,1,0,0,\"{}\",{}\r\n",
-            topic.topic(),
-            message.len()
-        );
+    /// Queues `message` for `topic` and sends it as soon as nothing else is in flight.
+    pub async fn publish<'a>(&self, message: &str, topic: AtReplyTopic, qos: QoS, retain: bool) {
+        self.publish_payload(message.as_bytes().to_vec(), topic, qos, retain)
+            .await;
+    }
 
-        info!("Command: {:?}", command);
+    /// Like [`AT::publish`], for payloads that need not be text.
+    pub async fn publish_payload<'a>(
+        &self,
+        payload: Vec<u8>,
+        topic: AtReplyTopic,
+        qos: QoS,
+        retain: bool,
+    ) {
+        self.publish_named(payload, topic.name(), qos, retain).await;
+    }
 
-        if let Ok(mut module) = self.module.try_lock() {
-            match module.publish_state {
-                PublishState::INIT | PublishState::PUBLISHED => {
-                    module.set_publish_message(Some(message.to_string()));
-                    module.set_publish_state(PublishState::PUBLISHING, None);
-                    if self.uart.write(command.as_bytes()).await.is_err() {
-                        if self.uart.wait_tx_done().await.is_ok() {
-                            info!("Message sent successfully");
-                        }
-                    }
-                    return;
-                }
-                PublishState::PUBLISHING => {
-                    info!("Message already publishing");
-                    return;
-                }
-                _ => {
-                    info!(
-                        "Message not published current state: {:?}",
-                        module.publish_state
-                    );
-                    return;
-                }
+    /// Like [`AT::publish_payload`], for a reply topic `name` that is not fixed, such as
+    /// `cmd/<id>`.
+    pub async fn publish_named<'a>(&self, payload: Vec<u8>, name: &str, qos: QoS, retain: bool) {
+        {
+            let mut module = self.module.lock().unwrap();
+            if !matches!(module.state, MouduleState::CONNECTED) {
+                info!("Not connected, skipping publish");
+                return;
             }
-        } else {
-            info!("Module is locked, skipping publish");
+
+            let topic = module.topics().publish(name);
+            module.publish_queue.push(topic, payload, qos, retain);
         }
+        self.drain_publish_queue().await;
     }
 
-    pub async fn send_serial_message(&self, message: String) {
-        if let Ok(mut module) = self.module.try_lock() {
+    /// Starts `AT+QMTPUBEX` for the next queued message, once connected and idle. With nothing
+    /// queued, the next batch of the offline backlog goes out.
+    async fn drain_publish_queue(&self) {
+        let command = {
+            let mut module = self.module.lock().unwrap();
+            if !matches!(module.state, MouduleState::CONNECTED)
+                || module.transactions.in_flight().is_some()
+            {
+                return;
+            }
+
+            let mut replaying = false;
+            if module.publish_queue.is_empty() && module.publish_queue.in_flight().is_none() {
+                let batch = self.backlog.lock().unwrap().next_batch();
+                if !batch.is_empty() {
+                    replaying = true;
+                    info!("Replaying {} backlog samples", batch.len());
+                    let topic = AtReplyTopic::BACKLOG.topic(&module.topics());
+                    let payload = module
+                        .settings
+                        .measurement_format
+                        .encoder()
+                        .encode_batch(&batch, &module.device_id());
+                    module
+                        .publish_queue
+                        .push(topic, payload, QoS::AtLeastOnce, false);
+                }
+            }
+
+            let command = match module.publish_queue.start() {
+                Some(message) => {
+                    if replaying {
+                        self.backlog.lock().unwrap().set_pending(message.msg_id);
+                    }
+                    message.command()
+                }
+                None => return,
+            };
+            module.begin(AtCommand::PUBLISH);
             module.command = Commander {
                 command: AtCommand::PUBLISH,
             };
-            let _ = self.uart.write(message.as_bytes()).await;
-        } else {
-            info!("Module is locked, skipping send_serial");
+            command
+        };
+
+        info!("Command: {:?}", command);
+        let _ = self.uart.write(command.as_bytes()).await;
+    }
+
+    pub async fn send_serial_message(&self, message: &[u8]) {
+        match self.module.try_lock() {
+            Ok(mut module) => {
+                module.command = Commander {
+                    command: AtCommand::PUBLISH,
+                };
+            }
+            Err(_) => {
+                info!("Module is locked, skipping send_serial");
+                return;
+            }
         }
+        let _ = self.uart.write(message).await;
     }
 
     pub async fn send_serial<'a>(&self, command: Commander) {
-        if let Ok(mut module) = self.module.try_lock() {
-            module.command = command;
-            let _ = self.uart.write(&module.command.command.as_bytes()).await;
-        } else {
-            info!("Module is locked, skipping send_serial");
-        }
+        let line = match self.module.try_lock() {
+            Ok(mut module) => {
+                module.begin(command.command);
+                module.command = command;
+                module.render(module.command.command)
+            }
+            Err(_) => {
+                info!("Module is locked, skipping send_serial");
+                return;
+            }
+        };
+        let _ = self.uart.write(line.as_bytes()).await;
     }
 
     pub async fn read_serail<'a>(&self) {
+        let mut framer = LineFramer::new();
         loop {
             let mut buffer = [0u8; 256];
             if let Ok(len) = self.uart.read(&mut buffer).await {
@@ -199,106 +375,227 @@ impl AT<'_> {
                     "Bytes {:?}",
                     std::str::from_utf8(&buffer[..len]).unwrap_or("Error Reading bytes")
                 );
-                let response = ATResponse::from_bytes(&buffer[..len]);
-                let command = self.module.lock().unwrap().command.command.clone();
-                let next_atcommands = ResponseHandler::new(response).handle_response(command);
-                info!("Next Command {:?}", next_atcommands);
-
-                match next_atcommands.control_command {
-                    NextControlCommand::POWERON => {
-                        let mut relaycontroller = self.relaycontroller.lock().unwrap();
-                        let _ = relaycontroller.start();
-                        let status = relaycontroller.status();
-                        self.sendstatus(Some(String::from(status)), AtReplyTopic::START)
-                            .await;
-                    }
-                    NextControlCommand::POWEROFF => {
-                        let mut relaycontroller = self.relaycontroller.lock().unwrap();
-                        let _ = relaycontroller.stop();
-                        let status = relaycontroller.status();
-                        self.sendstatus(Some(String::from(status)), AtReplyTopic::STOP)
-                            .await;
-                    }
-                    NextControlCommand::STATUSUPDATE => {
-                        let relaycontroller = self.relaycontroller.lock().unwrap();
-                        let status = relaycontroller.status();
-                        self.sendstatus(Some(status), AtReplyTopic::STATUS).await;
-                    }
-                    _ => {}
-                }
+                framer.push(&buffer[..len]);
 
-                match next_atcommands.at_command {
-                    AtCommand::NOOP => {
-                        info!("NOOP");
-                    }
-                    AtCommand::PUBLISH => {
-                        info!("Publishing message");
-                        let message = self.module.lock().unwrap().publish_message.clone();
-                        self.send_serial_message(message.unwrap()).await;
-                    }
-                    AtCommand::PUBLISHSUCCESS => {
-                        info!("Publishing message success");
-                        self.module
-                            .lock()
-                            .unwrap()
-                            .set_publish_state(PublishState::PUBLISHED, None);
-                    }
-                    _ => {
-                        if next_atcommands.at_command == AtCommand::QMTSUBEnd {
-                            self.module
-                                .lock()
-                                .unwrap()
-                                .set_state(MouduleState::CONNECTED);
+                while let Some(frame) = framer.next_frame() {
+                    let event = self.module.lock().unwrap().transactions.feed(frame);
+                    let mut refreshed = false;
+                    if let Some(event) = event {
+                        let next_atcommands = match event {
+                            Event::Completed(command, result) => {
+                                let failed = result.is_err();
+                                if !failed {
+                                    self.module.lock().unwrap().retry.on_success(command);
+                                }
+                                let mut next_atcommands =
+                                    ResponseHandler::handle_result(command, result);
+                                let mut module = self.module.lock().unwrap();
+                                refreshed =
+                                    module.refresh_next(command, &mut next_atcommands.at_command);
+                                module.reconnect_next(
+                                    command,
+                                    failed,
+                                    &mut next_atcommands.at_command,
+                                );
+                                // Connected once the broker acknowledged the subscription.
+                                if command == AtCommand::QMTSUB
+                                    && !failed
+                                    && next_atcommands.at_command != AtCommand::QMTSUB
+                                {
+                                    module.set_state(MouduleState::CONNECTED);
+                                }
+                                let escalation = module.bring_up_next(
+                                    command,
+                                    failed,
+                                    &mut next_atcommands.at_command,
+                                );
+                                drop(module);
+                                if let Some(escalation) = escalation {
+                                    self.escalate(escalation).await;
+                                }
+                                next_atcommands
+                            }
+                            Event::Prompt(_) => ResponseHandlerResponse::at(AtCommand::PUBLISH),
+                        };
+                        self.handle_next(next_atcommands).await;
+                        if refreshed {
+                            self.publish_health().await;
                         }
+                    }
 
-                        self.send_serial(Commander {
-                            command: next_atcommands.at_command,
-                        })
-                        .await;
+                    loop {
+                        let urc = self.module.lock().unwrap().transactions.next_urc();
+                        match urc {
+                            Some(urc) => {
+                                let response = ATResponse::from_bytes(urc.as_bytes());
+                                let (topics, router) = {
+                                    let module = self.module.lock().unwrap();
+                                    (module.topics(), module.router.clone())
+                                };
+                                let next_atcommands = ResponseHandler::new(response)
+                                    .handle_response(&topics, &router);
+                                self.handle_next(next_atcommands).await;
+                            }
+                            None => break,
+                        }
                     }
                 }
             }
         }
     }
 
-    pub async fn sendstatus<'a>(&self, message: Option<String>, topic: AtReplyTopic) {
-        info!("Sending status message {:?}", message);
-        let mut buffer = emon::Measurement::default();
-        let current_publish_state = self.module.try_lock();
+    async fn handle_next(&self, next_atcommands: ResponseHandlerResponse) {
+        info!("Next Command {:?}", next_atcommands);
 
-        if current_publish_state.is_err() {
-            info!("Module is locked");
-            return;
+        if let Some(status) = next_atcommands.status.clone() {
+            self.module.lock().unwrap().update_status(status);
         }
-        let current_publish_state = current_publish_state.unwrap().publish_state.clone();
-        log::info!("Current publish state: {:?}", current_publish_state);
 
-        match current_publish_state {
-            PublishState::PUBLISHING => {
-                info!("Message already publishing");
-                return;
+        if let Some(result) = next_atcommands.published {
+            let sent = self.module.lock().unwrap().publish_queue.on_result(result);
+            if let Some(msg_id) = sent {
+                self.backlog.lock().unwrap().commit(msg_id);
             }
+        }
 
+        if let Some(reason) = next_atcommands.disconnect {
+            info!("MQTT connection lost: {}", reason);
+            self.module.lock().unwrap().on_disconnect(reason);
+            return;
+        }
+
+        match next_atcommands.control_command {
+            NextControlCommand::POWERON => {
+                let status = self.run_relay(None);
+                self.sendstatus(Some(status), AtReplyTopic::START).await;
+            }
+            NextControlCommand::POWERONFOR(minutes) => {
+                let status = self.run_relay(Some(minutes));
+                self.sendstatus(Some(status), AtReplyTopic::START).await;
+            }
+            NextControlCommand::POWERONREJECTED => {
+                let reason = next_atcommands.payload.clone().unwrap_or_default();
+                info!("Rejecting start: {}", reason);
+                let reply = format!("ERROR:{}", reason);
+                self.sendstatus(Some(reply), AtReplyTopic::START).await;
+            }
+            NextControlCommand::POWEROFF => {
+                let status = self.set_relay(ControllerState::OFF, CommandSource::Mqtt);
+                self.sendstatus(Some(status), AtReplyTopic::STOP).await;
+            }
+            NextControlCommand::STATUSUPDATE => {
+                let status = self.relaycontroller.lock().unwrap().status();
+                self.sendstatus(Some(status), AtReplyTopic::STATUS).await;
+            }
+            NextControlCommand::CONFIG => {
+                let payload = next_atcommands.payload.clone().unwrap_or_default();
+                self.apply_config(&payload).await;
+            }
+            NextControlCommand::COMMAND => {
+                let payload = next_atcommands.payload.clone().unwrap_or_default();
+                self.handle_command(&payload).await;
+            }
+            NextControlCommand::REJECTED => {
+                let payload = next_atcommands.payload.clone().unwrap_or_default();
+                self.publish(&payload, AtReplyTopic::REJECTED, QoS::AtLeastOnce, false)
+                    .await;
+            }
+            NextControlCommand::SCHEDULEADD => {
+                let payload = next_atcommands.payload.clone().unwrap_or_default();
+                let reply = self.schedule_add(&payload);
+                self.sendstatus(Some(reply), AtReplyTopic::SCHEDULE).await;
+            }
+            NextControlCommand::SCHEDULELIST => {
+                let reply = self.schedule_list();
+                self.sendstatus(Some(reply), AtReplyTopic::SCHEDULE).await;
+            }
+            NextControlCommand::SCHEDULEDELETE => {
+                let payload = next_atcommands.payload.clone().unwrap_or_default();
+                let reply = self.schedule_delete(&payload);
+                self.sendstatus(Some(reply), AtReplyTopic::SCHEDULE).await;
+            }
             _ => {}
         }
 
+        match next_atcommands.at_command {
+            AtCommand::NOOP => {
+                info!("NOOP");
+            }
+            AtCommand::PUBLISH => {
+                info!("Publishing message");
+                let payload = self
+                    .module
+                    .lock()
+                    .unwrap()
+                    .publish_queue
+                    .in_flight()
+                    .map(|x| x.payload.clone());
+                match payload {
+                    Some(payload) => self.send_serial_message(&payload).await,
+                    None => info!("Prompt without a message in flight"),
+                }
+            }
+            AtCommand::PUBLISHSUCCESS => {
+                info!("Publishing message success");
+            }
+            _ => {
+                self.send_serial(Commander {
+                    command: next_atcommands.at_command,
+                })
+                .await;
+            }
+        }
+
+        self.drain_publish_queue().await;
+    }
+
+    /// One PZEM reading, holding the sensor only for its round trip.
+    async fn read_pzem(
+        &self,
+        buffer: &mut emon::Measurement,
+    ) -> Result<(), emon::Error<emon::MyWriteError, emon::MyReadError>> {
+        self.pzem.lock().await.read(buffer).await
+    }
+
+    pub async fn sendstatus<'a>(&self, message: Option<String>, topic: AtReplyTopic) {
+        info!("Sending status message {:?}", message);
+        let mut buffer = emon::Measurement::default();
+
         match message {
             Some(message) => {
-                self.publish(&message, topic).await;
+                self.publish(&message, topic, QoS::AtLeastOnce, false).await;
                 return;
             }
-            None => match self.pzem.lock().unwrap().read(&mut buffer).await {
+            None => match self.read_pzem(&mut buffer).await {
                 Ok(_) => {
-                    let message = format!(
-                        "{},{},{},{},{},{}",
-                        buffer.voltage,
-                        buffer.current,
-                        buffer.power,
-                        buffer.energy,
-                        buffer.frequency,
-                        buffer.pf
-                    );
-                    self.publish(&message, AtReplyTopic::POWER).await;
+                    let (sample, connected, payload) = {
+                        let mut module = self.module.lock().unwrap();
+                        let sample = Sample::now(module.next_sequence(), buffer);
+                        let payload = module
+                            .settings
+                            .measurement_format
+                            .encoder()
+                            .encode(&sample, &module.device_id());
+                        let connected = matches!(module.state, MouduleState::CONNECTED);
+                        (sample, connected, payload)
+                    };
+
+                    let queued = {
+                        let mut backlog = self.backlog.lock().unwrap();
+                        // Queued behind the backlog so the readings still go out in order.
+                        let queued = !connected || !backlog.is_empty();
+                        if queued {
+                            backlog.push(sample);
+                        }
+                        queued
+                    };
+                    if queued {
+                        self.drain_publish_queue().await;
+                        return;
+                    }
+
+                    self.publish_payload(payload, AtReplyTopic::POWER, QoS::AtLeastOnce, false)
+                        .await;
                 }
                 Err(e) => {
                     info!("Error reading from PZEM {:?}", e);
@@ -307,7 +604,7 @@ impl AT<'_> {
         }
     }
 
-    pub async fn check_at<'a>(&self) -> Result<bool, EspError> {
+    pub async fn check_at<'a>(&self) -> Result<bool, T::Error> {
         let mut started = false;
         while !started {
             info!("Checking AT");
@@ -324,20 +621,482 @@ impl AT<'_> {
         Ok(started)
     }
 
+    /// Retries, re-sequences or resets the module when the command in flight got no answer.
+    pub async fn check_timeouts<'a>(&self) {
+        if self.module.lock().unwrap().retry.take_reset() {
+            self.reset_module();
+            return;
+        }
+
+        let due = self.module.lock().unwrap().retry.due();
+        if let Some(command) = due {
+            info!("Retrying {:?}", command);
+            self.send_serial(Commander { command }).await;
+            return;
+        }
+
+        let expired = self.module.lock().unwrap().transactions.expired();
+        let command = match expired {
+            Some(command) => command,
+            None => return,
+        };
+
+        if command == AtCommand::PUBLISH {
+            info!("Publish timed out");
+            self.module
+                .lock()
+                .unwrap()
+                .publish_queue
+                .on_result(PublishResult::Failed);
+            self.drain_publish_queue().await;
+            return;
+        }
+
+        let escalation = self.module.lock().unwrap().retry.on_timeout(command);
+        self.escalate(escalation).await;
+    }
+
+    async fn escalate(&self, escalation: Escalation) {
+        match escalation {
+            Escalation::Retry(_) => {}
+            Escalation::RestartSequence => {
+                self.module.lock().unwrap().set_state(MouduleState::STARTED);
+                self.send_serial(Commander::at()).await;
+            }
+            Escalation::HardwareReset => self.reset_module(),
+        }
+    }
+
+    /// Resets the module through its power key and starts over once it has booted. Tried again
+    /// on the next tick while the relay controller is busy.
+    fn reset_module(&self) {
+        match self.relaycontroller.try_lock() {
+            Ok(mut controller) => {
+                if let Err(e) = controller.at_module_restart() {
+                    info!("Error resetting the module: {}", e);
+                }
+            }
+            Err(_) => {
+                info!("Relay controller busy, resetting the module on the next tick");
+                self.module.lock().unwrap().retry.defer_reset();
+                return;
+            }
+        }
+        let mut module = self.module.lock().unwrap();
+        module.set_state(MouduleState::INIT);
+        module.retry.schedule(AtCommand::AT, AT_BOOT_DELAY);
+    }
+
+    /// Refreshes the network status queries; the health report is published once they are done.
+    pub async fn send_health<'a>(&self) {
+        let command = self.module.lock().unwrap().start_refresh();
+        match command {
+            Some(command) => self.send_serial(Commander { command }).await,
+            None => info!("Not connected, skipping health report"),
+        }
+    }
+
+    /// Publishes `rssi_dbm,ber,registration,act,band,channel,operator,uptime,reconnects`.
+    pub async fn publish_health<'a>(&self) {
+        let message = {
+            let module = self.module.lock().unwrap();
+            let network = &module.network;
+            let signal = network.signal;
+            let cell = network.serving_cell.as_ref();
+            format!(
+                "{},{},{},{},{},{},{},{},{}",
+                signal
+                    .and_then(|x| x.rssi_dbm)
+                    .map_or(String::new(), |x| x.to_string()),
+                signal
+                    .and_then(|x| x.ber)
+                    .map_or(String::new(), |x| x.to_string()),
+                network
+                    .registration
+                    .as_ref()
+                    .map_or("", |x| x.stat.as_str()),
+                cell.map_or("", |x| x.act.as_str()),
+                cell.map_or("", |x| x.band.as_str()),
+                cell.map_or(String::new(), |x| x.channel.to_string()),
+                network.operator.as_ref().map_or("", |x| x.oper.as_str()),
+                module.uptime(),
+                module.reconnects(),
+            )
+        };
+        self.sendstatus(Some(message), AtReplyTopic::HEALTH).await;
+    }
+
+    /// Replaces the broker settings, used from the next `AT+QMTOPEN`/`AT+QMTCONN` on.
+    pub fn set_mqtt_config(&self, config: MqttConfig) -> Result<(), MqttConfigError> {
+        config.validate()?;
+        self.module.lock().unwrap().settings.mqtt = config;
+        Ok(())
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.module.lock().unwrap().settings.clone()
+    }
+
+    /// Validates and stores `settings`, then applies them. The broker settings are used from the
+    /// next connect, the PZEM address after a restart.
+    pub fn update_settings(&self, settings: Settings) -> Result<(), SettingsError> {
+        settings.save(&mut *self.storage.lock().unwrap())?;
+        let mut relaycontroller = self.relaycontroller.lock().unwrap();
+        relaycontroller.change_delay = settings.relay_change_delay;
+        relaycontroller.max_run = settings.max_run;
+        drop(relaycontroller);
+        let mut module = self.module.lock().unwrap();
+        module.publish_queue.overflow = settings.publish_overflow;
+        module.retry.policy = settings.retry.clone();
+        module
+            .router
+            .set_secret(settings.auth_secret.as_deref(), &self.storage);
+        module.settings = settings;
+        Ok(())
+    }
+
+    /// Applies a `SUBONE/config` payload and acknowledges it on `RTONE/config`.
+    pub async fn apply_config<'a>(&self, payload: &str) {
+        let request = match ConfigRequest::parse(payload) {
+            Ok(request) => request,
+            Err(e) => {
+                info!("Rejecting config {:?}: {}", payload, e);
+                self.sendstatus(Some(ConfigOutcome::malformed(e)), AtReplyTopic::CONFIG)
+                    .await;
+                return;
+            }
+        };
+
+        let current = self.settings();
+        let mut outcome = request.apply(&current);
+        if outcome.settings != current {
+            match self.update_settings(outcome.settings.clone()) {
+                Ok(()) => {
+                    if outcome.settings.pzem_threshold != current.pzem_threshold {
+                        let threshold = outcome.settings.pzem_threshold;
+                        let result = self.pzem.lock().await.set_threshold(threshold).await;
+                        if let Err(e) = result {
+                            info!("Error setting PZEM threshold {:?}", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    info!("Error storing settings: {}", e);
+                    outcome.fail(e);
+                }
+            }
+        }
+
+        self.sendstatus(Some(outcome.reply()), AtReplyTopic::CONFIG)
+            .await;
+    }
+
+    /// `true` once subscribed, until the connection is lost.
+    #[cfg(test)]
+    pub(crate) fn with_module<R>(&self, f: impl FnOnce(&mut ATMoudle) -> R) -> R {
+        f(&mut self.module.lock().unwrap())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.module.lock().unwrap().state, MouduleState::CONNECTED)
+    }
+
+    pub fn network_status(&self) -> NetworkStatus {
+        self.module.lock().unwrap().network.clone()
+    }
+
+    /// Switches the relay for `source` and persists the new state. Returns the status reply.
+    pub fn set_relay(&self, state: ControllerState, source: CommandSource) -> String {
+        if source != CommandSource::Protection {
+            self.protection.lock().unwrap().clear();
+        }
+        if source == CommandSource::Mqtt {
+            let local = self.local_time();
+            let mut scheduler = self.scheduler.lock().unwrap();
+            scheduler.set_override(local);
+            if let Err(e) = scheduler.save_override(&mut *self.storage.lock().unwrap()) {
+                info!("Error saving schedule override: {}", e);
+            }
+        }
+        let verify_delay = self.module.lock().unwrap().settings.verify_delay;
+        let mut relaycontroller = self.relaycontroller.lock().unwrap();
+        let previous = relaycontroller.state;
+        if let Err(e) = relaycontroller.set_state(state) {
+            info!("Error switching relay: {}", e);
+            return relaycontroller.status();
+        }
+
+        relaycontroller.last_command = Some(match state {
+            ControllerState::ON => NextControlCommand::POWERON,
+            ControllerState::OFF => NextControlCommand::POWEROFF,
+        });
+        if previous != relaycontroller.state {
+            relaycontroller.source = Some(source);
+            relaycontroller.schedule_verification(verify_delay);
+            if let Some(record) = relaycontroller.record() {
+                if let Err(e) = record.save(&mut *self.storage.lock().unwrap()) {
+                    info!("Error saving relay state: {}", e);
+                }
+            }
+        }
+        relaycontroller.status()
+    }
+
+    /// Carries out a `SUBONE/cmd` payload and acknowledges it on `RTONE/cmd/<id>`, with the
+    /// error when it could not be parsed.
+    pub async fn handle_command<'a>(&self, payload: &str) {
+        let (id, result) = match ControlRequest::parse(payload) {
+            Ok(request) => {
+                info!("Command {:?}", request);
+                let status = match request.action {
+                    ControlAction::On => self.run_relay(request.minutes),
+                    ControlAction::Off => self.set_relay(ControllerState::OFF, CommandSource::Mqtt),
+                    ControlAction::Status => self.relaycontroller.lock().unwrap().status(),
+                };
+                (request.id, Ok((request.action, status)))
+            }
+            Err((id, e)) => {
+                info!("Rejecting command {:?}: {}", payload, e);
+                (id, Err(e))
+            }
+        };
+
+        let reply = command::reply(id.as_deref(), result);
+        self.publish_named(
+            reply.into_bytes(),
+            &command::reply_name(id.as_deref()),
+            QoS::AtLeastOnce,
+            false,
+        )
+        .await;
+    }
+
+    /// Starts the relay on `SUBONE/start`, for `minutes` or until stopped, and restarts the
+    /// countdown if it already runs. Returns the status reply.
+    pub fn run_relay(&self, minutes: Option<u32>) -> String {
+        self.set_relay(ControllerState::ON, CommandSource::Mqtt);
+        let mut relaycontroller = self.relaycontroller.lock().unwrap();
+        if relaycontroller.state == ControllerState::ON {
+            relaycontroller.set_run_time(minutes);
+            // The deadline is persisted so a reboot does not extend the run.
+            if let Some(record) = relaycontroller.record() {
+                if let Err(e) = record.save(&mut *self.storage.lock().unwrap()) {
+                    info!("Error saving relay state: {}", e);
+                }
+            }
+        }
+        relaycontroller.status()
+    }
+
+    /// Stops the load once its timed run or the maximum run time is over, including a run
+    /// restored at boot once the clock tells how much of it is left.
+    pub async fn check_run_timer<'a>(&self) {
+        let expired = {
+            let mut relaycontroller = self.relaycontroller.lock().unwrap();
+            if let Some(now) = clock::now() {
+                relaycontroller.apply_restored_deadline(now);
+            }
+            relaycontroller.run_expired()
+        };
+        if !expired {
+            return;
+        }
+        info!("Run time over, stopping the device");
+        let status = self.set_relay(ControllerState::OFF, CommandSource::Timer);
+        self.publish(&status, AtReplyTopic::STOP, QoS::AtLeastOnce, false)
+            .await;
+    }
+
+    /// Once the load had time to settle after a relay change, confirms the change with a PZEM
+    /// reading and publishes the fault on `RTONE/fault` if the load did not follow.
+    pub async fn check_relay<'a>(&self) {
+        if !self.relaycontroller.lock().unwrap().verification_due() {
+            return;
+        }
+
+        let mut buffer = emon::Measurement::default();
+        if let Err(e) = self.read_pzem(&mut buffer).await {
+            info!("Cannot verify the relay, error reading from PZEM {:?}", e);
+            return;
+        }
+
+        let (on_current, off_current) = {
+            let module = self.module.lock().unwrap();
+            (
+                module.settings.verify_on_current,
+                module.settings.verify_off_current,
+            )
+        };
+        let result = self
+            .relaycontroller
+            .lock()
+            .unwrap()
+            .verify(&buffer, on_current, off_current);
+        if let Err(e) = result {
+            info!("{}", e);
+            self.publish(&e.report(), AtReplyTopic::FAULT, QoS::AtLeastOnce, false)
+                .await;
+        }
+    }
+
+    /// Stops the load when a reading stays outside the protection limits for the debounce
+    /// time, and starts it again after the restart delay once the supply is back within them.
+    /// Both are published on `RTONE/fault`.
+    pub async fn check_protection<'a>(&self) {
+        let limits = self.module.lock().unwrap().settings.protection;
+        let state = self.relaycontroller.lock().unwrap().state;
+        if state == ControllerState::OFF
+            && !self.protection.lock().unwrap().restart_pending(&limits)
+        {
+            return;
+        }
+
+        let mut buffer = emon::Measurement::default();
+        if let Err(e) = self.read_pzem(&mut buffer).await {
+            info!(
+                "Cannot check the protection, error reading from PZEM {:?}",
+                e
+            );
+            return;
+        }
+
+        match state {
+            ControllerState::ON => {
+                let trip = self.protection.lock().unwrap().check(&limits, &buffer);
+                if let Some(trip) = trip {
+                    info!("Protection trip: {}", trip.report());
+                    self.set_relay(ControllerState::OFF, CommandSource::Protection);
+                    self.publish(&trip.report(), AtReplyTopic::FAULT, QoS::AtLeastOnce, false)
+                        .await;
+                }
+            }
+            ControllerState::OFF => {
+                let trip = self.protection.lock().unwrap().restart(&limits, &buffer);
+                if let Some(trip) = trip {
+                    info!("Restarting after {} trip", trip.reason.as_str());
+                    let status = self.set_relay(ControllerState::ON, CommandSource::Protection);
+                    self.publish(
+                        &format!("RST:{},{}", trip.reason.as_str(), status),
+                        AtReplyTopic::FAULT,
+                        QoS::AtLeastOnce,
+                        false,
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+
+    /// Seconds since the epoch shifted by the UTC offset, once the clock is set.
+    fn local_time(&self) -> Option<u64> {
+        let offset = self.module.lock().unwrap().settings.utc_offset as i64 * 60;
+        clock::now().map(|now| now.saturating_add_signed(offset))
+    }
+
+    /// Switches the relay on the edges of the schedule windows, once the clock is set.
+    pub async fn check_schedule<'a>(&self) {
+        let local = match self.local_time() {
+            Some(local) => local,
+            None => return,
+        };
+        let by_schedule =
+            self.relaycontroller.lock().unwrap().source == Some(CommandSource::Schedule);
+
+        let state = {
+            let mut scheduler = self.scheduler.lock().unwrap();
+            let suppressed = scheduler.suppressed();
+            let state = scheduler.tick(local, by_schedule);
+            if scheduler.suppressed() != suppressed {
+                if let Err(e) = scheduler.save_override(&mut *self.storage.lock().unwrap()) {
+                    info!("Error saving schedule override: {}", e);
+                }
+            }
+            state
+        };
+        if let Some(state) = state {
+            info!("Schedule switching the relay {:?}", state);
+            let status = self.set_relay(state, CommandSource::Schedule);
+            let topic = match state {
+                ControllerState::ON => AtReplyTopic::START,
+                ControllerState::OFF => AtReplyTopic::STOP,
+            };
+            self.publish(&status, topic, QoS::AtLeastOnce, false).await;
+        }
+    }
+
+    /// `SUBONE/schedule/add` with `<days> <HH:MM>-<HH:MM>`, acknowledged with
+    /// `ADD:<id>,<window>`.
+    pub fn schedule_add(&self, payload: &str) -> String {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let window = match scheduler.add(payload) {
+            Ok(window) => window,
+            Err(e) => {
+                info!("Rejecting schedule window {:?}: {}", payload, e);
+                return format!("ERROR:{}", e);
+            }
+        };
+        if let Err(e) = scheduler.save(&mut *self.storage.lock().unwrap()) {
+            let _ = scheduler.delete(window.id);
+            return format!("ERROR:{}", e);
+        }
+        info!("Schedule window {} added: {}", window.id, window.spec());
+        format!("ADD:{},{}", window.id, window.spec())
+    }
+
+    /// `SUBONE/schedule/list`, answered with `LST:<id>,<window>;<id>,<window>;...`.
+    pub fn schedule_list(&self) -> String {
+        let windows = self
+            .scheduler
+            .lock()
+            .unwrap()
+            .windows()
+            .iter()
+            .map(|x| format!("{},{}", x.id, x.spec()))
+            .collect::<Vec<String>>();
+        format!("LST:{}", windows.join(";"))
+    }
+
+    /// `SUBONE/schedule/delete` with `<id>`, acknowledged with `DEL:<id>`.
+    pub fn schedule_delete(&self, payload: &str) -> String {
+        let id = match payload.trim().parse::<u8>() {
+            Ok(id) => id,
+            Err(_) => return "ERROR:Invalid window id".to_string(),
+        };
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let window = match scheduler.delete(id) {
+            Ok(window) => window,
+            Err(e) => return format!("ERROR:{}", e),
+        };
+        if let Err(e) = scheduler.save(&mut *self.storage.lock().unwrap()) {
+            scheduler.restore(window);
+            return format!("ERROR:{}", e);
+        }
+        info!("Schedule window {} deleted: {}", window.id, window.spec());
+        format!("DEL:{}", window.id)
+    }
+
     pub fn restart<'a>(&self) {
         info!("Restarting AT Module");
+        self.backlog.lock().unwrap().flush();
         if let Ok(mut controller) = self.relaycontroller.try_lock() {
+            if let Err(e) = controller::mark_planned_reboot(&mut *self.storage.lock().unwrap()) {
+                info!("Error marking planned reboot: {}", e);
+            }
             let _ = controller.at_module_restart();
-            restart();
+            controller::reboot();
         }
     }
 
     pub async fn init<'a>(&self) {
         let command = Commander::sim_status();
-        let mut module = self.module.lock().unwrap();
-        module.command = command.clone();
-        module.set_event();
-        self.uart.write(command.command.as_bytes()).await.unwrap();
+        let line = {
+            let mut module = self.module.lock().unwrap();
+            module.command = command.clone();
+            module.begin(command.command);
+            module.set_event();
+            module.render(command.command)
+        };
+        self.uart.write(line.as_bytes()).await.unwrap();
         self.uart.wait_tx_done().await.unwrap();
     }
 }
@@ -1,3 +1,7 @@
+use crate::settings::Settings;
+use crate::subscribe::TopicRouter;
+use crate::topics::Topics;
+
 pub const MAX_PUB_LINE: usize = 30;
 pub const AT: &str = "AT";
 pub const QMTCFG_VERSION_COMMAND: &str = "AT+QMTCFG=\"version\",0,3";
@@ -9,15 +13,20 @@ pub const QSSLCFG_SECLEVEL_COMMAND: &str = "AT+QSSLCFG=\"seclevel\",0,0";
 pub const QSSLCFG_CACERT_COMMAND: &str = "AT+QSSLCFG=\"cacert\",0,\"hive\"";
 pub const QSSLCFG_IGNOREINVALID_COMMAND: &str = "AT+QSSLCFG=\"ignoreinvalidcertsign\",0,1";
 pub const QSSLCFG_SNI_COMMAND: &str = "AT+QSSLCFG=\"sni\",0,1";
-pub const QMTOPEN_COMMAND: &str = "AT+QMTOPEN=0,\"abc.dev.url.com\",8883";
-pub const QMTCONN_COMMAND: &str = "AT+QMTCONN=0,\"U2\",\"username\",\"password\"";
-pub const QMTSUBSTART_COMMAND: &str = "AT+QMTSUB=0,1,\"SUBONE/start\",0";
-pub const QMTSUBEND_COMMAND: &str = "AT+QMTSUB=0,1,\"SUBONE/end\",0";
-pub const QMTSUBSTATUS_COMMAND: &str = "AT+QMTSUB=0,1,\"SUBONE/status\",0";
 
 // SIM STATUS
 pub const SIM_INIT_COMMAND: &str = "AT+QINISTAT";
 
+// Device Identity
+pub const IMEI_QUERY: &str = "AT+GSN";
+pub const ICCID_QUERY: &str = "AT+QCCID";
+
+// Time
+pub const TIME_ZONE_UPDATE_COMMAND: &str = "AT+CTZU=1";
+pub const CLOCK_QUERY: &str = "AT+CCLK?";
+/// PDP context `AT+QNTP` synchronises over, the one the MQTT connection uses.
+pub const NTP_CONTEXT_ID: u8 = 1;
+
 // Network Status
 pub const NETWORK_REGISTRATION_QUERY: &str = "AT+CREG?";
 pub const NETWORK_OPERATOR_QUERY: &str = "AT+COPS?";
@@ -37,6 +46,8 @@ pub enum AtCommand {
     QMTCFGVersion,
     QMTCFGSSLEnable,
     QMTCFGRecv,
+    QMTCFGKeepalive,
+    QMTCFGSession,
     QSSLCFGSSLVer,
     QSSLCFGCipher,
     QSSLCFGSecLevel,
@@ -45,10 +56,13 @@ pub enum AtCommand {
     QSSLCFGSNI,
     QMTOPEN,
     QMTCONN,
-    QMTSUBStart,
-    QMTSUBEnd,
-    QMTSUBStatus,
+    QMTSUB,
     SIMInit,
+    IMEIQuery,
+    ICCIDQuery,
+    TimeZoneUpdate,
+    ClockQuery,
+    NTPSync,
     NetworkRegistrationQuery,
     NetworkOperatorQuery,
     NetworkStrengthQuery,
@@ -62,19 +76,25 @@ pub enum AtCommand {
     NOOP,
 }
 
-pub const STATUS_COMMAND_SEQUENCE: [AtCommand; 6] = [
+pub const STATUS_COMMAND_SEQUENCE: [AtCommand; 10] = [
     AtCommand::AT,
     AtCommand::SIMInit,
+    AtCommand::IMEIQuery,
+    AtCommand::ICCIDQuery,
+    AtCommand::TimeZoneUpdate,
     AtCommand::NetworkOperatorQuery,
     AtCommand::NetworkStrengthQuery,
     AtCommand::NetworkQualityQuery,
     AtCommand::NetworkRegistrationQuery,
+    AtCommand::ClockQuery,
 ];
 
-pub const MQTT_CONFIG_COMMAND_SEQUENCE: [AtCommand; 9] = [
+pub const MQTT_CONFIG_COMMAND_SEQUENCE: [AtCommand; 11] = [
     AtCommand::QMTCFGVersion,
     AtCommand::QMTCFGSSLEnable,
     AtCommand::QMTCFGRecv,
+    AtCommand::QMTCFGKeepalive,
+    AtCommand::QMTCFGSession,
     AtCommand::QSSLCFGSSLVer,
     AtCommand::QSSLCFGCipher,
     AtCommand::QSSLCFGSecLevel,
@@ -83,12 +103,22 @@ pub const MQTT_CONFIG_COMMAND_SEQUENCE: [AtCommand; 9] = [
     AtCommand::QSSLCFGSNI,
 ];
 
-pub const MQTT_CONNECTION_COMMAND_SEQUENCE: [AtCommand; 5] = [
+pub const MQTT_CONNECTION_COMMAND_SEQUENCE: [AtCommand; 3] =
+    [AtCommand::QMTOPEN, AtCommand::QMTCONN, AtCommand::QMTSUB];
+
+/// Getting back to the broker after `+QMTSTAT`; continues like `MQTT_CONNECTION_COMMAND_SEQUENCE`.
+pub const MQTT_RECONNECT_COMMAND_SEQUENCE: [AtCommand; 4] = [
+    AtCommand::QMTCLOSE,
     AtCommand::QMTOPEN,
     AtCommand::QMTCONN,
-    AtCommand::QMTSUBStart,
-    AtCommand::QMTSUBEnd,
-    AtCommand::QMTSUBStatus,
+    AtCommand::QMTSUB,
+];
+
+pub const HEALTH_COMMAND_SEQUENCE: [AtCommand; 4] = [
+    AtCommand::NetworkStrengthQuery,
+    AtCommand::NetworkRegistrationQuery,
+    AtCommand::NetworkQualityQuery,
+    AtCommand::NetworkOperatorQuery,
 ];
 
 impl AtCommand {
@@ -104,12 +134,17 @@ impl AtCommand {
             AtCommand::QSSLCFGCACert => "AT+QSSLCFG=\"cacert\",0,\"hive\"\r\n",
             AtCommand::QSSLCFGIgnoreInvalid => "AT+QSSLCFG=\"ignoreinvalidcertsign\",0,1\r\n",
             AtCommand::QSSLCFGSNI => "AT+QSSLCFG=\"sni\",0,1\r\n",
-            AtCommand::QMTOPEN => "AT+QMTOPEN=0,\"abc.dev.url.com\",8883\r\n",
-            AtCommand::QMTCONN => "AT+QMTCONN=0,\"U2\",\"username\",\"password\"\r\n",
-            AtCommand::QMTSUBStart => "AT+QMTSUB=0,1,\"SUBONE/start\",0\r\n",
-            AtCommand::QMTSUBEnd => "AT+QMTSUB=0,1,\"SUBONE/end\",0\r\n",
-            AtCommand::QMTSUBStatus => "AT+QMTSUB=0,1,\"SUBONE/status\",0\r\n",
+            AtCommand::QMTCFGKeepalive => "",
+            AtCommand::QMTCFGSession => "",
+            AtCommand::QMTOPEN => "",
+            AtCommand::QMTCONN => "",
+            AtCommand::QMTSUB => "",
             AtCommand::SIMInit => "AT+QINISTAT\r\n",
+            AtCommand::IMEIQuery => "AT+GSN\r\n",
+            AtCommand::ICCIDQuery => "AT+QCCID\r\n",
+            AtCommand::TimeZoneUpdate => "AT+CTZU=1\r\n",
+            AtCommand::ClockQuery => "AT+CCLK?\r\n",
+            AtCommand::NTPSync => "",
             AtCommand::NetworkRegistrationQuery => "AT+CREG?\r\n",
             AtCommand::NetworkOperatorQuery => "AT+COPS?\r\n",
             AtCommand::NetworkStrengthQuery => "AT+CSQ\r\n",
@@ -130,8 +165,13 @@ impl AtCommand {
             r if r.contains("+QSSLCFG") => Some(AtCommand::QSSLCFGSSLVer),
             r if r.contains("+QMTOPEN") => Some(AtCommand::QMTOPEN),
             r if r.contains("+QMTCONN") => Some(AtCommand::QMTCONN),
-            r if r.contains("+QMTSUB") => Some(AtCommand::QMTSUBStart),
+            r if r.contains("+QMTSUB") => Some(AtCommand::QMTSUB),
             r if r.contains("+QINISTAT") => Some(AtCommand::SIMInit),
+            r if r.contains("+GSN") => Some(AtCommand::IMEIQuery),
+            r if r.contains("+QCCID") => Some(AtCommand::ICCIDQuery),
+            r if r.contains("+CTZU") => Some(AtCommand::TimeZoneUpdate),
+            r if r.contains("+CCLK") => Some(AtCommand::ClockQuery),
+            r if r.contains("+QNTP") => Some(AtCommand::NTPSync),
             r if r.contains("+CREG") => Some(AtCommand::NetworkRegistrationQuery),
             r if r.contains("+COPS") => Some(AtCommand::NetworkOperatorQuery),
             r if r.contains("+CSQ") => Some(AtCommand::NetworkStrengthQuery),
@@ -145,6 +185,101 @@ impl AtCommand {
     pub fn as_bytes(&self) -> &[u8] {
         self.as_str().as_bytes()
     }
+
+    /// The command line to send. Same as [`AtCommand::as_str`], except for the commands that
+    /// carry settings or device topics, which `as_str` leaves empty.
+    pub fn render(&self, settings: &Settings, topics: &Topics, router: &TopicRouter) -> String {
+        let config = &settings.mqtt;
+        match self {
+            AtCommand::QMTCFGKeepalive => config.keepalive_command(),
+            AtCommand::QMTCFGSession => config.session_command(),
+            AtCommand::QMTOPEN => config.open_command(),
+            AtCommand::QMTCONN => config.connect_command(),
+            AtCommand::QMTSUB => subscribe_command(&router.filters(topics)),
+            AtCommand::NTPSync => {
+                format!("AT+QNTP={},\"{}\"\r\n", NTP_CONTEXT_ID, settings.ntp_server)
+            }
+            _ => self.as_str().to_string(),
+        }
+    }
+
+    /// How long to wait for the command to complete, in milliseconds.
+    pub fn timeout(&self) -> u64 {
+        match self {
+            AtCommand::AT
+            | AtCommand::SIMInit
+            | AtCommand::IMEIQuery
+            | AtCommand::ICCIDQuery
+            | AtCommand::TimeZoneUpdate
+            | AtCommand::ClockQuery
+            | AtCommand::NetworkRegistrationQuery
+            | AtCommand::NetworkStrengthQuery
+            | AtCommand::NetworkQualityQuery
+            | AtCommand::QMTOPENQuery
+            | AtCommand::QMTCONNQuery => 1000,
+            AtCommand::NetworkOperatorQuery => 5000,
+            AtCommand::QMTCFGVersion
+            | AtCommand::QMTCFGSSLEnable
+            | AtCommand::QMTCFGRecv
+            | AtCommand::QMTCFGKeepalive
+            | AtCommand::QMTCFGSession
+            | AtCommand::QSSLCFGSSLVer
+            | AtCommand::QSSLCFGCipher
+            | AtCommand::QSSLCFGSecLevel
+            | AtCommand::QSSLCFGCACert
+            | AtCommand::QSSLCFGIgnoreInvalid
+            | AtCommand::QSSLCFGSNI => 2000,
+            AtCommand::QMTOPEN => 75000,
+            AtCommand::NTPSync => 125000,
+            AtCommand::QMTCONN
+            | AtCommand::QMTSUB
+            | AtCommand::QMTDISC
+            | AtCommand::QMTCLOSE
+            | AtCommand::PUBLISH => 30000,
+            AtCommand::PUBLISHSUCCESS | AtCommand::NOOP => 0,
+        }
+    }
+
+    /// Prefix of the information lines the module sends before the final result code.
+    pub fn response_prefix(&self) -> Option<&'static str> {
+        match self {
+            AtCommand::SIMInit => Some("+QINISTAT"),
+            AtCommand::ICCIDQuery => Some("+QCCID"),
+            AtCommand::ClockQuery => Some("+CCLK"),
+            AtCommand::NetworkRegistrationQuery => Some("+CREG"),
+            AtCommand::NetworkOperatorQuery => Some("+COPS"),
+            AtCommand::NetworkStrengthQuery => Some("+CSQ"),
+            AtCommand::NetworkQualityQuery => Some("+QNWINFO"),
+            AtCommand::QMTOPENQuery => Some("+QMTOPEN"),
+            AtCommand::QMTCONNQuery => Some("+QMTCONN"),
+            _ => None,
+        }
+    }
+
+    /// Prefix of the URC carrying the actual result, for commands where `OK` only means the
+    /// module accepted the request.
+    pub fn result_urc(&self) -> Option<&'static str> {
+        match self {
+            AtCommand::QMTOPEN => Some("+QMTOPEN"),
+            AtCommand::QMTCONN => Some("+QMTCONN"),
+            AtCommand::QMTSUB => Some("+QMTSUB"),
+            AtCommand::QMTDISC => Some("+QMTDISC"),
+            AtCommand::QMTCLOSE => Some("+QMTCLOSE"),
+            AtCommand::PUBLISH => Some("+QMTPUBEX"),
+            AtCommand::NTPSync => Some("+QNTP"),
+            _ => None,
+        }
+    }
+}
+
+/// `AT+QMTSUB=0,1,"<topic1>",0[,"<topic2>",0...]`
+fn subscribe_command(filters: &[String]) -> String {
+    let topics = filters
+        .iter()
+        .map(|x| format!("\"{}\",0", x))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("AT+QMTSUB=0,1,{}\r\n", topics)
 }
 
 #[derive(Debug, Clone)]
@@ -166,6 +301,38 @@ impl Commander {
         }
     }
 
+    // Device Identity Query Commands
+    pub fn query_imei() -> Self {
+        Commander {
+            command: AtCommand::IMEIQuery,
+        }
+    }
+
+    pub fn query_iccid() -> Self {
+        Commander {
+            command: AtCommand::ICCIDQuery,
+        }
+    }
+
+    // Time Commands
+    pub fn enable_time_zone_update() -> Self {
+        Commander {
+            command: AtCommand::TimeZoneUpdate,
+        }
+    }
+
+    pub fn query_clock() -> Self {
+        Commander {
+            command: AtCommand::ClockQuery,
+        }
+    }
+
+    pub fn sync_ntp() -> Self {
+        Commander {
+            command: AtCommand::NTPSync,
+        }
+    }
+
     pub fn query_network_strength() -> Self {
         Commander {
             command: AtCommand::NetworkStrengthQuery,
@@ -216,6 +383,18 @@ impl Commander {
         }
     }
 
+    pub fn config_mqtt_keepalive() -> Self {
+        Commander {
+            command: AtCommand::QMTCFGKeepalive,
+        }
+    }
+
+    pub fn config_mqtt_session() -> Self {
+        Commander {
+            command: AtCommand::QMTCFGSession,
+        }
+    }
+
     pub fn config_enable_ssl() -> Self {
         Commander {
             command: AtCommand::QMTCFGSSLEnable,
@@ -278,21 +457,9 @@ impl Commander {
         }
     }
 
-    pub fn subscribe_mqtt_start_topic() -> Self {
-        Commander {
-            command: AtCommand::QMTSUBStart,
-        }
-    }
-
-    pub fn subscribe_mqtt_end_topic() -> Self {
-        Commander {
-            command: AtCommand::QMTSUBEnd,
-        }
-    }
-
-    pub fn subscribe_mqtt_status_topic() -> Self {
+    pub fn subscribe_mqtt_topics() -> Self {
         Commander {
-            command: AtCommand::QMTSUBStatus,
+            command: AtCommand::QMTSUB,
         }
     }
 }
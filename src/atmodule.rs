@@ -1,12 +1,24 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
 use log::info;
 
-use crate::at::Messages;
 use crate::atcommands::{
-    AtCommand, Commander, MQTT_CONFIG_COMMAND_SEQUENCE, MQTT_CONNECTION_COMMAND_SEQUENCE,
-    STATUS_COMMAND_SEQUENCE,
+    AtCommand, Commander, HEALTH_COMMAND_SEQUENCE, MQTT_CONFIG_COMMAND_SEQUENCE,
+    MQTT_CONNECTION_COMMAND_SEQUENCE, MQTT_RECONNECT_COMMAND_SEQUENCE, STATUS_COMMAND_SEQUENCE,
 };
+use crate::clock;
+use crate::constants::CLOCK_RESYNC_INTERVAL;
+use crate::mqtt::DisconnectReason;
+use crate::network::{NetworkStatus, StatusUpdate};
+use crate::publish::PublishQueue;
+use crate::retry::{Escalation, ReconnectBackoff, RetryState};
+use crate::settings::Settings;
+use crate::subscribe::TopicRouter;
+use crate::topics::{DeviceIdentity, Topics};
+use crate::transaction::Transactions;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MouduleState {
     INIT,
     STARTED,
@@ -21,25 +33,31 @@ pub enum MoudleEvent {
     STATUS,
     CONFIG,
     CONNECT,
+    DISCONNECT,
     PUBLISH,
 }
 
-#[derive(Debug, Clone)]
-pub enum PublishState {
-    INIT,
-    PUBLISHING,
-    PUBLISHED,
-    ERROR,
-}
-
 #[derive(Debug, Clone)]
 pub struct ATMoudle {
     pub state: MouduleState,
     pub event: MoudleEvent,
-    pub publish_state: PublishState,
-    pub publish_message: Option<String>,
-    pub messages: Vec<Messages>,
+    pub publish_queue: PublishQueue,
     pub command: Commander,
+    pub transactions: Transactions,
+    pub retry: RetryState,
+    pub network: NetworkStatus,
+    pub refresh: VecDeque<AtCommand>,
+    /// The health query of the refresh that already had its one retry.
+    refresh_retried: Option<AtCommand>,
+    pub started_at: Instant,
+    pub connections: u32,
+    /// Sequence number of the next reading.
+    pub sequence: u32,
+    pub settings: Settings,
+    pub identity: DeviceIdentity,
+    pub router: TopicRouter,
+    pub reconnect: ReconnectBackoff,
+    pub last_disconnect: Option<DisconnectReason>,
 }
 
 impl ATMoudle {
@@ -47,10 +65,21 @@ impl ATMoudle {
         ATMoudle {
             state: MouduleState::INIT,
             event: MoudleEvent::INIT,
-            publish_state: PublishState::INIT,
-            publish_message: None,
-            messages: Vec::new(),
+            publish_queue: PublishQueue::default(),
             command: Commander::at(),
+            transactions: Transactions::new(),
+            retry: RetryState::default(),
+            network: NetworkStatus::default(),
+            refresh: VecDeque::new(),
+            refresh_retried: None,
+            started_at: Instant::now(),
+            connections: 0,
+            sequence: 0,
+            settings: Settings::default(),
+            identity: DeviceIdentity::default(),
+            router: TopicRouter::default(),
+            reconnect: ReconnectBackoff::default(),
+            last_disconnect: None,
         }
     }
 
@@ -58,51 +87,213 @@ impl ATMoudle {
         ATMoudle {
             state,
             event: MoudleEvent::INIT,
-            publish_state: PublishState::INIT,
-            publish_message: None,
-            messages: Vec::new(),
+            publish_queue: PublishQueue::default(),
             command: Commander::at(),
+            transactions: Transactions::new(),
+            retry: RetryState::default(),
+            network: NetworkStatus::default(),
+            refresh: VecDeque::new(),
+            refresh_retried: None,
+            started_at: Instant::now(),
+            connections: 0,
+            sequence: 0,
+            settings: Settings::default(),
+            identity: DeviceIdentity::default(),
+            router: TopicRouter::default(),
+            reconnect: ReconnectBackoff::default(),
+            last_disconnect: None,
         }
     }
 
+    /// Moves to `state`. A health refresh running does not outlive the state it started in,
+    /// which also ends it on every escalation of a timeout.
     pub fn set_state(&mut self, state: MouduleState) {
+        if state == self.state {
+            return;
+        }
+        if state == MouduleState::CONNECTED {
+            self.connections += 1;
+            self.reconnect.reset();
+        }
+        self.refresh.clear();
+        self.refresh_retried = None;
         self.state = state;
     }
 
-    pub fn set_event(&mut self) {
-        let event = ATMoudle::get_event_type(self.command.command.clone());
-        self.event = event;
+    /// Handles `+QMTSTAT`: drops whatever was in flight and schedules the reconnect sequence.
+    pub fn on_disconnect(&mut self, reason: DisconnectReason) {
+        self.set_state(MouduleState::DISCONNECTED);
+        self.last_disconnect = Some(reason);
+        if let Some(command) = self.transactions.cancel() {
+            info!("Dropping {:?} after disconnect", command);
+        }
+        self.publish_queue.requeue();
+        self.schedule_reconnect();
+    }
+
+    fn schedule_reconnect(&mut self) {
+        let delay = self.reconnect.next_delay();
+        info!(
+            "Reconnecting in {}ms (attempt {})",
+            delay,
+            self.reconnect.attempts()
+        );
+        self.retry
+            .schedule(MQTT_RECONNECT_COMMAND_SEQUENCE[0], delay);
     }
 
-    pub fn set_publish_state(&mut self, publish_state: PublishState, message: Option<String>) {
-        info!("Setting publish state");
-        self.publish_state = publish_state;
+    /// While reconnecting, a failed step of the reconnect sequence is not retried right away
+    /// but starts the sequence over after the next backoff delay. A step fails when it ends in
+    /// `ERROR` (`failed`) or when its result code asks for it to be sent again.
+    pub fn reconnect_next(&mut self, command: AtCommand, failed: bool, next: &mut AtCommand) {
+        if !matches!(self.state, MouduleState::DISCONNECTED)
+            || !MQTT_RECONNECT_COMMAND_SEQUENCE.contains(&command)
+            || !(failed || *next == command)
+        {
+            return;
+        }
+        info!("{:?} failed while reconnecting", command);
+        *next = AtCommand::NOOP;
+        self.schedule_reconnect();
+    }
 
-        if message.is_some() {
-            info!("Setting publish message, deleting old message");
-            self.publish_message = message.clone();
-            let removed = self.messages.pop();
-            let copied = removed.clone().unwrap();
-            if removed.is_some_and(|m| m.message == message.unwrap()) {
-                info!("Message removed successfully from the list {:?}", copied);
-                self.messages.push(copied);
+    /// During the bring-up a step that ended in `ERROR` counts against the same retries as a
+    /// timeout instead of stopping the sequence.
+    pub fn bring_up_next(
+        &mut self,
+        command: AtCommand,
+        failed: bool,
+        next: &mut AtCommand,
+    ) -> Option<Escalation> {
+        if !failed
+            || command == AtCommand::PUBLISH
+            || matches!(
+                self.state,
+                MouduleState::CONNECTED | MouduleState::DISCONNECTED
+            )
+        {
+            return None;
+        }
+        *next = AtCommand::NOOP;
+        Some(self.retry.on_error(command))
+    }
+
+    /// Queues the health queries and returns the first one to send. Only done while connected,
+    /// where the status queries must not restart the bring-up sequence. The clock is synced
+    /// along with them when it is unset or older than `CLOCK_RESYNC_INTERVAL`.
+    pub fn start_refresh(&mut self) -> Option<AtCommand> {
+        if !matches!(self.state, MouduleState::CONNECTED)
+            || !self.refresh.is_empty()
+            || self.transactions.in_flight().is_some()
+        {
+            return None;
+        }
+        self.refresh.extend(HEALTH_COMMAND_SEQUENCE.iter().skip(1));
+        if clock::age().map_or(true, |x| x >= CLOCK_RESYNC_INTERVAL) {
+            self.refresh.push_back(self.time_sync_command());
+        }
+        Some(HEALTH_COMMAND_SEQUENCE[0])
+    }
+
+    /// `AT+QNTP` when an NTP server is configured, the network time otherwise.
+    pub fn time_sync_command(&self) -> AtCommand {
+        match self.settings.ntp_server.is_empty() {
+            true => AtCommand::ClockQuery,
+            false => AtCommand::NTPSync,
+        }
+    }
+
+    /// Replaces the bring-up successor of a completed status query with the next health query.
+    /// A query that failed keeps its retry once before the refresh moves on. Returns `true`
+    /// once the last query of the refresh has completed.
+    pub fn refresh_next(&mut self, command: AtCommand, next: &mut AtCommand) -> bool {
+        if !matches!(self.state, MouduleState::CONNECTED)
+            || !(HEALTH_COMMAND_SEQUENCE.contains(&command)
+                || matches!(command, AtCommand::ClockQuery | AtCommand::NTPSync))
+        {
+            return false;
+        }
+
+        if *next == command && self.refresh_retried != Some(command) {
+            info!("Retrying {:?} of the health refresh", command);
+            self.refresh_retried = Some(command);
+            return false;
+        }
+        self.refresh_retried = None;
+
+        match self.refresh.pop_front() {
+            Some(command) => {
+                *next = command;
+                false
+            }
+            None => {
+                *next = AtCommand::NOOP;
+                true
             }
         }
     }
 
-    pub fn set_publish_message(&mut self, message: Option<String>) {
-        info!("Setting publish message");
-        self.publish_message = message.clone();
-        self.messages.push(Messages {
-            message: message.clone().unwrap(),
-            length: message.unwrap().len(),
-        });
+    pub fn update_status(&mut self, update: StatusUpdate) {
+        match update {
+            StatusUpdate::Imei(imei) => self.identity.imei = Some(imei),
+            StatusUpdate::Iccid(iccid) => self.identity.iccid = Some(iccid),
+            StatusUpdate::Time(unix, source) => clock::set(unix, source),
+            update => self.network.update(update),
+        }
+    }
+
+    pub fn topics(&self) -> Topics {
+        Topics::new(&self.settings, &self.identity)
+    }
+
+    /// The command line for `command` with this module's broker settings and topics.
+    pub fn render(&self, command: AtCommand) -> String {
+        command.render(&self.settings, &self.topics(), &self.router)
+    }
+
+    pub fn uptime(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    pub fn reconnects(&self) -> u32 {
+        self.connections.saturating_sub(1)
+    }
+
+    pub fn next_sequence(&mut self) -> u32 {
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        sequence
+    }
+
+    /// How readings identify this device: the IMEI, or the MQTT client id until it is known.
+    pub fn device_id(&self) -> String {
+        self.identity
+            .imei
+            .clone()
+            .unwrap_or_else(|| self.settings.mqtt.client_id.clone())
+    }
+
+    /// Opens the transaction for `command`. A publish it replaces goes back into the queue.
+    pub fn begin(&mut self, command: AtCommand) {
+        if self.transactions.begin(command) == Some(AtCommand::PUBLISH) {
+            self.publish_queue.requeue();
+        }
+    }
+
+    pub fn set_event(&mut self) {
+        let event = ATMoudle::get_event_type(self.command.command.clone());
+        self.event = event;
     }
 
     pub fn get_event_type(command: AtCommand) -> MoudleEvent {
         match command {
             AtCommand::AT
             | AtCommand::SIMInit
+            | AtCommand::IMEIQuery
+            | AtCommand::ICCIDQuery
+            | AtCommand::TimeZoneUpdate
+            | AtCommand::ClockQuery
+            | AtCommand::NTPSync
             | AtCommand::NetworkStrengthQuery
             | AtCommand::NetworkQualityQuery
             | AtCommand::NetworkOperatorQuery
@@ -111,6 +302,8 @@ impl ATMoudle {
             AtCommand::QMTCFGVersion
             | AtCommand::QMTCFGSSLEnable
             | AtCommand::QMTCFGRecv
+            | AtCommand::QMTCFGKeepalive
+            | AtCommand::QMTCFGSession
             | AtCommand::QSSLCFGSSLVer
             | AtCommand::QSSLCFGCipher
             | AtCommand::QSSLCFGSecLevel
@@ -118,11 +311,9 @@ impl ATMoudle {
             | AtCommand::QSSLCFGIgnoreInvalid
             | AtCommand::QSSLCFGSNI => MoudleEvent::CONFIG,
 
-            AtCommand::QMTOPEN
-            | AtCommand::QMTCONN
-            | AtCommand::QMTSUBStart
-            | AtCommand::QMTSUBEnd
-            | AtCommand::QMTSUBStatus => MoudleEvent::CONNECT,
+            AtCommand::QMTOPEN | AtCommand::QMTCONN | AtCommand::QMTSUB => MoudleEvent::CONNECT,
+
+            AtCommand::QMTDISC | AtCommand::QMTCLOSE => MoudleEvent::DISCONNECT,
 
             _ => MoudleEvent::PUBLISH,
         }
@@ -176,9 +367,61 @@ impl ATMoudle {
                     None => return AtCommand::NOOP,
                 }
             }
+            MoudleEvent::DISCONNECT => match command {
+                AtCommand::QMTCLOSE => MQTT_CONNECTION_COMMAND_SEQUENCE[0],
+                _ => AtCommand::NOOP,
+            },
 
             MoudleEvent::PUBLISH => return AtCommand::PUBLISHSUCCESS,
             _ => AtCommand::NOOP,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_health_query_keeps_its_retry_once() {
+        let mut module = ATMoudle::new_with_state(MouduleState::CONNECTED);
+        let first = module.start_refresh().unwrap();
+
+        let mut next = first;
+        assert!(!module.refresh_next(first, &mut next));
+        assert_eq!(next, first);
+
+        let mut next = first;
+        assert!(!module.refresh_next(first, &mut next));
+        assert_eq!(next, HEALTH_COMMAND_SEQUENCE[1]);
+    }
+
+    #[test]
+    fn state_change_ends_the_refresh() {
+        let mut module = ATMoudle::new_with_state(MouduleState::CONNECTED);
+        assert!(module.start_refresh().is_some());
+        assert!(module.start_refresh().is_none());
+
+        module.set_state(MouduleState::STARTED);
+        assert!(module.refresh.is_empty());
+        module.set_state(MouduleState::CONNECTED);
+        assert!(module.start_refresh().is_some());
+    }
+
+    #[test]
+    fn failed_reconnect_step_waits_for_the_backoff() {
+        let mut module = ATMoudle::new_with_state(MouduleState::CONNECTED);
+        module.on_disconnect(DisconnectReason::from_code(1));
+        assert_eq!(module.reconnect.attempts(), 1);
+
+        let mut next = AtCommand::NOOP;
+        module.reconnect_next(AtCommand::QMTCONN, true, &mut next);
+        assert_eq!(next, AtCommand::NOOP);
+        assert_eq!(module.reconnect.attempts(), 2);
+
+        let mut next = AtCommand::QMTSUB;
+        module.reconnect_next(AtCommand::QMTCONN, false, &mut next);
+        assert_eq!(next, AtCommand::QMTSUB);
+        assert_eq!(module.reconnect.attempts(), 2);
+    }
+}
@@ -3,7 +3,15 @@ use log::*;
 use crate::{
     atcommands::AtCommand,
     atmodule::ATMoudle,
-    subscribe::{NextControlCommand, SubMessage},
+    clock::{self, TimeSource},
+    mqtt::DisconnectReason,
+    network::{
+        OperatorInfo, Registration, RegistrationStatus, ServingCell, SignalQuality, StatusUpdate,
+    },
+    publish::PublishResult,
+    subscribe::{NextControlCommand, SubMessage, TopicRouter},
+    topics::Topics,
+    transaction::{AtError, Line},
 };
 
 #[derive(Debug)]
@@ -27,6 +35,13 @@ pub enum ResponseType {
 pub struct ResponseHandlerResponse {
     pub at_command: AtCommand,
     pub control_command: NextControlCommand,
+    pub status: Option<StatusUpdate>,
+    /// Payload of the received message, for control commands that carry one.
+    pub payload: Option<String>,
+    /// Set when the module reported the MQTT connection as dropped.
+    pub disconnect: Option<DisconnectReason>,
+    /// Outcome of the publish in flight, when this settles it.
+    pub published: Option<PublishResult>,
 }
 
 impl ResponseHandlerResponse {
@@ -34,6 +49,10 @@ impl ResponseHandlerResponse {
         ResponseHandlerResponse {
             at_command,
             control_command,
+            status: None,
+            payload: None,
+            disconnect: None,
+            published: None,
         }
     }
 
@@ -41,6 +60,10 @@ impl ResponseHandlerResponse {
         ResponseHandlerResponse {
             at_command,
             control_command: NextControlCommand::NOOP,
+            status: None,
+            payload: None,
+            disconnect: None,
+            published: None,
         }
     }
 
@@ -48,6 +71,10 @@ impl ResponseHandlerResponse {
         ResponseHandlerResponse {
             at_command: AtCommand::NOOP,
             control_command,
+            status: None,
+            payload: None,
+            disconnect: None,
+            published: None,
         }
     }
 
@@ -55,6 +82,10 @@ impl ResponseHandlerResponse {
         ResponseHandlerResponse {
             at_command: AtCommand::NOOP,
             control_command: NextControlCommand::NOOP,
+            status: None,
+            payload: None,
+            disconnect: None,
+            published: None,
         }
     }
 }
@@ -83,6 +114,16 @@ impl<'a, 'b, 'c> ATResponse<'a> {
 
     pub fn from_string(response: &'a [u8]) -> ATResponse<'a> {
         if let Ok(response) = std::str::from_utf8(response) {
+            // Kept whole, the payload may span lines.
+            let message = response.trim_start_matches(['\r', '\n']);
+            if message.starts_with("+QMTRECV") {
+                return ATResponse {
+                    response_type: ResponseType::MESSAGE,
+                    response: message,
+                    response_vec: ["MANA", message],
+                };
+            }
+
             let mut responses: Vec<&str> =
                 response.split("\r\n").filter(|&x| !x.is_empty()).collect();
 
@@ -202,7 +243,79 @@ impl<'a> ResponseHandler<'a> {
         ProcessedResponse::Passed
     }
 
-    pub fn handle_network_operator_query(responses: Vec<&str>) -> ProcessedResponse {
+    pub fn handle_imei_query(responses: Vec<&str>) -> Option<String> {
+        // Processes: <IMEI>
+        // The 15 digit IMEI, without a prefix.
+        info!("IMEI Response: {:?}", responses);
+
+        let imei = responses.first().unwrap_or(&"MANA").trim();
+        if imei.is_empty() || !imei.chars().all(|x| x.is_ascii_digit()) {
+            info!("Invalid IMEI Response");
+            return None;
+        }
+
+        info!("IMEI: {}", imei);
+        Some(imei.to_string())
+    }
+
+    pub fn handle_iccid_query(responses: Vec<&str>) -> Option<String> {
+        // Processes: +QCCID: <ICCID>
+        // The ICCID of the SIM, 19/20 characters, may end in `F`.
+        info!("ICCID Response: {:?}", responses);
+
+        let iccid_split = responses
+            .first()
+            .unwrap_or(&"MANA")
+            .split(":")
+            .into_iter()
+            .map(|x| x.trim())
+            .into_iter()
+            .collect::<Vec<&str>>();
+
+        if iccid_split.len() < 2 || iccid_split[1].is_empty() {
+            info!("Invalid ICCID Response");
+            return None;
+        }
+
+        let iccid = iccid_split[1].trim_matches('"');
+        if !iccid.chars().all(|x| x.is_ascii_alphanumeric()) {
+            info!("Invalid ICCID Response");
+            return None;
+        }
+
+        info!("ICCID: {}", iccid);
+        Some(iccid.to_string())
+    }
+
+    pub fn handle_clock_query(responses: Vec<&str>) -> Option<u64> {
+        // Processes: +CCLK: "<yy/MM/dd,hh:mm:ss±zz>"
+        // Local time, zz is the offset from UTC in quarter hours.
+        info!("Clock Response: {:?}", responses);
+
+        let (_, time) = responses.first()?.split_once(':')?;
+        let unix = clock::parse_modem_time(time);
+        if unix.is_none() {
+            info!("Clock not set by the network yet");
+        }
+        unix
+    }
+
+    pub fn handle_ntp_response(responses: Vec<&str>) -> Option<u64> {
+        // Processes: +QNTP: <err>[,"<yyyy/MM/dd,hh:mm:ss±zz>"]
+        // err: 0: Success, anything else failed.
+        info!("NTP Response: {:?}", responses);
+
+        let response = responses.iter().find(|x| x.starts_with("+QNTP"))?;
+        let (_, values) = response.split_once(':')?;
+        let (err, time) = values.split_once(',').unwrap_or((values, ""));
+        if err.trim() != "0" {
+            info!("NTP sync failed: {}", err.trim());
+            return None;
+        }
+        clock::parse_modem_time(time)
+    }
+
+    pub fn handle_network_operator_query(responses: Vec<&str>) -> Option<OperatorInfo> {
         // Processes: +COPS: <mode>[,<format>[,<oper>[,<AcT>]]]
         // mode: (integer type) : 0: Automatic, 1: Manual, 2: Deregister from
         // network format: (integer type) : 0: Long alphanumeric, 1: Short
@@ -222,7 +335,7 @@ impl<'a> ResponseHandler<'a> {
 
         if network_operator_split.len() < 2 {
             info!("Invalid Network Operator Response");
-            return ProcessedResponse::Failed;
+            return None;
         }
 
         let network_operator = network_operator_split[1]
@@ -234,23 +347,25 @@ impl<'a> ResponseHandler<'a> {
 
         if network_operator.len() < 3 {
             info!("Invalid Network Operator Response");
-            return ProcessedResponse::Failed;
+            return None;
         }
 
-        let mode = network_operator[0].parse::<u8>().unwrap_or(99);
-        let format = network_operator[1].parse::<u8>().unwrap_or(99);
-        let operator = network_operator[2];
-        let act = network_operator[3].parse::<u8>().unwrap_or(99);
+        let operator = OperatorInfo {
+            mode: network_operator[0].parse::<u8>().unwrap_or(99),
+            format: network_operator[1].parse::<u8>().unwrap_or(99),
+            oper: network_operator[2].trim_matches('"').to_string(),
+            act: network_operator.get(3).and_then(|x| x.parse::<u8>().ok()),
+        };
 
         info!(
-            "Mode: {}, Format: {}, Operator: {}, Access Technology: {}",
-            mode, format, operator, act
+            "Mode: {}, Format: {}, Operator: {}, Access Technology: {:?}",
+            operator.mode, operator.format, operator.oper, operator.act
         );
 
-        return ProcessedResponse::Passed;
+        Some(operator)
     }
 
-    pub fn handle_network_strength_query(responses: Vec<&str>) -> ProcessedResponse {
+    pub fn handle_network_strength_query(responses: Vec<&str>) -> Option<SignalQuality> {
         // Processes: +CSQ: <rssi>,<ber>
         // rssi: 0-31, 99
         // ber: 0-7, 99
@@ -267,7 +382,7 @@ impl<'a> ResponseHandler<'a> {
 
         if network_strength_split.len() < 2 {
             info!("Invalid Network Strength Response");
-            return ProcessedResponse::Failed;
+            return None;
         }
 
         let network_strength = network_strength_split[1]
@@ -279,18 +394,23 @@ impl<'a> ResponseHandler<'a> {
 
         if network_strength.len() < 2 {
             info!("Invalid Network Strength Response");
-            return ProcessedResponse::Failed;
+            return None;
         }
 
         let rssi = network_strength[0].parse::<u8>().unwrap_or(99);
         let ber = network_strength[1].parse::<u8>().unwrap_or(99);
 
-        info!("RSSI: {}, BER: {}", rssi, ber);
+        let signal = SignalQuality::from_csq(rssi, ber);
 
-        ProcessedResponse::Passed
+        info!(
+            "RSSI: {}, BER: {}, RSSI dBm: {:?}",
+            rssi, ber, signal.rssi_dbm
+        );
+
+        Some(signal)
     }
 
-    pub fn handle_network_quality_strength(responses: Vec<&str>) -> ProcessedResponse {
+    pub fn handle_network_quality_strength(responses: Vec<&str>) -> Option<ServingCell> {
         // Processes: +QNWINFO: <AcT>,<oper>,<band>,<channel>
         // Parameter
         // AcT: (String type): The selected access technology.
@@ -335,7 +455,7 @@ impl<'a> ResponseHandler<'a> {
 
         if network_quality_split.len() < 2 {
             info!("Invalid Network Quality Response");
-            return ProcessedResponse::Failed;
+            return None;
         }
 
         let network_quality = network_quality_split[1]
@@ -347,25 +467,27 @@ impl<'a> ResponseHandler<'a> {
 
         if network_quality.len() < 4 {
             info!("Invalid Network Quality Response");
-            return ProcessedResponse::Failed;
+            return None;
         }
 
         info!("Network Quality: {:?}", network_quality);
 
-        let act = network_quality[0];
-        let oper = network_quality[1];
-        let band = network_quality[2];
-        let channel = network_quality[3];
+        let serving_cell = ServingCell {
+            act: network_quality[0].trim_matches('"').to_string(),
+            oper: network_quality[1].trim_matches('"').to_string(),
+            band: network_quality[2].trim_matches('"').to_string(),
+            channel: network_quality[3].parse::<u32>().unwrap_or(0),
+        };
 
         info!(
             "Access Technology: {}, Operator: {}, Band: {}, Channel: {}",
-            act, oper, band, channel
+            serving_cell.act, serving_cell.oper, serving_cell.band, serving_cell.channel
         );
 
-        ProcessedResponse::Passed
+        Some(serving_cell)
     }
 
-    pub fn handle_network_registration_query(responses: Vec<&str>) -> ProcessedResponse {
+    pub fn handle_network_registration_query(responses: Vec<&str>) -> Option<Registration> {
         // Processes: +CREG: <n>,<stat>[,<lac>,<ci>[,<AcT>]]
         // n: (integer type)
         // These just means will/will not receive URCs for network registration
@@ -395,7 +517,7 @@ impl<'a> ResponseHandler<'a> {
             .collect::<Vec<&str>>();
 
         if network_registration_split.len() < 2 {
-            return ProcessedResponse::Failed;
+            return None;
         }
 
         let network_registration = network_registration_split[1]
@@ -406,7 +528,7 @@ impl<'a> ResponseHandler<'a> {
             .collect::<Vec<&str>>();
 
         if network_registration.len() < 2 {
-            return ProcessedResponse::Failed;
+            return None;
         }
 
         let n = network_registration[0].parse::<u8>().unwrap_or(99);
@@ -445,19 +567,35 @@ impl<'a> ResponseHandler<'a> {
                 }
             }
 
-            return ProcessedResponse::Passed;
+            return Some(Registration {
+                n,
+                stat: RegistrationStatus::from_stat(stat),
+                lac: None,
+                ci: None,
+                act: None,
+            });
         }
 
-        let lac = network_registration[2];
-        let ci = network_registration[3];
-        let act = network_registration[4].parse::<u8>().unwrap_or(99);
+        let registration = Registration {
+            n,
+            stat: RegistrationStatus::from_stat(stat),
+            lac: network_registration
+                .get(2)
+                .map(|x| x.trim_matches('"').to_string()),
+            ci: network_registration
+                .get(3)
+                .map(|x| x.trim_matches('"').to_string()),
+            act: network_registration
+                .get(4)
+                .and_then(|x| x.parse::<u8>().ok()),
+        };
 
         info!(
-            "N: {}, Stat: {}, LAC: {}, CI: {}, ACT: {}",
-            n, stat, lac, ci, act
+            "N: {}, Stat: {:?}, LAC: {:?}, CI: {:?}, ACT: {:?}",
+            registration.n, registration.stat, registration.lac, registration.ci, registration.act
         );
 
-        ProcessedResponse::Passed
+        Some(registration)
     }
 
     pub fn handle_mqtt_open_command(responses: Vec<&str>) -> ProcessedResponse {
@@ -693,146 +831,209 @@ impl<'a> ResponseHandler<'a> {
         ProcessedResponse::Passed
     }
 
-    pub fn handle_publish_response<'b>(responses: Vec<&'b str>) -> ProcessedResponse {
-        info!("PUB Response: {:?}", responses);
-        ProcessedResponse::Passed
-    }
+    pub fn handle_mqtt_stat(responses: Vec<&str>) -> Option<DisconnectReason> {
+        // Process: +QMTSTAT: <client_idx>,<err_code>
+        // - `<err_code>`: Integer type. Why the connection was dropped.
+        //   - 1: Connection is closed or reset by a peer
+        //   - 2: Sending PINGREQ packet timed out or failed
+        //   - 3: Sending CONNECT packet timed out or failed
+        //   - 4: Receiving CONNACK packet timed out or failed
+        //   - 5: The server closed the connection after a DISCONNECT packet
+        //   - 6: The client closed the connection because sending packets kept failing
+        //   - 7: The link is not alive or the server is unavailable
+        info!("MQTT Stat Response: {:?}", responses);
+
+        let mqtt_stat_split = responses
+            .first()
+            .unwrap_or(&"MANA")
+            .split(":")
+            .into_iter()
+            .map(|x| x.trim())
+            .into_iter()
+            .collect::<Vec<&str>>();
 
-    pub fn handle_response(&self, prev_command: AtCommand) -> ResponseHandlerResponse {
-        info!("Response: {:?} {:?}", self.response.response, prev_command);
-        match self.response.response_type {
-            ResponseType::OK => {
-                info!("OK :{:?}", self.response);
-                let passed = match prev_command {
-                    AtCommand::SIMInit => ResponseHandler::handle_sim_stat_response(
-                        self.response.response_vec.to_vec(),
-                    ),
+        if mqtt_stat_split.len() < 2 {
+            return None;
+        }
 
-                    AtCommand::NetworkOperatorQuery => {
-                        ResponseHandler::handle_network_operator_query(
-                            self.response.response_vec.to_vec(),
-                        )
-                    }
+        let mqtt_stat = mqtt_stat_split[1]
+            .split(",")
+            .into_iter()
+            .map(|x| x.trim())
+            .into_iter()
+            .collect::<Vec<&str>>();
 
-                    AtCommand::NetworkStrengthQuery => {
-                        ResponseHandler::handle_network_strength_query(
-                            self.response.response_vec.to_vec(),
-                        )
-                    }
-                    AtCommand::NetworkQualityQuery => {
-                        ResponseHandler::handle_network_quality_strength(
-                            self.response.response_vec.to_vec(),
-                        )
-                    }
-                    AtCommand::NetworkRegistrationQuery => {
-                        ResponseHandler::handle_network_registration_query(
-                            self.response.response_vec.to_vec(),
-                        )
-                    }
+        if mqtt_stat.len() < 2 {
+            return None;
+        }
 
-                    AtCommand::PUBLISH => ProcessedResponse::Passed,
+        let reason = DisconnectReason::from_code(mqtt_stat[1].parse::<u8>().unwrap_or(0));
+        info!("Client ID: {}, Reason: {}", mqtt_stat[0], reason);
+
+        Some(reason)
+    }
 
-                    AtCommand::QMTCFGVersion
-                    | AtCommand::QMTCFGSSLEnable
-                    | AtCommand::QMTCFGRecv
-                    | AtCommand::QSSLCFGSSLVer
-                    | AtCommand::QSSLCFGCipher
-                    | AtCommand::QSSLCFGSecLevel
-                    | AtCommand::QSSLCFGCACert
-                    | AtCommand::QSSLCFGIgnoreInvalid
-                    | AtCommand::QSSLCFGSNI => ProcessedResponse::Passed,
+    /// `+QMTPUBEX: <client_idx>,<msgID>,<result>[,<value>]`
+    pub fn handle_publish_response<'b>(responses: Vec<&'b str>) -> Option<PublishResult> {
+        info!("PUB Response: {:?}", responses);
+        let response = responses.iter().find(|x| x.starts_with("+QMTPUBEX"))?;
+        let (_, values) = response.split_once(':')?;
+        let result = values.split(',').nth(2)?.trim().parse::<u8>().ok()?;
+        Some(PublishResult::from_result(result))
+    }
 
-                    AtCommand::QMTOPEN | AtCommand::QMTCONN | AtCommand::QMTSUBStart => {
-                        ProcessedResponse::Noop
+    pub fn handle_result(
+        command: AtCommand,
+        result: Result<Vec<Line>, AtError>,
+    ) -> ResponseHandlerResponse {
+        info!("Result: {:?} {:?}", command, result);
+
+        let lines = match result {
+            Ok(lines) => lines,
+            Err(e) => {
+                info!("{:?} failed: {}", command, e);
+                match command {
+                    AtCommand::QMTOPEN | AtCommand::QMTCLOSE => {
+                        // A link the broker already dropped can fail to close, open it anyway.
+                        return ResponseHandlerResponse::at(AtCommand::QMTOPEN);
+                    }
+                    AtCommand::PUBLISH => {
+                        let mut response = ResponseHandlerResponse::noop();
+                        response.published = Some(PublishResult::Failed);
+                        return response;
                     }
                     _ => {
-                        info!("Unknown Command");
-                        ProcessedResponse::Noop
+                        return ResponseHandlerResponse::noop();
                     }
-                };
+                }
+            }
+        };
+        let responses = lines.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
+
+        if command == AtCommand::PUBLISH {
+            let mut response = ResponseHandlerResponse::noop();
+            response.published = Some(
+                ResponseHandler::handle_publish_response(responses)
+                    .unwrap_or(PublishResult::Failed),
+            );
+            return response;
+        }
 
-                match passed {
-                    ProcessedResponse::Passed => {
-                        info!("Passed");
-                        return ResponseHandlerResponse::at(ATMoudle::get_next_command(
-                            prev_command,
-                        ));
+        let mut status = None;
+        let passed = match command {
+            AtCommand::SIMInit => ResponseHandler::handle_sim_stat_response(responses),
+            AtCommand::IMEIQuery
+            | AtCommand::ICCIDQuery
+            | AtCommand::NetworkOperatorQuery
+            | AtCommand::NetworkStrengthQuery
+            | AtCommand::NetworkQualityQuery
+            | AtCommand::NetworkRegistrationQuery => {
+                status = match command {
+                    AtCommand::IMEIQuery => {
+                        ResponseHandler::handle_imei_query(responses).map(StatusUpdate::Imei)
                     }
-                    ProcessedResponse::Failed => {
-                        info!("Failed");
-                        return ResponseHandlerResponse::at(prev_command);
+                    AtCommand::ICCIDQuery => {
+                        ResponseHandler::handle_iccid_query(responses).map(StatusUpdate::Iccid)
                     }
-                    ProcessedResponse::Noop => {
-                        info!("Noop");
-                        return ResponseHandlerResponse::noop();
+                    AtCommand::NetworkOperatorQuery => {
+                        ResponseHandler::handle_network_operator_query(responses)
+                            .map(StatusUpdate::Operator)
                     }
+                    AtCommand::NetworkStrengthQuery => {
+                        ResponseHandler::handle_network_strength_query(responses)
+                            .map(StatusUpdate::Signal)
+                    }
+                    AtCommand::NetworkQualityQuery => {
+                        ResponseHandler::handle_network_quality_strength(responses)
+                            .map(StatusUpdate::ServingCell)
+                    }
+                    _ => ResponseHandler::handle_network_registration_query(responses)
+                        .map(StatusUpdate::Registration),
+                };
+
+                match status {
+                    Some(_) => ProcessedResponse::Passed,
+                    None => ProcessedResponse::Failed,
                 }
             }
 
-            ResponseType::REPLY => {
-                info!("REPLY");
-                return ResponseHandlerResponse::at(AtCommand::PUBLISH);
+            // A clock the network has not set yet is no reason to hold up the bring-up.
+            AtCommand::ClockQuery => {
+                status = ResponseHandler::handle_clock_query(responses)
+                    .map(|x| StatusUpdate::Time(x, TimeSource::Network));
+                ProcessedResponse::Passed
             }
-
-            ResponseType::PUBRESPONSE => {
-                info!("Got response for publish message command");
-                ResponseHandler::handle_publish_response(self.response.response_vec.to_vec());
-                return ResponseHandlerResponse::at(AtCommand::PUBLISHSUCCESS);
+            AtCommand::NTPSync => {
+                status = ResponseHandler::handle_ntp_response(responses)
+                    .map(|x| StatusUpdate::Time(x, TimeSource::Ntp));
+                ProcessedResponse::Passed
             }
 
-            ResponseType::QMTOPEN => {
-                ResponseHandler::handle_mqtt_open_command(self.response.response_vec.to_vec());
-                return ResponseHandlerResponse::at(ATMoudle::get_next_command(prev_command));
+            AtCommand::AT
+            | AtCommand::TimeZoneUpdate
+            | AtCommand::QMTCFGVersion
+            | AtCommand::QMTCFGSSLEnable
+            | AtCommand::QMTCFGRecv
+            | AtCommand::QMTCFGKeepalive
+            | AtCommand::QMTCFGSession
+            | AtCommand::QSSLCFGSSLVer
+            | AtCommand::QSSLCFGCipher
+            | AtCommand::QSSLCFGSecLevel
+            | AtCommand::QSSLCFGCACert
+            | AtCommand::QSSLCFGIgnoreInvalid
+            | AtCommand::QSSLCFGSNI => ProcessedResponse::Passed,
+
+            AtCommand::QMTOPEN => ResponseHandler::handle_mqtt_open_command(responses),
+            AtCommand::QMTCONN => ResponseHandler::handle_mqtt_conn_command(responses),
+            AtCommand::QMTSUB => ResponseHandler::handle_subscribe_command(responses),
+            AtCommand::QMTDISC | AtCommand::QMTCLOSE => {
+                info!("MQTT Close Response: {:?}", responses);
+                ProcessedResponse::Passed
             }
 
-            ResponseType::QMTCONN => {
-                ResponseHandler::handle_mqtt_conn_command(self.response.response_vec.to_vec());
-                return ResponseHandlerResponse::at(ATMoudle::get_next_command(prev_command));
+            _ => {
+                info!("Unknown Command");
+                ProcessedResponse::Noop
             }
-
-            ResponseType::QMTSUB => {
-                info!("QMTSUB");
-
-                let processed =
-                    ResponseHandler::handle_subscribe_command(self.response.response_vec.to_vec());
-
-                match processed {
-                    ProcessedResponse::Passed => {
-                        info!("Passed");
-
-                        // if prev_command == AtCommand::QMTSUBEnd {
-                        //     return ResponseHandlerResponse::control(
-                        //         NextControlCommand::STATUSUPDATE,
-                        //     );
-                        // }
-
-                        return ResponseHandlerResponse::at(ATMoudle::get_next_command(
-                            prev_command,
-                        ));
-                    }
-                    ProcessedResponse::Failed => {
-                        info!("Failed");
-                        return ResponseHandlerResponse::at(prev_command);
-                    }
-                    ProcessedResponse::Noop => {
-                        info!("Noop");
-                        return ResponseHandlerResponse::noop();
-                    }
-                }
+        };
+
+        match passed {
+            ProcessedResponse::Passed => {
+                info!("Passed");
+                let mut response = ResponseHandlerResponse::at(ATMoudle::get_next_command(command));
+                response.status = status;
+                response
             }
+            ProcessedResponse::Failed => {
+                info!("Failed");
+                ResponseHandlerResponse::at(command)
+            }
+            ProcessedResponse::Noop => {
+                info!("Noop");
+                ResponseHandlerResponse::noop()
+            }
+        }
+    }
 
-            ResponseType::ERROR => {
-                info!("ERROR");
-
-                match prev_command {
-                    AtCommand::QMTOPEN => {
-                        info!("SIM Init Failed");
-                        return ResponseHandlerResponse::at(AtCommand::QMTOPEN);
-                    }
-                    _ => {
-                        return ResponseHandlerResponse::noop();
-                    }
-                }
+    /// Handles what is not the result of a command: URCs and the data prompt.
+    pub fn handle_response(
+        &self,
+        topics: &Topics,
+        router: &TopicRouter,
+    ) -> ResponseHandlerResponse {
+        info!("Response: {:?}", self.response.response);
+        match self.response.response_type {
+            ResponseType::REPLY => {
+                info!("REPLY");
+                return ResponseHandlerResponse::at(AtCommand::PUBLISH);
+            }
+            ResponseType::OK
+            | ResponseType::ERROR
+            | ResponseType::PUBRESPONSE
+            | ResponseType::QMTOPEN
+            | ResponseType::QMTCONN
+            | ResponseType::QMTSUB => {
+                info!("Result without a command in flight: {:?}", self.response);
             }
             ResponseType::STATUS => {
                 info!("STATUS");
@@ -844,34 +1045,19 @@ impl<'a> ResponseHandler<'a> {
                 info!("UNKNOWN");
             }
             ResponseType::MESSAGE => {
-                let processed = SubMessage::process_received_message(&self.response.response_vec);
-                if let Ok(message) = processed {
-                    match message.next_control_command {
-                        NextControlCommand::STATUSUPDATE => {
-                            info!("STATUSUPDATE");
-                            return ResponseHandlerResponse::control(
-                                NextControlCommand::STATUSUPDATE,
-                            );
-                        }
-                        NextControlCommand::POWEROFF => {
-                            info!("POWEROFF");
-                            return ResponseHandlerResponse::control(NextControlCommand::POWEROFF);
-                        }
-                        NextControlCommand::POWERON => {
-                            info!("POWERON");
-                            return ResponseHandlerResponse::control(NextControlCommand::POWERON);
-                        }
-                        NextControlCommand::NOOP => {
-                            info!("NOOP");
-                            return ResponseHandlerResponse::noop();
-                        }
-                    }
+                match SubMessage::parse(self.response.response) {
+                    Ok(message) => return router.dispatch(&message, topics),
+                    Err(e) => info!("Dropping received message: {}", e),
                 }
 
                 return ResponseHandlerResponse::noop();
             }
             ResponseType::MQTTSTAT => {
                 info!("MQTTSTAT");
+                let mut response = ResponseHandlerResponse::noop();
+                response.disconnect =
+                    ResponseHandler::handle_mqtt_stat(vec![self.response.response_vec[1]]);
+                return response;
             }
             ResponseType::MQTTPING => {
                 info!("MQTTPING");
@@ -0,0 +1,273 @@
+use core::fmt;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use hmac::{Hmac, Mac};
+use log::info;
+use sha2::Sha256;
+
+use crate::clock;
+use crate::constants::{AUTH_MAX_SKEW, AUTH_NONCE_WINDOW};
+use crate::settings::{self, SharedStorage};
+
+/// Longest nonce accepted.
+const MAX_NONCE_LEN: usize = 32;
+
+// Kept next to the settings, see `settings::SETTINGS_NAMESPACE`.
+const KEY_AUTH_TS: &str = "auth_ts";
+
+/// Why a received message was not acted on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    /// No `|<nonce>|<ts>|<sig>` trailer.
+    Unsigned,
+    Malformed,
+    BadSignature,
+    /// The nonce was seen recently.
+    Replay,
+    /// The timestamp is too far from the device clock.
+    Stale,
+    /// The device clock is not set, so the timestamp cannot be checked.
+    ClockUnset,
+    /// No secret is provisioned, so nothing can be verified.
+    Unprovisioned,
+}
+
+impl AuthError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthError::Unsigned => "unsigned",
+            AuthError::Malformed => "malformed",
+            AuthError::BadSignature => "bad-signature",
+            AuthError::Replay => "replay",
+            AuthError::Stale => "stale",
+            AuthError::ClockUnset => "clock-unset",
+            AuthError::Unprovisioned => "unprovisioned",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Verifies received payloads signed with the device secret.
+///
+/// A signed payload is `<body>|<nonce>|<ts>|<sig>`: `ts` in Unix seconds and `sig` the hex
+/// HMAC-SHA256 of `<topic>|<body>|<nonce>|<ts>` keyed with the secret, so a message is not
+/// valid on another topic. The last [`AUTH_NONCE_WINDOW`] nonces are remembered to reject
+/// replays, and `ts` must be within [`AUTH_MAX_SKEW`] seconds of the device clock. Until the
+/// clock is set every signed message is rejected, as a replay of any old one would pass.
+///
+/// The nonces only live in RAM, so the newest accepted `ts` is persisted and, after a reboot,
+/// only messages signed later than it are accepted.
+///
+/// Clones share the nonces.
+#[derive(Clone)]
+pub struct Authenticator {
+    secret: Vec<u8>,
+    seen: Arc<Mutex<Seen>>,
+    storage: Option<SharedStorage>,
+}
+
+/// What was accepted so far.
+#[derive(Debug, Default)]
+struct Seen {
+    nonces: VecDeque<String>,
+    /// Newest `ts` accepted before the boot, anything up to it is a replay.
+    floor: u64,
+    /// Newest `ts` accepted.
+    newest: u64,
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seen = self.seen.lock().unwrap();
+        f.debug_struct("Authenticator")
+            .field("nonces", &seen.nonces.len())
+            .field("floor", &seen.floor)
+            .finish()
+    }
+}
+
+impl Authenticator {
+    /// Keeps what it accepted in RAM only.
+    pub fn new(secret: &str) -> Self {
+        Authenticator {
+            secret: secret.as_bytes().to_vec(),
+            seen: Arc::new(Mutex::new(Seen::default())),
+            storage: None,
+        }
+    }
+
+    /// Persists the newest accepted `ts` in `storage` and starts from the one stored there.
+    pub fn persistent(secret: &str, storage: SharedStorage) -> Self {
+        let floor = settings::read::<u64>(&*storage.lock().unwrap(), KEY_AUTH_TS).unwrap_or(0);
+        let mut auth = Authenticator::new(secret);
+        auth.seen = Arc::new(Mutex::new(Seen {
+            floor,
+            newest: floor,
+            ..Seen::default()
+        }));
+        auth.storage = Some(storage);
+        auth
+    }
+
+    pub fn has_secret(&self, secret: &str) -> bool {
+        self.secret == secret.as_bytes()
+    }
+
+    /// Checks `payload` received on `topic` and returns its body.
+    pub fn verify<'a>(&self, topic: &str, payload: &'a str) -> Result<&'a str, AuthError> {
+        self.verify_at(topic, payload, clock::now())
+    }
+
+    /// [`Authenticator::verify`] at Unix time `now`, `None` while the clock is not set.
+    fn verify_at<'a>(
+        &self,
+        topic: &str,
+        payload: &'a str,
+        now: Option<u64>,
+    ) -> Result<&'a str, AuthError> {
+        let (signed, sig) = payload.rsplit_once('|').ok_or(AuthError::Unsigned)?;
+        let mut fields = signed.rsplitn(3, '|');
+        let (ts, nonce, body) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(ts), Some(nonce), Some(body)) => (ts, nonce, body),
+            _ => return Err(AuthError::Unsigned),
+        };
+
+        let ts = ts.parse::<u64>().map_err(|_| AuthError::Malformed)?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(AuthError::Malformed);
+        }
+        let sig = decode_hex(sig).ok_or(AuthError::Malformed)?;
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).map_err(|_| AuthError::BadSignature)?;
+        mac.update(topic.as_bytes());
+        mac.update(b"|");
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&sig)
+            .map_err(|_| AuthError::BadSignature)?;
+
+        let now = now.ok_or(AuthError::ClockUnset)?;
+        if now.abs_diff(ts) > AUTH_MAX_SKEW {
+            info!("Message timestamp {} is {}s off", ts, now.abs_diff(ts));
+            return Err(AuthError::Stale);
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        if ts <= seen.floor || seen.nonces.iter().any(|x| x == nonce) {
+            return Err(AuthError::Replay);
+        }
+        if seen.nonces.len() >= AUTH_NONCE_WINDOW {
+            seen.nonces.pop_front();
+        }
+        seen.nonces.push_back(nonce.to_string());
+
+        if ts > seen.newest {
+            seen.newest = ts;
+            if let Some(storage) = &self.storage {
+                if let Err(e) = settings::write(&mut *storage.lock().unwrap(), KEY_AUTH_TS, &ts) {
+                    info!("Error saving the message timestamp: {}", e);
+                }
+            }
+        }
+
+        Ok(body)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::MemoryStorage;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn sign(topic: &str, body: &str, nonce: &str, ts: u64) -> String {
+        let signed = format!("{}|{}|{}", body, nonce, ts);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(format!("{}|{}", topic, signed).as_bytes());
+        let sig = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        format!("{}|{}", signed, sig)
+    }
+
+    #[test]
+    fn accepts_a_signed_message_once() {
+        let auth = Authenticator::new("secret");
+        let payload = sign("SUBONE/start", "on", "n1", NOW);
+        assert_eq!(
+            auth.verify_at("SUBONE/start", &payload, Some(NOW)),
+            Ok("on")
+        );
+        assert_eq!(
+            auth.verify_at("SUBONE/start", &payload, Some(NOW)),
+            Err(AuthError::Replay)
+        );
+        assert_eq!(
+            auth.verify_at(
+                "SUBONE/end",
+                &sign("SUBONE/start", "on", "n2", NOW),
+                Some(NOW)
+            ),
+            Err(AuthError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_a_replay_after_a_reboot() {
+        let storage: SharedStorage = Arc::new(Mutex::new(MemoryStorage::new()));
+        let before = Authenticator::persistent("secret", storage.clone());
+        let payload = sign("SUBONE/start", "on", "n1", NOW);
+        assert_eq!(
+            before.verify_at("SUBONE/start", &payload, Some(NOW)),
+            Ok("on")
+        );
+
+        let after = Authenticator::persistent("secret", storage);
+        assert_eq!(
+            after.verify_at("SUBONE/start", &payload, Some(NOW + 60)),
+            Err(AuthError::Replay)
+        );
+        let newer = sign("SUBONE/start", "on", "n2", NOW + 1);
+        assert_eq!(
+            after.verify_at("SUBONE/start", &newer, Some(NOW + 60)),
+            Ok("on")
+        );
+    }
+
+    #[test]
+    fn rejects_signed_messages_while_the_clock_is_unset() {
+        let auth = Authenticator::new("secret");
+        let payload = sign("SUBONE/start", "on", "n1", 0);
+        assert_eq!(
+            auth.verify_at("SUBONE/start", &payload, None),
+            Err(AuthError::ClockUnset)
+        );
+        assert_eq!(
+            auth.verify_at("SUBONE/start", &payload, Some(NOW)),
+            Err(AuthError::Stale)
+        );
+    }
+}
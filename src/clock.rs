@@ -0,0 +1,179 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::{settimeofday, timeval};
+use log::info;
+
+/// Earliest year a modem date is believed. Until the network has set it, the EC200T reports a
+/// date in 1980.
+const MIN_VALID_YEAR: i64 = 2024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    /// `AT+CCLK?`, kept up to date by the network with `AT+CTZU=1`.
+    Network,
+    /// `AT+QNTP`
+    Ntp,
+}
+
+/// Wall-clock time at a point of the monotonic clock.
+#[derive(Debug, Clone, Copy)]
+struct Sync {
+    unix: u64,
+    at: Instant,
+    source: TimeSource,
+}
+
+static CLOCK: Mutex<Option<Sync>> = Mutex::new(None);
+
+/// Takes `unix` as the current time. The system clock is set as well, so log records carry it.
+pub fn set(unix: u64, source: TimeSource) {
+    let previous = now();
+    *CLOCK.lock().unwrap() = Some(Sync {
+        unix,
+        at: Instant::now(),
+        source,
+    });
+
+    match previous {
+        None => info!("Clock set to {} from {:?}", unix, source),
+        Some(previous) if previous.abs_diff(unix) > 1 => {
+            info!(
+                "Clock stepped from {} to {} by {:?}",
+                previous, unix, source
+            )
+        }
+        Some(_) => {}
+    }
+
+    // A host build leaves the system clock alone.
+    #[cfg(target_os = "espidf")]
+    {
+        let time = timeval {
+            tv_sec: unix as _,
+            tv_usec: 0,
+        };
+        unsafe {
+            settimeofday(&time, core::ptr::null());
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, `None` until the clock has been set.
+pub fn now() -> Option<u64> {
+    CLOCK
+        .lock()
+        .unwrap()
+        .map(|x| x.unix + x.at.elapsed().as_secs())
+}
+
+pub fn source() -> Option<TimeSource> {
+    CLOCK.lock().unwrap().map(|x| x.source)
+}
+
+/// Milliseconds since the clock was last set, `None` if it never was.
+pub fn age() -> Option<u64> {
+    CLOCK
+        .lock()
+        .unwrap()
+        .map(|x| x.at.elapsed().as_millis() as u64)
+}
+
+/// Parses `yy/MM/dd,hh:mm:ss±zz` (`+CCLK`) or `yyyy/MM/dd,hh:mm:ss±zz` (`+QNTP`), local time
+/// with the offset from UTC in quarter hours, into Unix seconds.
+pub fn parse_modem_time(time: &str) -> Option<u64> {
+    let (date, time) = time.trim().trim_matches('"').split_once(',')?;
+
+    let date = date
+        .split('/')
+        .map(|x| x.parse::<i64>().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    let (year, month, day) = match date[..] {
+        [year, month, day] => (year, month, day),
+        _ => return None,
+    };
+    let year = match year {
+        0..=69 => 2000 + year,
+        70..=99 => 1900 + year,
+        year => year,
+    };
+
+    let (time, zone) = match time.find(['+', '-']) {
+        Some(i) => (&time[..i], time[i..].parse::<i64>().ok()?),
+        None => (time, 0),
+    };
+    let time = time
+        .split(':')
+        .map(|x| x.parse::<i64>().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    let (hour, minute, second) = match time[..] {
+        [hour, minute, second] => (hour, minute, second),
+        _ => return None,
+    };
+
+    if year < MIN_VALID_YEAR
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return None;
+    }
+
+    let unix = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second
+        - zone * 15 * 60;
+    u64::try_from(unix).ok()
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarter_hour_zone_is_taken_off() {
+        // +22 is UTC+5:30, -14 is UTC-3:30.
+        assert_eq!(
+            parse_modem_time("\"24/11/22,10:15:30+22\""),
+            Some(1732250730)
+        );
+        assert_eq!(parse_modem_time("24/11/22,10:15:30-14"), Some(1732283130));
+        assert_eq!(parse_modem_time("2024/11/22,04:45:30+00"), Some(1732250730));
+    }
+
+    #[test]
+    fn unset_clock_is_rejected() {
+        assert_eq!(parse_modem_time("\"80/01/06,00:00:05+00\""), None);
+        assert_eq!(parse_modem_time("24/13/01,00:00:00+00"), None);
+        assert_eq!(parse_modem_time("24/11/22"), None);
+    }
+
+    #[test]
+    fn leap_days_are_counted() {
+        assert_eq!(parse_modem_time("24/02/29,12:00:00+00"), Some(1709208000));
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(
+            days_from_civil(2024, 3, 1) - days_from_civil(2024, 2, 28),
+            2
+        );
+        assert_eq!(
+            days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28),
+            1
+        );
+        assert_eq!(
+            days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28),
+            2
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use core::fmt;
+
+use serde_json::{json, Map, Value};
+
+/// Name of the command topic, `SUBONE/cmd`, and of its replies, `RTONE/cmd/<id>`.
+pub const COMMAND_TOPIC: &str = "cmd";
+
+/// Longest command id, it becomes a topic level of the reply.
+const MAX_ID_LEN: usize = 32;
+
+/// Every field a command may carry; anything else is answered with an error.
+const FIELDS: [&str; 3] = ["cmd", "id", "ttl"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlAction {
+    On,
+    Off,
+    Status,
+}
+
+impl ControlAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControlAction::On => "on",
+            ControlAction::Off => "off",
+            ControlAction::Status => "status",
+        }
+    }
+
+    pub fn from_str(action: &str) -> Option<ControlAction> {
+        match action {
+            "on" => Some(ControlAction::On),
+            "off" => Some(ControlAction::Off),
+            "status" => Some(ControlAction::Status),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    Malformed,
+    MissingCommand,
+    UnknownCommand(String),
+    InvalidId,
+    InvalidTtl,
+    UnknownField(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Malformed => write!(f, "Malformed JSON payload"),
+            CommandError::MissingCommand => write!(f, "Missing cmd"),
+            CommandError::UnknownCommand(cmd) => write!(f, "Unknown cmd {}", cmd),
+            CommandError::InvalidId => {
+                write!(f, "id must be 1 to {} of A-Z a-z 0-9 - _", MAX_ID_LEN)
+            }
+            CommandError::InvalidTtl => write!(f, "ttl must be a positive whole number of minutes"),
+            CommandError::UnknownField(field) => write!(f, "Unknown field {}", field),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// A control command received on `SUBONE/cmd`:
+/// `{"cmd":"on","id":"abc","ttl":60}`.
+///
+/// `cmd` is `on`, `off` or `status`. `id` is optional and echoed in the reply topic,
+/// `RTONE/cmd/abc`, so the sender can match the acknowledgement to the request. `ttl` makes an
+/// `on` a timed run of that many minutes, as `minutes` does on `SUBONE/start`. Any other field
+/// is rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlRequest {
+    pub action: ControlAction,
+    pub id: Option<String>,
+    pub minutes: Option<u32>,
+}
+
+impl ControlRequest {
+    /// Parses `payload`. On an error the id is returned with it when it could be read, so the
+    /// error reply still reaches the sender.
+    pub fn parse(payload: &str) -> Result<ControlRequest, (Option<String>, CommandError)> {
+        let object = serde_json::from_str::<Map<String, Value>>(payload.trim())
+            .map_err(|_| (None, CommandError::Malformed))?;
+
+        let id = match object.get("id") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(id)) if is_valid_id(id) => Some(id.clone()),
+            Some(_) => return Err((None, CommandError::InvalidId)),
+        };
+
+        if let Some(field) = object.keys().find(|x| !FIELDS.contains(&x.as_str())) {
+            return Err((id, CommandError::UnknownField(field.clone())));
+        }
+
+        let action = match object.get("cmd") {
+            Some(Value::String(cmd)) => ControlAction::from_str(cmd)
+                .ok_or_else(|| (id.clone(), CommandError::UnknownCommand(cmd.clone())))?,
+            _ => return Err((id, CommandError::MissingCommand)),
+        };
+
+        let minutes = match object.get("ttl") {
+            None | Some(Value::Null) => None,
+            Some(ttl) => match ttl.as_u64().and_then(|x| u32::try_from(x).ok()) {
+                Some(minutes) if minutes > 0 => Some(minutes),
+                _ => return Err((id, CommandError::InvalidTtl)),
+            },
+        };
+
+        Ok(ControlRequest {
+            action,
+            id,
+            minutes,
+        })
+    }
+}
+
+/// `true` if `id` can be used as a topic level.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+/// Name of the reply topic for the command `id`, `cmd/<id>`, or `cmd` without one.
+pub fn reply_name(id: Option<&str>) -> String {
+    match id {
+        Some(id) => format!("{}/{}", COMMAND_TOPIC, id),
+        None => COMMAND_TOPIC.to_string(),
+    }
+}
+
+/// Payload for `RTONE/cmd/<id>`: `{"id":"abc","cmd":"on","status":"STR:3600"}`, or
+/// `{"id":"abc","error":"Unknown cmd fly"}`.
+pub fn reply(id: Option<&str>, result: Result<(ControlAction, String), CommandError>) -> String {
+    let mut object = match result {
+        Ok((action, status)) => json!({ "cmd": action.as_str(), "status": status }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    if let Some(id) = id {
+        object["id"] = Value::from(id);
+    }
+    object.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttl_makes_a_timed_run() {
+        assert_eq!(
+            ControlRequest::parse(r#"{"cmd":"on","id":"abc","ttl":60}"#),
+            Ok(ControlRequest {
+                action: ControlAction::On,
+                id: Some("abc".to_string()),
+                minutes: Some(60),
+            })
+        );
+        assert_eq!(
+            ControlRequest::parse(r#"{"cmd":"on","id":"abc","ttl":0}"#),
+            Err((Some("abc".to_string()), CommandError::InvalidTtl))
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert_eq!(
+            ControlRequest::parse(r#"{"cmd":"on","id":"abc","minutes":60}"#),
+            Err((
+                Some("abc".to_string()),
+                CommandError::UnknownField("minutes".to_string())
+            ))
+        );
+        assert_eq!(
+            reply(
+                Some("abc"),
+                Err(CommandError::UnknownField("minutes".to_string()))
+            ),
+            r#"{"error":"Unknown field minutes","id":"abc"}"#
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use log::info;
+use serde_json::{Map, Value};
+
+use crate::settings::{Settings, SettingsError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    /// `status_ms=5000;topic_prefix=PUMP1`, entries separated by `;` or newlines.
+    KeyValue,
+    /// `{"status_ms":5000,"topic_prefix":"PUMP1"}`
+    Json,
+}
+
+/// Settings changes received on `SUBONE/config`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigRequest {
+    pub format: ConfigFormat,
+    pub entries: Vec<(String, String)>,
+}
+
+/// What became of each entry of a [`ConfigRequest`].
+#[derive(Debug, Clone)]
+pub struct ConfigOutcome {
+    pub format: ConfigFormat,
+    pub settings: Settings,
+    pub results: Vec<(String, Result<String, SettingsError>)>,
+}
+
+impl ConfigRequest {
+    pub fn parse(payload: &str) -> Result<ConfigRequest, &'static str> {
+        let payload = payload.trim();
+        if payload.starts_with('{') {
+            return ConfigRequest::parse_json(payload);
+        }
+
+        let entries = payload
+            .split([';', '\n'])
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| match x.split_once('=') {
+                Some((key, value)) => (key.trim().to_string(), value.trim().to_string()),
+                None => (x.to_string(), String::new()),
+            })
+            .collect::<Vec<(String, String)>>();
+
+        if entries.is_empty() {
+            return Err("Empty payload");
+        }
+
+        Ok(ConfigRequest {
+            format: ConfigFormat::KeyValue,
+            entries,
+        })
+    }
+
+    fn parse_json(payload: &str) -> Result<ConfigRequest, &'static str> {
+        let object = serde_json::from_str::<Map<String, Value>>(payload)
+            .map_err(|_| "Malformed JSON payload")?;
+
+        let entries = object
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(value) => value,
+                    Value::Null => String::new(),
+                    value => value.to_string(),
+                };
+                (key, value)
+            })
+            .collect::<Vec<(String, String)>>();
+
+        if entries.is_empty() {
+            return Err("Empty payload");
+        }
+
+        Ok(ConfigRequest {
+            format: ConfigFormat::Json,
+            entries,
+        })
+    }
+
+    /// Applies the entries to a copy of `current` one by one. An entry that does not parse, or
+    /// leaves the settings invalid, is skipped and reported without affecting the others, as is
+    /// one for a key [`Settings::check_remote`] refuses.
+    pub fn apply(&self, current: &Settings) -> ConfigOutcome {
+        let mut settings = current.clone();
+        let mut results = Vec::new();
+
+        for (key, value) in &self.entries {
+            let mut next = settings.clone();
+            let result = Settings::check_remote(key)
+                .and_then(|_| next.set(key, value))
+                .and_then(|_| next.validate());
+            match result {
+                Ok(()) => {
+                    info!("Config {} = {}", key, value);
+                    results.push((key.clone(), Ok(next.get(key).unwrap_or_default())));
+                    settings = next;
+                }
+                Err(e) => {
+                    info!("Config {} rejected: {}", key, e);
+                    results.push((key.clone(), Err(e)));
+                }
+            }
+        }
+
+        ConfigOutcome {
+            format: self.format,
+            settings,
+            results,
+        }
+    }
+}
+
+impl ConfigOutcome {
+    /// Marks every applied entry as failed, used when the settings could not be stored.
+    pub fn fail(&mut self, error: SettingsError) {
+        for (_, result) in self.results.iter_mut() {
+            if result.is_ok() {
+                *result = Err(error.clone());
+            }
+        }
+    }
+
+    /// Payload for `RTONE/config`: every key with its applied value or its error, in the format
+    /// of the request.
+    pub fn reply(&self) -> String {
+        match self.format {
+            ConfigFormat::KeyValue => self
+                .results
+                .iter()
+                .map(|(key, result)| match result {
+                    Ok(value) => format!("{}={}", key, value),
+                    Err(e) => format!("{}=ERROR:{}", key, e),
+                })
+                .collect::<Vec<String>>()
+                .join(";"),
+            ConfigFormat::Json => {
+                let mut object = Map::new();
+                for (key, result) in &self.results {
+                    let value = match result {
+                        Ok(value) => Value::String(value.clone()),
+                        Err(e) => {
+                            let mut error = Map::new();
+                            error.insert("error".to_string(), Value::String(e.to_string()));
+                            Value::Object(error)
+                        }
+                    };
+                    object.insert(key.clone(), value);
+                }
+                Value::Object(object).to_string()
+            }
+        }
+    }
+
+    /// Reply for a payload that could not be parsed at all.
+    pub fn malformed(error: &str) -> String {
+        format!("ERROR:{}", error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_secret_is_refused_over_config() {
+        let request = ConfigRequest::parse("auth_secret=0123456789abcdef;status_ms=5000").unwrap();
+        let current = Settings::default();
+        let outcome = request.apply(&current);
+        assert_eq!(outcome.settings.auth_secret, current.auth_secret);
+        assert_eq!(
+            outcome.results[0].1,
+            Err(SettingsError::LocalOnly("auth_secret"))
+        );
+        assert!(outcome.results[1].1.is_ok());
+    }
+}
@@ -1,6 +1,40 @@
+use core::ops::RangeInclusive;
+
 pub const RELAY_CHANGE_DELAY: u32 = 500;
 pub const AT_RESTART_DELAY: u32 = 1500;
 pub const DEUBGLOGS: bool = true;
 pub const ATSTATUS: u64 = 10000;
 pub const ATREAD: u64 = 500;
 pub const ATRESTART: u64 = 1000 * 60 * 10;
+pub const ATSTATUS_RANGE: RangeInclusive<u64> = 1000..=1000 * 60 * 60;
+pub const ATREAD_RANGE: RangeInclusive<u64> = 100..=1000 * 10;
+pub const ATRESTART_RANGE: RangeInclusive<u64> = 1000 * 60 * 5..=1000 * 60 * 60 * 24 * 7;
+pub const ATWATCHDOG: u64 = 1000;
+pub const AT_MAX_RETRIES: u8 = 3;
+pub const AT_RETRY_BACKOFF: u64 = 2000;
+pub const AT_RETRY_MAX_BACKOFF: u64 = 30000;
+pub const AT_BOOT_DELAY: u64 = 15000;
+pub const ATHEALTH: u64 = 1000 * 60;
+pub const AT_RECONNECT_BACKOFF: u64 = 5000;
+pub const AT_RECONNECT_MAX_BACKOFF: u64 = 1000 * 60 * 5;
+pub const PUBLISH_QUEUE_LEN: usize = 16;
+pub const PUBLISH_MAX_ATTEMPTS: u8 = 3;
+pub const OFFLINE_BUFFER_LEN: usize = 128;
+pub const OFFLINE_BATCH_LEN: usize = 32;
+pub const OFFLINE_SPILL_CHUNKS: u32 = 128;
+pub const CLOCK_RESYNC_INTERVAL: u64 = 1000 * 60 * 60;
+pub const RELAY_VERIFY_DELAY: u32 = 3000;
+pub const RELAY_ON_CURRENT: u32 = 200;
+pub const RELAY_OFF_CURRENT: u32 = 50;
+pub const RELAY_MAX_RUN: u32 = 240;
+pub const TRIP_OVER_VOLTAGE: u16 = 260;
+pub const TRIP_UNDER_VOLTAGE: u16 = 180;
+pub const TRIP_OVER_CURRENT: u32 = 10000;
+pub const TRIP_MIN_FREQUENCY: u16 = 450;
+pub const TRIP_MAX_FREQUENCY: u16 = 550;
+pub const TRIP_DEBOUNCE: u32 = 2000;
+pub const SCHEDULE_MAX_WINDOWS: usize = 16;
+pub const AUTH_NONCE_WINDOW: usize = 64;
+pub const AUTH_MAX_SKEW: u64 = 300;
+/// Control payload signing key flashed with the firmware, `AUTH_SECRET=... cargo build`.
+pub const AUTH_SECRET: Option<&str> = option_env!("AUTH_SECRET");
@@ -1,24 +1,297 @@
 use core::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::{
     delay::Ets,
-    gpio::{Gpio3, Gpio8, Gpio9, Output, PinDriver},
+    gpio::{Output, Pin, PinDriver},
     reset::restart,
 };
 use log::info;
 
-use crate::constants::{AT_RESTART_DELAY, RELAY_CHANGE_DELAY};
+use crate::clock;
+use crate::constants::AT_RESTART_DELAY;
+use crate::emon::Measurement;
+use crate::settings::{self, SettingValue, SettingsError, SettingsStorage};
 use crate::subscribe::NextControlCommand;
 
+// Kept next to the settings, see `settings::SETTINGS_NAMESPACE`.
+const KEY_RELAY_STATE: &str = "relay_state";
+const KEY_RELAY_SOURCE: &str = "relay_source";
+const KEY_RELAY_UPDATED_AT: &str = "relay_at";
+const KEY_RELAY_UNTIL: &str = "relay_until";
+const KEY_PLANNED_REBOOT: &str = "planned_reboot";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ControllerState {
     ON,
     OFF,
 }
 
-#[derive(Debug)]
+/// Who asked for a relay change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandSource {
+    /// `SUBONE/start`, `SUBONE/end`
+    Mqtt,
+    /// The restore policy at boot.
+    Restore,
+    /// A protection trip or the restart after one.
+    Protection,
+    /// A window of the on-device schedule.
+    Schedule,
+    /// The end of a timed run or the maximum run time.
+    Timer,
+}
+
+impl CommandSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandSource::Mqtt => "mqtt",
+            CommandSource::Restore => "restore",
+            CommandSource::Protection => "protection",
+            CommandSource::Schedule => "schedule",
+            CommandSource::Timer => "timer",
+        }
+    }
+}
+
+/// What the relay is set to at boot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestorePolicy {
+    AlwaysOff,
+    RestoreLast,
+    /// Restore the last state after a reboot the firmware asked for, stay off after a power loss
+    /// or crash.
+    RestoreIfPlanned,
+}
+
+impl RestorePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestorePolicy::AlwaysOff => "off",
+            RestorePolicy::RestoreLast => "last",
+            RestorePolicy::RestoreIfPlanned => "planned",
+        }
+    }
+
+    pub fn from_str(policy: &str) -> Option<RestorePolicy> {
+        match policy {
+            "off" => Some(RestorePolicy::AlwaysOff),
+            "last" => Some(RestorePolicy::RestoreLast),
+            "planned" => Some(RestorePolicy::RestoreIfPlanned),
+            _ => None,
+        }
+    }
+
+    /// The state to start in, given the persisted record and whether the reboot was planned.
+    pub fn target(&self, record: Option<&RelayRecord>, planned: bool) -> ControllerState {
+        let last = record.map_or(ControllerState::OFF, |x| x.state);
+        match self {
+            RestorePolicy::AlwaysOff => ControllerState::OFF,
+            RestorePolicy::RestoreLast => last,
+            RestorePolicy::RestoreIfPlanned if planned => last,
+            RestorePolicy::RestoreIfPlanned => ControllerState::OFF,
+        }
+    }
+}
+
+impl SettingValue for ControllerState {
+    fn encode(&self) -> Vec<u8> {
+        vec![matches!(self, ControllerState::ON) as u8]
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(ControllerState::OFF),
+            [1] => Some(ControllerState::ON),
+            _ => None,
+        }
+    }
+}
+
+impl SettingValue for CommandSource {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            b"mqtt" => Some(CommandSource::Mqtt),
+            b"restore" => Some(CommandSource::Restore),
+            b"protection" => Some(CommandSource::Protection),
+            b"schedule" => Some(CommandSource::Schedule),
+            b"timer" => Some(CommandSource::Timer),
+            _ => None,
+        }
+    }
+}
+
+/// The last commanded relay state, persisted so it survives a reboot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayRecord {
+    pub state: ControllerState,
+    pub source: CommandSource,
+    pub updated_at: Option<u64>,
+    /// Unix time the running load is stopped at, `None` without a countdown or while the clock
+    /// was not set.
+    pub run_until: Option<u64>,
+}
+
+impl RelayRecord {
+    pub fn load(storage: &dyn SettingsStorage) -> Option<RelayRecord> {
+        Some(RelayRecord {
+            state: settings::read(storage, KEY_RELAY_STATE)?,
+            source: settings::read(storage, KEY_RELAY_SOURCE)?,
+            updated_at: settings::read(storage, KEY_RELAY_UPDATED_AT),
+            run_until: settings::read(storage, KEY_RELAY_UNTIL),
+        })
+    }
+
+    pub fn save(&self, storage: &mut dyn SettingsStorage) -> Result<(), SettingsError> {
+        match self.updated_at {
+            Some(updated_at) => settings::write(storage, KEY_RELAY_UPDATED_AT, &updated_at)?,
+            None => storage.remove(KEY_RELAY_UPDATED_AT)?,
+        }
+        match self.run_until {
+            Some(run_until) => settings::write(storage, KEY_RELAY_UNTIL, &run_until)?,
+            None => storage.remove(KEY_RELAY_UNTIL)?,
+        }
+        settings::write(storage, KEY_RELAY_SOURCE, &self.source)?;
+        settings::write(storage, KEY_RELAY_STATE, &self.state)
+    }
+}
+
+/// Notes that the coming reboot is one the firmware asked for.
+pub fn mark_planned_reboot(storage: &mut dyn SettingsStorage) -> Result<(), SettingsError> {
+    settings::write(storage, KEY_PLANNED_REBOOT, &true)
+}
+
+/// Whether this boot follows [`mark_planned_reboot`], clearing the mark.
+pub fn take_planned_reboot(storage: &mut dyn SettingsStorage) -> bool {
+    let planned = settings::read(storage, KEY_PLANNED_REBOOT).unwrap_or(false);
+    if planned {
+        let _ = storage.remove(KEY_PLANNED_REBOOT);
+    }
+    planned
+}
+
+/// Restarts the ESP32. On a host build it panics instead, so a test that reaches it fails
+/// rather than ending the test run.
+pub fn reboot() -> ! {
+    #[cfg(target_os = "espidf")]
+    restart();
+    #[cfg(not(target_os = "espidf"))]
+    panic!("Reboot requested")
+}
+
+/// Holds a pulse on an output for `ms` milliseconds.
+fn pulse_delay(ms: u32) {
+    #[cfg(target_os = "espidf")]
+    Ets::delay_ms(ms);
+    #[cfg(not(target_os = "espidf"))]
+    std::thread::sleep(Duration::from_millis(ms as u64));
+}
+
+/// An output of the relay board: the start and stop coils and the modem reset line.
+pub trait RelayPin {
+    fn set_high(&mut self) -> Result<(), RelayFault>;
+    fn set_low(&mut self) -> Result<(), RelayFault>;
+}
+
+#[cfg(target_os = "espidf")]
+impl<'d, P: Pin> RelayPin for PinDriver<'d, P, Output> {
+    fn set_high(&mut self) -> Result<(), RelayFault> {
+        PinDriver::set_high(self).map_err(|_| RelayFault::Gpio)
+    }
+
+    fn set_low(&mut self) -> Result<(), RelayFault> {
+        PinDriver::set_low(self).map_err(|_| RelayFault::Gpio)
+    }
+}
+
+/// An output that only keeps its level, for host builds. Clones share the level.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPin {
+    high: Arc<AtomicBool>,
+}
+
+impl MemoryPin {
+    pub fn new() -> Self {
+        MemoryPin::default()
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.high.load(Ordering::Relaxed)
+    }
+}
+
+impl RelayPin for MemoryPin {
+    fn set_high(&mut self) -> Result<(), RelayFault> {
+        self.high.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), RelayFault> {
+        self.high.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// The outputs the relay controller drives.
+pub struct RelayPins<'a> {
+    pub start: Box<dyn RelayPin + 'a>,
+    pub stop: Box<dyn RelayPin + 'a>,
+    pub at_restart: Box<dyn RelayPin + 'a>,
+}
+
+impl RelayPins<'static> {
+    /// [`MemoryPin`]s, for host builds.
+    pub fn memory() -> Self {
+        RelayPins {
+            start: Box::new(MemoryPin::new()),
+            stop: Box::new(MemoryPin::new()),
+            at_restart: Box::new(MemoryPin::new()),
+        }
+    }
+}
+
+/// Why a relay change failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayFault {
+    /// The start/stop output could not be driven.
+    Gpio,
+    /// No current after starting: the contactor did not pull in, or nothing is connected.
+    NoLoad,
+    /// Current still flowing after stopping: the contactor did not drop out.
+    StuckContactor,
+}
+
+impl RelayFault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayFault::Gpio => "gpio",
+            RelayFault::NoLoad => "no-load",
+            RelayFault::StuckContactor => "stuck-contactor",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RelayControllerError {
-    StartError,
-    StopError,
+    StartError(RelayFault),
+    StopError(RelayFault),
+}
+
+impl RelayControllerError {
+    /// Payload for `RTONE/fault`: `STR:no-load`, `STP:stuck-contactor`, ...
+    pub fn report(&self) -> String {
+        match self {
+            RelayControllerError::StartError(fault) => format!("STR:{}", fault.as_str()),
+            RelayControllerError::StopError(fault) => format!("STP:{}", fault.as_str()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -30,8 +303,16 @@ pub enum RelaySuccess {
 impl fmt::Display for RelayControllerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            RelayControllerError::StartError => write!(f, "Failed to start the relay controller"),
-            RelayControllerError::StopError => write!(f, "Failed to stop the relay controller"),
+            RelayControllerError::StartError(fault) => {
+                write!(
+                    f,
+                    "Failed to start the relay controller: {}",
+                    fault.as_str()
+                )
+            }
+            RelayControllerError::StopError(fault) => {
+                write!(f, "Failed to stop the relay controller: {}", fault.as_str())
+            }
         }
     }
 }
@@ -41,10 +322,20 @@ impl std::error::Error for RelayControllerError {}
 pub struct RelayController<'a> {
     pub state: ControllerState,
     pub last_command: Option<NextControlCommand>,
+    pub source: Option<CommandSource>,
+    /// Unix time of the last state change, `None` if the clock was not set at the time.
     pub updated_at: Option<u64>,
-    pub start: PinDriver<'a, Gpio8, Output>,
-    pub stop: PinDriver<'a, Gpio9, Output>,
-    pub at_restart: PinDriver<'a, Gpio3, Output>,
+    /// Length of the start/stop pulse in milliseconds.
+    pub change_delay: u32,
+    /// Longest run in minutes, applied to every start, `0` for no limit.
+    pub max_run: u32,
+    /// When the running load is stopped.
+    pub run_until: Option<Instant>,
+    /// Unix deadline of the run before a reboot, waiting for the clock to be set.
+    pub restored_until: Option<u64>,
+    /// When the last change is checked against a PZEM reading.
+    pub verify_at: Option<Instant>,
+    pub pins: RelayPins<'a>,
 }
 
 impl<'a, 'b> RelayController<'a> {
@@ -52,17 +343,21 @@ impl<'a, 'b> RelayController<'a> {
         state: ControllerState,
         last_command: Option<NextControlCommand>,
         updated_at: Option<u64>,
-        start: PinDriver<'a, Gpio8, Output>,
-        stop: PinDriver<'a, Gpio9, Output>,
-        at_restart: PinDriver<'a, Gpio3, Output>,
+        change_delay: u32,
+        max_run: u32,
+        pins: RelayPins<'a>,
     ) -> Self {
         RelayController {
             state,
             last_command,
+            source: None,
             updated_at,
-            start,
-            stop,
-            at_restart,
+            change_delay,
+            max_run,
+            run_until: None,
+            restored_until: None,
+            verify_at: None,
+            pins,
         }
     }
 
@@ -77,21 +372,25 @@ impl<'a, 'b> RelayController<'a> {
             }
         }
 
-        if self.start.set_high().is_ok() {
-            Ets::delay_ms(RELAY_CHANGE_DELAY);
-            if self.start.set_low().is_ok() {
+        if self.pins.start.set_high().is_ok() {
+            pulse_delay(self.change_delay);
+            if self.pins.start.set_low().is_ok() {
                 self.state = ControllerState::ON;
+                self.updated_at = clock::now();
+                self.set_run_time(None);
                 return Ok(RelaySuccess::StartSuccess);
             }
         }
 
-        Err(RelayControllerError::StartError)
+        Err(RelayControllerError::StartError(RelayFault::Gpio))
     }
 
     pub fn stop(&mut self) -> Result<RelaySuccess, RelayControllerError> {
         match self.state {
             ControllerState::OFF => {
                 info!("Device is already OFF");
+                self.run_until = None;
+                self.restored_until = None;
                 return Ok(RelaySuccess::StopSuccess);
             }
             ControllerState::ON => {
@@ -99,27 +398,30 @@ impl<'a, 'b> RelayController<'a> {
             }
         }
 
-        if self.stop.set_low().is_ok() {
-            Ets::delay_ms(RELAY_CHANGE_DELAY);
-            if self.stop.set_high().is_ok() {
+        if self.pins.stop.set_low().is_ok() {
+            pulse_delay(self.change_delay);
+            if self.pins.stop.set_high().is_ok() {
                 self.state = ControllerState::OFF;
+                self.updated_at = clock::now();
+                self.run_until = None;
+                self.restored_until = None;
                 return Ok(RelaySuccess::StopSuccess);
             }
         }
 
-        Err(RelayControllerError::StopError)
+        Err(RelayControllerError::StopError(RelayFault::Gpio))
     }
 
     pub fn at_module_restart(&mut self) -> Result<RelaySuccess, RelayControllerError> {
         info!("Restarting the AT Module");
-        if self.at_restart.set_high().is_ok() {
-            Ets::delay_ms(AT_RESTART_DELAY);
-            if self.at_restart.set_low().is_ok() {
+        if self.pins.at_restart.set_high().is_ok() {
+            pulse_delay(AT_RESTART_DELAY);
+            if self.pins.at_restart.set_low().is_ok() {
                 info!("AT Module Restarted");
                 return Ok(RelaySuccess::StartSuccess);
             }
         }
-        Err(RelayControllerError::StartError)
+        Err(RelayControllerError::StartError(RelayFault::Gpio))
     }
 
     pub fn set_state(
@@ -132,10 +434,191 @@ impl<'a, 'b> RelayController<'a> {
         }
     }
 
-    pub fn status(&self) -> String {
+    /// Stops the running load after `minutes`, or after [`RelayController::max_run`] if that is
+    /// sooner or `minutes` is `None`. Replaces the countdown running.
+    pub fn set_run_time(&mut self, minutes: Option<u32>) {
+        let limit = [minutes.unwrap_or(0), self.max_run]
+            .into_iter()
+            .filter(|x| *x > 0)
+            .min();
+        self.run_until = limit.map(|x| Instant::now() + Duration::from_secs(x as u64 * 60));
+        self.restored_until = None;
+        if let Some(limit) = limit {
+            info!("Device runs for {} minutes at most", limit);
+        }
+    }
+
+    /// Keeps the deadline of a run persisted before a reboot, so the reboot does not extend
+    /// it. Applied by [`RelayController::apply_restored_deadline`] once the clock is set.
+    pub fn restore_deadline(&mut self, until: u64) {
+        self.restored_until = Some(until);
+    }
+
+    /// Shortens the countdown to the restored deadline, `now` being the Unix time.
+    pub fn apply_restored_deadline(&mut self, now: u64) {
+        let until = match self.restored_until.take() {
+            Some(until) => until,
+            None => return,
+        };
+        if self.state == ControllerState::OFF {
+            return;
+        }
+        info!("Restored run ends in {}s", until.saturating_sub(now));
+        let deadline = Instant::now() + Duration::from_secs(until.saturating_sub(now));
+        self.run_until = Some(self.run_until.map_or(deadline, |x| x.min(deadline)));
+    }
+
+    /// Time left until the running load is stopped, `None` if it runs until told otherwise.
+    pub fn remaining(&self) -> Option<Duration> {
         match self.state {
-            ControllerState::ON => "STR".to_string(),
-            ControllerState::OFF => "STP".to_string(),
+            ControllerState::ON => self
+                .run_until
+                .map(|x| x.saturating_duration_since(Instant::now())),
+            ControllerState::OFF => None,
         }
     }
+
+    /// `true` once the countdown of the running load has expired.
+    pub fn run_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Checks the change just made once the load had `delay` milliseconds to settle. `0`
+    /// disables the check.
+    pub fn schedule_verification(&mut self, delay: u32) {
+        self.verify_at = match delay {
+            0 => None,
+            delay => Some(Instant::now() + Duration::from_millis(delay as u64)),
+        };
+    }
+
+    /// `true` once, when the scheduled verification is due.
+    pub fn verification_due(&mut self) -> bool {
+        match self.verify_at {
+            Some(at) if at <= Instant::now() => {
+                self.verify_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks a reading taken after the settle time against the state and returns the fault
+    /// on a mismatch. The state stays the commanded one, which is what is persisted and
+    /// restored; the fault is only reported.
+    pub fn verify(
+        &self,
+        measurement: &Measurement,
+        on_current: u32,
+        off_current: u32,
+    ) -> Result<(), RelayControllerError> {
+        let current = (measurement.current * 1000.0) as u32;
+        match self.state {
+            ControllerState::ON if current < on_current => {
+                Err(RelayControllerError::StartError(RelayFault::NoLoad))
+            }
+            ControllerState::OFF if current > off_current => {
+                Err(RelayControllerError::StopError(RelayFault::StuckContactor))
+            }
+            _ => {
+                info!("Relay {} confirmed at {}mA", self.status(), current);
+                Ok(())
+            }
+        }
+    }
+
+    /// What to persist after a change.
+    pub fn record(&self) -> Option<RelayRecord> {
+        Some(RelayRecord {
+            state: self.state,
+            source: self.source?,
+            updated_at: self.updated_at,
+            run_until: match self.restored_until {
+                Some(until) => Some(until),
+                None => clock::now()
+                    .zip(self.remaining())
+                    .map(|(now, left)| now + left.as_secs()),
+            },
+        })
+    }
+
+    /// `STP`, `STR`, or `STR:<seconds>` while a countdown runs.
+    pub fn status(&self) -> String {
+        match (self.state, self.remaining()) {
+            (ControllerState::ON, Some(remaining)) => format!("STR:{}", remaining.as_secs()),
+            (ControllerState::ON, None) => "STR".to_string(),
+            (ControllerState::OFF, _) => "STP".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::MemoryStorage;
+
+    fn controller(state: ControllerState) -> RelayController<'static> {
+        RelayController::new(state, None, None, 0, 0, RelayPins::memory())
+    }
+
+    fn reading(current: f32) -> Measurement {
+        Measurement {
+            current,
+            ..Measurement::default()
+        }
+    }
+
+    #[test]
+    fn record_round_trip_with_deadline() {
+        let mut storage = MemoryStorage::new();
+        let record = RelayRecord {
+            state: ControllerState::ON,
+            source: CommandSource::Mqtt,
+            updated_at: Some(1_700_000_000),
+            run_until: Some(1_700_001_800),
+        };
+        record.save(&mut storage).unwrap();
+        assert_eq!(RelayRecord::load(&storage), Some(record));
+
+        RelayRecord {
+            run_until: None,
+            ..record
+        }
+        .save(&mut storage)
+        .unwrap();
+        assert_eq!(RelayRecord::load(&storage).unwrap().run_until, None);
+    }
+
+    #[test]
+    fn restored_deadline_shortens_the_run() {
+        let mut relay = controller(ControllerState::ON);
+        relay.source = Some(CommandSource::Restore);
+        relay.max_run = 240;
+        relay.set_run_time(None);
+        relay.restore_deadline(1_000 + 600);
+        assert_eq!(relay.record().unwrap().run_until, Some(1_600));
+
+        relay.apply_restored_deadline(1_000);
+        assert!(relay.remaining().unwrap() <= Duration::from_secs(600));
+
+        // Past while the device was down, so the run is over.
+        relay.restore_deadline(900);
+        relay.apply_restored_deadline(1_000);
+        assert!(relay.run_expired());
+    }
+
+    #[test]
+    fn verify_reports_a_mismatch_without_changing_the_state() {
+        let on = controller(ControllerState::ON);
+        assert_eq!(
+            on.verify(&reading(0.0), 100, 50).map_err(|e| e.report()),
+            Err("STR:no-load".to_string())
+        );
+        assert_eq!(on.state, ControllerState::ON);
+
+        let off = controller(ControllerState::OFF);
+        assert!(off.verify(&reading(2.0), 100, 50).is_err());
+        assert_eq!(off.state, ControllerState::OFF);
+        assert!(off.verify(&reading(0.0), 100, 50).is_ok());
+    }
 }
@@ -1,8 +1,8 @@
-use esp_idf_svc::hal::uart::{AsyncUartDriver, UartDriver};
+use crate::transport::AtTransport;
 
 use core::fmt::Display;
 use core::fmt::Formatter;
-const ADDR_DEFAULT: u8 = 0xf8; // Universal address for single-slave environment
+pub const ADDR_DEFAULT: u8 = 0xf8; // Universal address for single-slave environment
 const ADDR_MIN: u8 = 0x01;
 const ADDR_MAX: u8 = 0xf7;
 //
@@ -50,6 +50,11 @@ impl<WriteError: Display, ReadError: Display> Display for Error<WriteError, Read
     }
 }
 //
+/// `true` for the single-slave address and every address a slave can be assigned.
+pub fn is_valid_address(addr: u8) -> bool {
+    addr == ADDR_DEFAULT || (ADDR_MIN..=ADDR_MAX).contains(&addr)
+}
+
 // 16-bit cyclic redundancy check (CRC).
 fn crc_write(buf: &mut [u8]) {
     let n = buf.len();
@@ -100,22 +105,22 @@ pub struct Measurement {
     pub alarm: bool,
 }
 
-/// Struct representing a PZEM004T sensor connected to a serial bus.
-pub struct Pzem<'a> {
-    pub uart: AsyncUartDriver<'a, UartDriver<'a>>,
+/// Struct representing a PZEM004T sensor connected to a serial bus, the second UART on the
+/// ESP32.
+pub struct Pzem<S: AtTransport> {
+    pub uart: S,
     pub addr: u8,
 }
 //
-impl<'a> Pzem<'a> {
+impl<S: AtTransport> Pzem<S> {
     /// Creates a new PZEM004T struct, consuming the serial peripheral.
     ///
-    /// When omitting the `addr` argument, will use the default general address for a
-    /// single-slave environment, namely `0xf8`.
+    /// Use [`ADDR_DEFAULT`], the general address for a single-slave environment, namely `0xf8`,
+    /// unless several sensors share the bus.
     ///
-    /// Can return `Err(Error::IllegalAddress)` if `addr` is not in range of legal addresses `[0x01..0xf8]`.
-    pub fn new<'b>(uart: AsyncUartDriver<'a, UartDriver<'a>>) -> Result<Self, bool> {
-        let addr = ADDR_DEFAULT;
-        if addr != ADDR_DEFAULT && (addr < ADDR_MIN || addr > ADDR_MAX) {
+    /// Returns `Err(false)` if `addr` is not in range of legal addresses `[0x01..0xf8]`.
+    pub fn new(uart: S, addr: u8) -> Result<Self, bool> {
+        if !is_valid_address(addr) {
             return Err(false);
         }
         Ok(Self { uart, addr })
@@ -184,4 +189,35 @@ impl<'a> Pzem<'a> {
         result_convert(&resp, m);
         Ok(())
     }
+
+    /// Sets the power alarm threshold, in watts.
+    pub async fn set_threshold(
+        &mut self,
+        threshold: u16,
+    ) -> Result<(), Error<MyWriteError, MyReadError>> {
+        self.write_param(PARAM_THRESHOLD, threshold).await
+    }
+
+    async fn write_param(
+        &mut self,
+        param: u16,
+        value: u16,
+    ) -> Result<(), Error<MyWriteError, MyReadError>> {
+        let mut buf = [
+            self.addr,          // Slave address
+            CMD_WRITE_PARAM,    // Function code: write single register
+            (param >> 8) as u8, // Register address high byte
+            (param >> 0) as u8, // Register address low byte
+            (value >> 8) as u8, // Register value high byte
+            (value >> 0) as u8, // Register value low byte
+            0,                  // CRC
+            0,                  // CRC
+        ];
+
+        crc_write(&mut buf);
+
+        // The response echoes the request.
+        let mut resp: [u8; 8] = [0; 8];
+        self.communicate(&buf, &mut resp).await
+    }
 }
@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::info;
+
+use crate::atcommands::{
+    AtCommand, MQTT_CONFIG_COMMAND_SEQUENCE, MQTT_CONNECTION_COMMAND_SEQUENCE,
+    STATUS_COMMAND_SEQUENCE,
+};
+use crate::settings::Settings;
+use crate::subscribe::TopicRouter;
+use crate::topics::{DeviceIdentity, Topics};
+use crate::transport::AtTransport;
+
+/// Network details the emulator reports for the status queries.
+#[derive(Debug, Clone)]
+pub struct EmulatorProfile {
+    pub sim_status: u8,
+    pub imei: String,
+    pub iccid: String,
+    pub operator: String,
+    pub act: u8,
+    pub rssi: u8,
+    pub ber: u8,
+    pub network_act: String,
+    pub network_oper: String,
+    pub band: String,
+    pub channel: u32,
+    pub registration: u8,
+    /// `+CCLK` time, `yy/MM/dd,hh:mm:ss±zz`.
+    pub clock: String,
+}
+
+impl Default for EmulatorProfile {
+    fn default() -> Self {
+        EmulatorProfile {
+            sim_status: 7,
+            imei: "864792041234567".to_string(),
+            iccid: "89918580400012345678".to_string(),
+            operator: "Jio 4G".to_string(),
+            act: 7,
+            rssi: 24,
+            ber: 99,
+            network_act: "FDD LTE".to_string(),
+            network_oper: "40587".to_string(),
+            band: "LTE BAND 3".to_string(),
+            channel: 1350,
+            registration: 1,
+            clock: "24/11/22,10:15:30+22".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct EmulatorState {
+    profile: EmulatorProfile,
+    scripts: HashMap<String, Vec<String>>,
+    /// Like `scripts`, but dropped after the first match.
+    once: HashMap<String, Vec<String>>,
+    commands: Vec<String>,
+    published: Vec<(String, String)>,
+    mqtt_open: bool,
+    mqtt_connected: bool,
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+enum Pending {
+    Command,
+    Payload {
+        msg_id: String,
+        topic: String,
+        length: usize,
+    },
+}
+
+/// Host side stand-in for the Quectel EC200T.
+///
+/// Sits on the far end of an [`AtTransport`] and answers the commands the firmware sends with
+/// the same lines a real module produces: `OK`/`ERROR`, the `+QINISTAT`/`+COPS`/`+CSQ`/
+/// `+QNWINFO`/`+CREG` status replies, the MQTT `+QMTOPEN`/`+QMTCONN`/`+QMTSUB` URCs and the
+/// `>` prompt of `AT+QMTPUBEX`. Replies can be overridden per command prefix with
+/// [`ModemEmulator::script`], and unsolicited lines pushed with [`ModemEmulator::inject_urc`].
+///
+/// All methods take `&self`, so [`ModemEmulator::run`] can be joined with a future that drives
+/// the firmware side and injects URCs at the same time.
+pub struct ModemEmulator<T: AtTransport> {
+    transport: T,
+    state: Mutex<EmulatorState>,
+}
+
+impl<T: AtTransport> ModemEmulator<T> {
+    pub fn new(transport: T) -> Self {
+        ModemEmulator::with_profile(transport, EmulatorProfile::default())
+    }
+
+    pub fn with_profile(transport: T, profile: EmulatorProfile) -> Self {
+        ModemEmulator {
+            transport,
+            state: Mutex::new(EmulatorState {
+                profile,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Replaces the reply for every command starting with `prefix`.
+    ///
+    /// Each entry of `lines` is written as a separate `\r\n<line>\r\n` frame. An empty slice makes
+    /// the emulator stay silent, which is how a module that never answers is simulated.
+    pub fn script(&self, prefix: &str, lines: &[&str]) {
+        self.state.lock().unwrap().scripts.insert(
+            prefix.to_string(),
+            lines.iter().map(|x| x.to_string()).collect(),
+        );
+    }
+
+    /// Like [`ModemEmulator::script`], but only for the next command starting with `prefix`.
+    pub fn script_once(&self, prefix: &str, lines: &[&str]) {
+        self.state.lock().unwrap().once.insert(
+            prefix.to_string(),
+            lines.iter().map(|x| x.to_string()).collect(),
+        );
+    }
+
+    pub fn clear_script(&self, prefix: &str) {
+        self.state.lock().unwrap().scripts.remove(prefix);
+    }
+
+    /// Every command line received so far, without the trailing `\r\n`.
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// `(topic, payload)` of every `AT+QMTPUBEX` that completed.
+    pub fn published(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().published.clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().mqtt_connected
+    }
+
+    /// Sends an unsolicited line such as `+QMTSTAT: 0,1`.
+    pub async fn inject_urc(&self, line: &str) {
+        self.send(line).await;
+    }
+
+    /// Drops the MQTT connection and reports it with `+QMTSTAT: 0,<err_code>`.
+    pub async fn drop_connection(&self, err_code: u8) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.mqtt_connected = false;
+            state.mqtt_open = false;
+        }
+        self.send(&format!("+QMTSTAT: 0,{}", err_code)).await;
+    }
+
+    /// Delivers a message on `topic` the way `recv/mode` `0,0,1` reports it.
+    pub async fn inject_message(&self, msg_id: u16, topic: &str, payload: &str) {
+        let line = format!(
+            "+QMTRECV: 0,{},\"{}\",{},\"{}\"",
+            msg_id,
+            topic,
+            payload.len(),
+            payload
+        );
+        self.send(&line).await;
+    }
+
+    /// The commands a clean bring-up with `settings` and the default routes sends, in order.
+    pub fn expected_bring_up(&self, settings: &Settings) -> Vec<String> {
+        let topics = {
+            let state = self.state.lock().unwrap();
+            let identity = DeviceIdentity {
+                imei: Some(state.profile.imei.clone()),
+                iccid: Some(state.profile.iccid.clone()),
+            };
+            Topics::new(settings, &identity)
+        };
+        let router = TopicRouter::default();
+        STATUS_COMMAND_SEQUENCE
+            .iter()
+            .chain(MQTT_CONFIG_COMMAND_SEQUENCE.iter())
+            .chain(MQTT_CONNECTION_COMMAND_SEQUENCE.iter())
+            .map(|x| x.render(settings, &topics, &router).trim_end().to_string())
+            .collect()
+    }
+
+    /// Serves the transport until the firmware side closes it.
+    pub async fn run(&self) {
+        let mut pending = Pending::Command;
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; 256];
+
+        loop {
+            let len = match self.transport.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(len) => len,
+            };
+            buffer.extend_from_slice(&chunk[..len]);
+
+            loop {
+                match pending {
+                    Pending::Payload {
+                        ref msg_id,
+                        ref topic,
+                        length,
+                    } => {
+                        if buffer.len() < length {
+                            break;
+                        }
+                        let payload: Vec<u8> = buffer.drain(..length).collect();
+                        let payload = String::from_utf8_lossy(&payload).to_string();
+                        let msg_id = msg_id.clone();
+                        self.state
+                            .lock()
+                            .unwrap()
+                            .published
+                            .push((topic.clone(), payload));
+                        pending = Pending::Command;
+                        self.send("OK").await;
+                        self.send(&format!("+QMTPUBEX: 0,{},0", msg_id)).await;
+                    }
+                    Pending::Command => {
+                        let end = match buffer.iter().position(|&x| x == b'\n') {
+                            Some(end) => end,
+                            None => break,
+                        };
+                        let line: Vec<u8> = buffer.drain(..=end).collect();
+                        let line = String::from_utf8_lossy(&line).trim().to_string();
+                        if line.is_empty() {
+                            continue;
+                        }
+                        pending = self.handle_command(&line).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_command(&self, line: &str) -> Pending {
+        info!("Emulator received: {}", line);
+
+        let scripted = {
+            let mut state = self.state.lock().unwrap();
+            state.commands.push(line.to_string());
+            let once = state
+                .once
+                .keys()
+                .filter(|prefix| line.starts_with(prefix.as_str()))
+                .max_by_key(|prefix| prefix.len())
+                .cloned();
+            match once {
+                Some(prefix) => state.once.remove(&prefix),
+                None => state
+                    .scripts
+                    .iter()
+                    .filter(|(prefix, _)| line.starts_with(prefix.as_str()))
+                    .max_by_key(|(prefix, _)| prefix.len())
+                    .map(|(_, lines)| lines.clone()),
+            }
+        };
+
+        if let Some(lines) = scripted {
+            for line in lines {
+                self.send(&line).await;
+            }
+            return Pending::Command;
+        }
+
+        if let Some(params) = line.strip_prefix("AT+QMTPUBEX=") {
+            let params = split_params(params);
+            if params.len() < 6 || !self.is_connected() {
+                self.send("ERROR").await;
+                return Pending::Command;
+            }
+            let length = match params[5].parse::<usize>() {
+                Ok(length) => length,
+                Err(_) => {
+                    self.send("ERROR").await;
+                    return Pending::Command;
+                }
+            };
+            let _ = self.transport.write(b"\r\n> ").await;
+            return Pending::Payload {
+                msg_id: params[1].to_string(),
+                topic: params[4].to_string(),
+                length,
+            };
+        }
+
+        for line in self.default_reply(line) {
+            self.send(&line).await;
+        }
+        Pending::Command
+    }
+
+    fn default_reply(&self, line: &str) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let ok = "OK".to_string();
+
+        if line == AtCommand::AT.as_str().trim_end() {
+            return vec![ok];
+        }
+
+        if let Some(params) = line.strip_prefix("AT+QMTOPEN=") {
+            let params = split_params(params);
+            if params.len() < 3 {
+                return vec!["ERROR".to_string()];
+            }
+            state.host = Some(params[1].to_string());
+            state.port = params[2].parse().ok();
+            state.mqtt_open = true;
+            return vec![ok, "+QMTOPEN: 0,0".to_string()];
+        }
+
+        if line.starts_with("AT+QMTCONN=") {
+            if !state.mqtt_open {
+                return vec![ok, "+QMTCONN: 0,2".to_string()];
+            }
+            state.mqtt_connected = true;
+            return vec![ok, "+QMTCONN: 0,0,0".to_string()];
+        }
+
+        if let Some(params) = line.strip_prefix("AT+QMTSUB=") {
+            let params = split_params(params);
+            if params.len() < 2 || !state.mqtt_connected {
+                return vec!["ERROR".to_string()];
+            }
+            return vec![ok, format!("+QMTSUB: 0,{},0,0", params[1])];
+        }
+
+        let profile = &state.profile;
+        match line {
+            "AT+QINISTAT" => vec![format!("+QINISTAT: {}", profile.sim_status), ok],
+            "AT+GSN" => vec![profile.imei.clone(), ok],
+            "AT+QCCID" => vec![format!("+QCCID: {}", profile.iccid), ok],
+            "AT+CTZU=1" => vec![ok],
+            "AT+CCLK?" => vec![format!("+CCLK: \"{}\"", profile.clock), ok],
+            r if r.starts_with("AT+QNTP=") => {
+                vec![ok, format!("+QNTP: 0,\"20{}\"", profile.clock)]
+            }
+            "AT+COPS?" => vec![
+                format!("+COPS: 0,0,\"{}\",{}", profile.operator, profile.act),
+                ok,
+            ],
+            "AT+CSQ" => vec![format!("+CSQ: {},{}", profile.rssi, profile.ber), ok],
+            "AT+QNWINFO" => vec![
+                format!(
+                    "+QNWINFO: \"{}\",\"{}\",\"{}\",{}",
+                    profile.network_act, profile.network_oper, profile.band, profile.channel
+                ),
+                ok,
+            ],
+            "AT+CREG?" => vec![format!("+CREG: 0,{}", profile.registration), ok],
+            "AT+QMTOPEN?" => match (&state.host, state.port, state.mqtt_open) {
+                (Some(host), Some(port), true) => {
+                    vec![format!("+QMTOPEN: 0,\"{}\",{}", host, port), ok]
+                }
+                _ => vec![ok],
+            },
+            "AT+QMTCONN?" => match state.mqtt_connected {
+                true => vec!["+QMTCONN: 0,3".to_string(), ok],
+                false => vec![ok],
+            },
+            "AT+QMTDISC=0" => {
+                state.mqtt_connected = false;
+                state.mqtt_open = false;
+                vec![ok, "+QMTDISC: 0,0".to_string()]
+            }
+            "AT+QMTCLOSE=0" => {
+                state.mqtt_connected = false;
+                state.mqtt_open = false;
+                vec![ok, "+QMTCLOSE: 0,0".to_string()]
+            }
+            r if r.starts_with("AT+QMTCFG=") || r.starts_with("AT+QSSLCFG=") => vec![ok],
+            _ => vec!["ERROR".to_string()],
+        }
+    }
+
+    async fn send(&self, line: &str) {
+        let _ = self
+            .transport
+            .write(format!("\r\n{}\r\n", line).as_bytes())
+            .await;
+    }
+}
+
+fn split_params(params: &str) -> Vec<&str> {
+    params
+        .split(',')
+        .map(|x| x.trim().trim_matches('"'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::{poll_fn, Future};
+    use core::task::Poll;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_futures::yield_now;
+
+    use super::*;
+    use crate::at::{AtReplyTopic, AT};
+    use crate::controller::RelayPins;
+    use crate::offline::{OfflineBuffer, SharedBacklog};
+    use crate::publish::QoS;
+    use crate::retry::ReconnectBackoff;
+    use crate::settings::{MemoryStorage, SharedStorage};
+    use crate::transport::PipeTransport;
+
+    type HostAt = AT<'static, PipeTransport, PipeTransport>;
+
+    /// The firmware side on one end of a pipe, the emulator on the other, and a PZEM that never
+    /// answers.
+    fn setup() -> (HostAt, ModemEmulator<PipeTransport>, PipeTransport) {
+        let (uart, modem) = PipeTransport::pair();
+        let (serial, pzem) = PipeTransport::pair();
+        let storage: SharedStorage = Arc::new(Mutex::new(MemoryStorage::new()));
+        let backlog: SharedBacklog = Arc::new(Mutex::new(OfflineBuffer::new(8, None)));
+        let at = AT::with_transport(uart, RelayPins::memory(), serial, storage, backlog);
+        (at, ModemEmulator::new(modem), pzem)
+    }
+
+    /// Brings the firmware up against the emulator and runs `test` while it reads the modem,
+    /// for at most a few seconds.
+    fn drive<F: Future<Output = ()>>(
+        at: &HostAt,
+        emulator: &ModemEmulator<PipeTransport>,
+        test: F,
+    ) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let timeout = poll_fn(|_| match Instant::now() < deadline {
+            true => Poll::Pending,
+            false => Poll::Ready(()),
+        });
+        let firmware = async {
+            at.check_at().await.unwrap();
+            at.init().await;
+            select(at.read_serail(), test).await
+        };
+        match block_on(select(select(emulator.run(), firmware), timeout)) {
+            Either::First(Either::Second(Either::Second(()))) => {}
+            Either::First(_) => panic!("The firmware or the emulator stopped"),
+            Either::Second(()) => panic!("Timed out, commands: {:?}", emulator.commands()),
+        }
+    }
+
+    /// Resolves once `condition` holds. `block_on` polls in a loop, so no waker is needed.
+    async fn until(condition: impl Fn() -> bool) {
+        poll_fn(|_| match condition() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        })
+        .await
+    }
+
+    #[test]
+    fn brings_up_status_config_and_connection() {
+        let (at, emulator, _pzem) = setup();
+        let expected = emulator.expected_bring_up(&at.settings());
+        drive(&at, &emulator, async {
+            until(|| emulator.commands().len() >= expected.len() && at.is_connected()).await;
+        });
+
+        assert!(at.is_connected());
+        assert!(emulator.is_connected());
+        assert_eq!(emulator.commands(), expected);
+        assert_eq!(
+            at.network_status()
+                .registration
+                .map(|x| x.stat.is_registered()),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn publishes_once_connected() {
+        let (at, emulator, _pzem) = setup();
+        drive(&at, &emulator, async {
+            until(|| at.is_connected()).await;
+            at.publish("STR", AtReplyTopic::START, QoS::AtMostOnce, true)
+                .await;
+            until(|| !emulator.published().is_empty()).await;
+        });
+
+        let topic = AtReplyTopic::START.topic(&Topics::new(
+            &at.settings(),
+            &DeviceIdentity {
+                imei: Some(EmulatorProfile::default().imei),
+                iccid: Some(EmulatorProfile::default().iccid),
+            },
+        ));
+        let command = format!("AT+QMTPUBEX=0,0,0,1,\"{}\",3", topic);
+        assert!(emulator.commands().contains(&command));
+        assert_eq!(emulator.published(), vec![(topic, "STR".to_string())]);
+    }
+
+    #[test]
+    fn retries_a_failed_bring_up_step() {
+        let (at, emulator, _pzem) = setup();
+        at.with_module(|x| x.retry.policy.backoff = 20);
+        emulator.script_once("AT+CREG?", &["+CME ERROR: 30"]);
+        drive(&at, &emulator, async {
+            let ticks = async {
+                loop {
+                    at.check_timeouts().await;
+                    yield_now().await;
+                }
+            };
+            select(ticks, until(|| at.is_connected())).await;
+        });
+
+        let registrations = emulator
+            .commands()
+            .iter()
+            .filter(|x| x.as_str() == "AT+CREG?")
+            .count();
+        assert_eq!(registrations, 2);
+        assert!(emulator.is_connected());
+    }
+
+    #[test]
+    fn reconnects_after_a_failed_connect() {
+        let (at, emulator, _pzem) = setup();
+        at.with_module(|x| x.reconnect = ReconnectBackoff::new(20, 100));
+        drive(&at, &emulator, async {
+            until(|| at.is_connected()).await;
+            emulator.script_once("AT+QMTCONN=", &["ERROR"]);
+            emulator.drop_connection(1).await;
+            until(|| !at.is_connected()).await;
+
+            let ticks = async {
+                loop {
+                    at.check_timeouts().await;
+                    yield_now().await;
+                }
+            };
+            select(ticks, until(|| at.is_connected())).await;
+        });
+
+        let connects = emulator
+            .commands()
+            .iter()
+            .filter(|x| x.starts_with("AT+QMTCONN="))
+            .count();
+        assert_eq!(connects, 3);
+        assert!(emulator.is_connected());
+        assert_eq!(at.with_module(|x| x.reconnect.attempts()), 0);
+    }
+}
@@ -0,0 +1,249 @@
+use serde_json::{json, Value};
+
+use crate::offline::Sample;
+
+/// Version byte leading every [`BinaryEncoder`] record.
+pub const BINARY_VERSION: u8 = 1;
+
+/// Bytes of a [`BinaryEncoder`] record.
+pub const BINARY_RECORD_LEN: usize = 1 + 1 + 4 + 4 + 6 * 4;
+
+/// Encoding of the `RTONE/Power` and `RTONE/backlog` payloads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MeasurementFormat {
+    Csv,
+    Json,
+    Binary,
+}
+
+impl MeasurementFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MeasurementFormat::Csv => "csv",
+            MeasurementFormat::Json => "json",
+            MeasurementFormat::Binary => "binary",
+        }
+    }
+
+    pub fn from_str(format: &str) -> Option<MeasurementFormat> {
+        match format {
+            "csv" => Some(MeasurementFormat::Csv),
+            "json" => Some(MeasurementFormat::Json),
+            "binary" => Some(MeasurementFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn encoder(&self) -> &'static dyn MeasurementEncoder {
+        match self {
+            MeasurementFormat::Csv => &CsvEncoder,
+            MeasurementFormat::Json => &JsonEncoder,
+            MeasurementFormat::Binary => &BinaryEncoder,
+        }
+    }
+}
+
+pub trait MeasurementEncoder {
+    /// Payload for one sample. `device` identifies the sender where the format carries it.
+    fn encode(&self, sample: &Sample, device: &str) -> Vec<u8>;
+
+    /// Payload for several samples, oldest first.
+    fn encode_batch(&self, samples: &[Sample], device: &str) -> Vec<u8>;
+}
+
+/// `<voltage>,<current>,<power>,<energy>,<frequency>,<pf>,<alarm>,<seq>,<timestamp>`
+///
+/// The first six fields are the ones published before, so existing consumers keep working. A
+/// batch has one sample per line.
+pub struct CsvEncoder;
+
+impl CsvEncoder {
+    fn line(sample: &Sample) -> String {
+        let m = &sample.measurement;
+        format!(
+            "{},{},{},{},{},{},{},{},{}",
+            m.voltage,
+            m.current,
+            m.power,
+            m.energy,
+            m.frequency,
+            m.pf,
+            m.alarm as u8,
+            sample.sequence,
+            sample.timestamp
+        )
+    }
+}
+
+impl MeasurementEncoder for CsvEncoder {
+    fn encode(&self, sample: &Sample, _device: &str) -> Vec<u8> {
+        CsvEncoder::line(sample).into_bytes()
+    }
+
+    fn encode_batch(&self, samples: &[Sample], _device: &str) -> Vec<u8> {
+        samples
+            .iter()
+            .map(CsvEncoder::line)
+            .collect::<Vec<String>>()
+            .join("\n")
+            .into_bytes()
+    }
+}
+
+/// `{"device":"<imei>","seq":1,"ts":1700000000,"voltage":230.1,...,"alarm":false}`, a batch is
+/// `{"device":"<imei>","samples":[{"seq":1,...},...]}`.
+pub struct JsonEncoder;
+
+impl JsonEncoder {
+    fn object(sample: &Sample) -> Value {
+        let m = &sample.measurement;
+        json!({
+            "seq": sample.sequence,
+            "ts": sample.timestamp,
+            "voltage": JsonEncoder::number(m.voltage),
+            "current": JsonEncoder::number(m.current),
+            "power": JsonEncoder::number(m.power),
+            "energy": JsonEncoder::number(m.energy),
+            "frequency": JsonEncoder::number(m.frequency),
+            "pf": JsonEncoder::number(m.pf),
+            "alarm": m.alarm,
+        })
+    }
+
+    /// Goes through the shortest text form, so `230.1f32` is not sent as `230.10000610351562`.
+    fn number(value: f32) -> Value {
+        value
+            .to_string()
+            .parse::<f64>()
+            .map_or(Value::Null, Value::from)
+    }
+}
+
+impl MeasurementEncoder for JsonEncoder {
+    fn encode(&self, sample: &Sample, device: &str) -> Vec<u8> {
+        let mut object = JsonEncoder::object(sample);
+        object["device"] = Value::from(device);
+        object.to_string().into_bytes()
+    }
+
+    fn encode_batch(&self, samples: &[Sample], device: &str) -> Vec<u8> {
+        let samples = samples
+            .iter()
+            .map(JsonEncoder::object)
+            .collect::<Vec<Value>>();
+        json!({ "device": device, "samples": samples })
+            .to_string()
+            .into_bytes()
+    }
+}
+
+/// Fixed [`BINARY_RECORD_LEN`] byte record, little endian:
+///
+/// | Offset | Size | Field                          |
+/// |--------|------|--------------------------------|
+/// | 0      | 1    | [`BINARY_VERSION`]             |
+/// | 1      | 1    | Flags, bit 0 is the alarm      |
+/// | 2      | 4    | Sequence number (`u32`)        |
+/// | 6      | 4    | Unix timestamp (`u32`)         |
+/// | 10     | 24   | Voltage, current, power, energy, frequency, pf (`f32`) |
+///
+/// The device is known from the topic. A batch is the records back to back.
+pub struct BinaryEncoder;
+
+impl BinaryEncoder {
+    fn record(sample: &Sample) -> [u8; BINARY_RECORD_LEN] {
+        let m = &sample.measurement;
+        let mut record = [0u8; BINARY_RECORD_LEN];
+        record[0] = BINARY_VERSION;
+        record[1] = m.alarm as u8;
+        record[2..6].copy_from_slice(&sample.sequence.to_le_bytes());
+        record[6..10].copy_from_slice(&(sample.timestamp as u32).to_le_bytes());
+        for (i, value) in [m.voltage, m.current, m.power, m.energy, m.frequency, m.pf]
+            .iter()
+            .enumerate()
+        {
+            let offset = 10 + i * 4;
+            record[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        record
+    }
+}
+
+impl MeasurementEncoder for BinaryEncoder {
+    fn encode(&self, sample: &Sample, _device: &str) -> Vec<u8> {
+        BinaryEncoder::record(sample).to_vec()
+    }
+
+    fn encode_batch(&self, samples: &[Sample], _device: &str) -> Vec<u8> {
+        samples.iter().flat_map(BinaryEncoder::record).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emon::Measurement;
+
+    fn sample(sequence: u32) -> Sample {
+        Sample {
+            timestamp: 1_700_000_000,
+            sequence,
+            measurement: Measurement {
+                voltage: 230.1,
+                current: 4.25,
+                power: 978.0,
+                energy: 1234.0,
+                frequency: 50.0,
+                pf: 0.99,
+                alarm: true,
+            },
+        }
+    }
+
+    fn f32_at(record: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes(record[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn binary_record_decodes_to_the_sample() {
+        let sample = sample(7);
+        let record = BinaryEncoder.encode(&sample, "864792041234567");
+        assert_eq!(record.len(), BINARY_RECORD_LEN);
+
+        assert_eq!(record[0], BINARY_VERSION);
+        assert_eq!(record[1], 1);
+        assert_eq!(u32::from_le_bytes(record[2..6].try_into().unwrap()), 7);
+        assert_eq!(
+            u32::from_le_bytes(record[6..10].try_into().unwrap()) as u64,
+            sample.timestamp
+        );
+        let m = &sample.measurement;
+        let values = [m.voltage, m.current, m.power, m.energy, m.frequency, m.pf];
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(f32_at(&record, 10 + i * 4), *value);
+        }
+    }
+
+    #[test]
+    fn binary_batch_is_records_back_to_back() {
+        let batch = BinaryEncoder.encode_batch(&[sample(1), sample(2)], "");
+        assert_eq!(batch.len(), 2 * BINARY_RECORD_LEN);
+        assert_eq!(
+            batch[BINARY_RECORD_LEN..],
+            BinaryEncoder.encode(&sample(2), "")[..]
+        );
+    }
+
+    #[test]
+    fn csv_starts_with_the_previous_six_fields() {
+        let sample = sample(7);
+        let line = String::from_utf8(CsvEncoder.encode(&sample, "")).unwrap();
+        assert_eq!(line, "230.1,4.25,978,1234,50,0.99,1,7,1700000000");
+
+        let batch = CsvEncoder.encode_batch(&[sample, sample], "");
+        assert_eq!(
+            String::from_utf8(batch).unwrap(),
+            format!("{}\n{}", line, line)
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::params::Params;
+
+/// Longest line kept while waiting for its `\r\n`; anything longer is flushed as is.
+pub const MAX_LINE_LEN: usize = 1024;
+
+/// Longest `+QMTRECV` payload waited for; a longer one is framed line by line.
+pub const MAX_RECV_LEN: usize = 4096;
+
+const RECV_PREFIX: &[u8] = b"+QMTRECV:";
+
+const PROMPT: &[u8] = b"> ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// A complete line without its line terminator.
+    Line(String),
+    /// The bare `> ` prompt the module sends when it expects data (`AT+QMTPUBEX`).
+    Prompt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineKind {
+    /// `OK`, `ERROR` or `+CME ERROR: <err>`, ends the command in flight.
+    Final,
+    /// Unsolicited MQTT result codes, which can arrive at any time.
+    Urc,
+    /// Information lines belonging to the command in flight (`+CSQ: 24,99`).
+    Intermediate,
+}
+
+impl LineKind {
+    pub fn of(line: &str) -> LineKind {
+        match line.trim() {
+            r if r == "OK" || r == "ERROR" || r.starts_with("+CME ERROR") => LineKind::Final,
+            r if r.starts_with("+QMT") => LineKind::Urc,
+            _ => LineKind::Intermediate,
+        }
+    }
+}
+
+/// Accumulates raw bytes from the module and splits them into [`Frame`]s.
+///
+/// Bytes can be pushed in chunks of any size; a line split across two reads is held back until
+/// its `\r\n` arrives, and several lines delivered by one read come out one frame at a time.
+///
+/// A `+QMTRECV` with a `<payload_len>` is one frame up to the end of its payload, line breaks
+/// in the payload included.
+#[derive(Debug, Default)]
+pub struct LineFramer {
+    buffer: Vec<u8>,
+}
+
+impl LineFramer {
+    pub fn new() -> Self {
+        LineFramer { buffer: Vec::new() }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Bytes received that are not part of a complete frame yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        let start = self
+            .buffer
+            .iter()
+            .position(|&x| !matches!(x, b'\r' | b'\n' | b' '))
+            .unwrap_or(self.buffer.len());
+        self.buffer.drain(..start);
+
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        // Only `> ` exactly is the prompt; a line that merely starts with `>` is framed by its
+        // `\r\n` like any other. A lone `>` waits for the byte after it.
+        if self.buffer.starts_with(PROMPT) {
+            self.buffer.drain(..PROMPT.len());
+            return Some(Frame::Prompt);
+        }
+        if self.buffer == PROMPT[..1] {
+            return None;
+        }
+
+        match self.recv_len() {
+            Some(Some(end)) => {
+                let newline = self.buffer[end..].iter().position(|&x| x == b'\n')?;
+                let line: Vec<u8> = self.buffer.drain(..=end + newline).collect();
+                return Some(Frame::Line(
+                    String::from_utf8_lossy(&line[..end]).to_string(),
+                ));
+            }
+            Some(None) => return None,
+            None => {}
+        }
+
+        let end = match self.buffer.iter().position(|&x| x == b'\n') {
+            Some(end) => end,
+            None if self.buffer.len() >= MAX_LINE_LEN => self.buffer.len() - 1,
+            None => return None,
+        };
+
+        let line: Vec<u8> = self.buffer.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        Some(Frame::Line(line))
+    }
+}
+
+impl LineFramer {
+    /// For a `+QMTRECV: <client_idx>,<msgID>,"<topic>",<payload_len>,"<payload>"` at the start
+    /// of the buffer, the length up to the closing quote of the payload, `Some(None)` while not
+    /// all of it has arrived. `None` for anything else, which is framed by line.
+    fn recv_len(&self) -> Option<Option<usize>> {
+        if !self.buffer.starts_with(RECV_PREFIX) {
+            return None;
+        }
+
+        // The payload starts after the fourth comma outside quotes.
+        let mut quoted = false;
+        let mut commas = 0;
+        let mut start = None;
+        for (i, &x) in self.buffer.iter().enumerate() {
+            match x {
+                b'"' => quoted = !quoted,
+                b',' if !quoted => {
+                    commas += 1;
+                    if commas == 4 {
+                        start = Some(i + 1);
+                        break;
+                    }
+                }
+                b'\n' => return None,
+                _ => {}
+            }
+        }
+        let start = match start {
+            Some(start) => start,
+            None if self.buffer.len() < MAX_LINE_LEN => return Some(None),
+            None => return None,
+        };
+
+        let header = std::str::from_utf8(&self.buffer[RECV_PREFIX.len()..start - 1]).ok()?;
+        let len = Params::new(header).nth(3)?.ok()?.parse::<usize>()?;
+        if len > MAX_RECV_LEN {
+            return None;
+        }
+        match self.buffer.get(start) {
+            Some(b'"') => {}
+            Some(_) => return None,
+            None => return Some(None),
+        }
+
+        // Opening quote, payload, closing quote.
+        let end = start + 1 + len + 1;
+        match self.buffer.get(end - 1) {
+            Some(b'"') => Some(Some(end)),
+            // The length does not match, leave it to the parser to report.
+            Some(_) => None,
+            None => Some(None),
+        }
+    }
+}
+
+impl Iterator for LineFramer {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        self.next_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(chunks: &[&[u8]]) -> Vec<Frame> {
+        let mut framer = LineFramer::new();
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            framer.push(chunk);
+            frames.extend(&mut framer);
+        }
+        frames
+    }
+
+    fn line(line: &str) -> Frame {
+        Frame::Line(line.to_string())
+    }
+
+    #[test]
+    fn holds_a_line_split_across_pushes() {
+        assert_eq!(
+            frames(&[b"\r\n+CSQ: 2", b"4,99\r", b"\n\r\nOK\r\n"]),
+            vec![line("+CSQ: 24,99"), line("OK")]
+        );
+    }
+
+    #[test]
+    fn prompt_at_the_head() {
+        assert_eq!(frames(&[b"\r\n> "]), vec![Frame::Prompt]);
+    }
+
+    #[test]
+    fn prompt_split_after_the_angle_bracket() {
+        let mut framer = LineFramer::new();
+        framer.push(b"\r\n>");
+        assert_eq!(framer.next_frame(), None);
+        framer.push(b" ");
+        assert_eq!(framer.next_frame(), Some(Frame::Prompt));
+        assert!(framer.pending().is_empty());
+    }
+
+    #[test]
+    fn prompt_followed_by_a_urc_in_the_same_read() {
+        assert_eq!(
+            frames(&[b"\r\n> \r\n+QMTSTAT: 0,1\r\n"]),
+            vec![Frame::Prompt, line("+QMTSTAT: 0,1")]
+        );
+    }
+
+    #[test]
+    fn line_starting_with_angle_bracket_is_not_a_prompt() {
+        assert_eq!(frames(&[b"\r\n>abc", b"\r\n"]), vec![line(">abc")]);
+        assert_eq!(frames(&[b"\r\n>", b">\r\n"]), vec![line(">>")]);
+    }
+
+    #[test]
+    fn recv_payload_with_line_break_split_across_pushes() {
+        assert_eq!(
+            frames(&[
+                b"\r\n+QMTRECV: 0,1,\"SUBONE/config\",8,\"a=1",
+                b"\r\nb=2\"",
+                b"\r\n",
+            ]),
+            vec![line("+QMTRECV: 0,1,\"SUBONE/config\",8,\"a=1\r\nb=2\"")]
+        );
+    }
+}
@@ -0,0 +1,32 @@
+//! AT/MQTT stack of the relay controller. The ESP-IDF drivers are only built for the
+//! `espidf` target; everything else also builds on the host, where the modem is reached
+//! through [`transport::PipeTransport`].
+
+pub mod at;
+pub mod atcommands;
+pub mod atmodule;
+pub mod atres;
+pub mod auth;
+pub mod clock;
+pub mod command;
+pub mod config;
+pub mod constants;
+pub mod controller;
+pub mod emon;
+#[cfg(any(test, feature = "emulator"))]
+pub mod emulator;
+pub mod encoder;
+pub mod framer;
+pub mod mqtt;
+pub mod network;
+pub mod offline;
+pub mod params;
+pub mod protection;
+pub mod publish;
+pub mod retry;
+pub mod schedule;
+pub mod settings;
+pub mod subscribe;
+pub mod topics;
+pub mod transaction;
+pub mod transport;
@@ -1,36 +1,47 @@
 //! MQTT asynchronous client example which subscribes to an internet MQTT server and then sends
 //! and receives events in its own topic.
 
-pub mod at;
-pub mod atcommands;
-pub mod atmodule;
-pub mod atres;
-pub mod constants;
-pub mod controller;
-pub mod emon;
-pub mod subscribe;
+#![cfg_attr(not(target_os = "espidf"), allow(unused_imports))]
 
 use core::pin::pin;
 use core::time::Duration;
 
-use at::AT;
-use constants::{ATREAD, ATRESTART, ATSTATUS};
-use embassy_futures::select::{select, select3, Either3};
+use std::sync::{Arc, Mutex};
 
+use atcontroller::at::{self, AT};
+use atcontroller::constants::{self, ATHEALTH, ATWATCHDOG, OFFLINE_BUFFER_LEN};
+use atcontroller::offline::{
+    OfflineBuffer, SharedBacklog, SpillStore, OFFLINE_NAMESPACE, OFFLINE_PARTITION,
+};
+use atcontroller::settings::{SettingsError, SharedStorage};
+use embassy_futures::select::{select, select4, Either, Either4};
+use log::*;
+
+#[cfg(target_os = "espidf")]
+use atcontroller::settings::NvsStorage;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::delay::Delay;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::task::block_on;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspDefaultNvsPartition};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::sys::{esp_log_level_set, EspError};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::timer::{EspAsyncTimer, EspTimerService};
 
-use log::*;
-
+#[cfg(target_os = "espidf")]
 async fn run(
     timer_one: &mut EspAsyncTimer,
     timer_two: &mut EspAsyncTimer,
     restart_timer: &mut EspAsyncTimer,
+    watchdog_timer: &mut EspAsyncTimer,
+    health_timer: &mut EspAsyncTimer,
+    storage: SharedStorage,
+    backlog: SharedBacklog,
 ) -> Result<(), EspError> {
     info!("About to start the MQTT client");
-    let at = AT::new();
+    let at = AT::new(storage, backlog);
     let _ = at.check_at().await;
     at.init().await;
 
@@ -38,36 +49,65 @@ async fn run(
 
     info!("AT Moudle UART Connection established");
 
-    let res = select3(
+    let res = select4(
         pin!(async {
             loop {
-                let _ = timer_one.after(Duration::from_millis(ATSTATUS)).await;
+                let _ = timer_one
+                    .after(Duration::from_millis(at.settings().status_interval))
+                    .await;
                 at.sendstatus(None, at::AtReplyTopic::STATUS).await;
             }
         }),
         pin!(async {
             loop {
                 info!("Waiting for  Reading message");
-                let _ = timer_two.after(Duration::from_millis(ATREAD)).await;
+                let _ = timer_two
+                    .after(Duration::from_millis(at.settings().read_interval))
+                    .await;
                 at.read_serail().await;
             }
         }),
         pin!(async {
             loop {
-                let _ = restart_timer.after(Duration::from_millis(ATRESTART)).await;
+                let _ = restart_timer
+                    .after(Duration::from_millis(at.settings().restart_interval))
+                    .await;
                 at.restart();
             }
         }),
+        select(
+            pin!(async {
+                loop {
+                    let _ = watchdog_timer
+                        .after(Duration::from_millis(ATWATCHDOG))
+                        .await;
+                    at.check_timeouts().await;
+                    at.check_relay().await;
+                    at.check_protection().await;
+                    at.check_schedule().await;
+                    at.check_run_timer().await;
+                }
+            }),
+            pin!(async {
+                loop {
+                    let _ = health_timer.after(Duration::from_millis(ATHEALTH)).await;
+                    at.send_health().await;
+                }
+            }),
+        ),
     )
     .await;
 
     match res {
-        Either3::First(_) => Ok(()),
-        Either3::Second(_) => Ok(()),
-        Either3::Third(_) => Ok(()),
+        Either4::First(_) => Ok(()),
+        Either4::Second(_) => Ok(()),
+        Either4::Third(_) => Ok(()),
+        Either4::Fourth(Either::First(_)) => Ok(()),
+        Either4::Fourth(Either::Second(_)) => Ok(()),
     }
 }
 
+#[cfg(target_os = "espidf")]
 fn main() {
     unsafe {
         use std::ffi::CString;
@@ -82,6 +122,21 @@ fn main() {
     esp_idf_svc::log::EspLogger::initialize_default();
     info!("Starting ATController");
     let timer_service = EspTimerService::new().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+    let storage: SharedStorage = Arc::new(Mutex::new(NvsStorage::new(nvs).unwrap()));
+
+    let spill = EspCustomNvsPartition::take(OFFLINE_PARTITION)
+        .map_err(SettingsError::from)
+        .and_then(|x| NvsStorage::custom(x, OFFLINE_NAMESPACE));
+    let spill = match spill {
+        Ok(spill) => Some(SpillStore::new(Box::new(spill))),
+        Err(e) => {
+            info!("No backlog partition ({}), keeping the backlog in RAM", e);
+            None
+        }
+    };
+    let backlog: SharedBacklog =
+        Arc::new(Mutex::new(OfflineBuffer::new(OFFLINE_BUFFER_LEN, spill)));
 
     let delay: Delay = Default::default();
     delay.delay_ms(10000);
@@ -89,10 +144,28 @@ fn main() {
     let mut timer_one = timer_service.timer_async().unwrap();
     let mut timer_two = timer_service.timer_async().unwrap();
     let mut restart_timer = timer_service.timer_async().unwrap();
+    let mut watchdog_timer = timer_service.timer_async().unwrap();
+    let mut health_timer = timer_service.timer_async().unwrap();
 
     block_on(async {
         loop {
-            let _ = run(&mut timer_one, &mut timer_two, &mut restart_timer).await;
+            let _ = run(
+                &mut timer_one,
+                &mut timer_two,
+                &mut restart_timer,
+                &mut watchdog_timer,
+                &mut health_timer,
+                storage.clone(),
+                backlog.clone(),
+            )
+            .await;
         }
     })
 }
+
+/// The firmware needs the ESP32; on the host the stack is exercised through the library and
+/// its emulator instead.
+#[cfg(not(target_os = "espidf"))]
+fn main() {
+    eprintln!("atcontroller runs on the ESP32-S3, build it for the xtensa-esp32s3-espidf target");
+}
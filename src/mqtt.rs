@@ -0,0 +1,261 @@
+use core::fmt;
+
+pub const DEFAULT_HOST: &str = "abc.dev.url.com";
+pub const DEFAULT_PORT: u16 = 8883;
+pub const DEFAULT_CLIENT_ID: &str = "U2";
+pub const DEFAULT_KEEPALIVE: u16 = 120;
+pub const MAX_KEEPALIVE: u16 = 3600;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MqttConfigError {
+    EmptyHost,
+    InvalidPort,
+    EmptyClientId,
+    InvalidKeepalive,
+    /// The field would break out of the quoted AT command parameter.
+    QuoteInField(&'static str),
+    /// A `\r`, `\n` or other control character, which would end the AT command line early.
+    ControlInField(&'static str),
+}
+
+impl fmt::Display for MqttConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttConfigError::EmptyHost => write!(f, "Broker host is empty"),
+            MqttConfigError::InvalidPort => write!(f, "Broker port must not be 0"),
+            MqttConfigError::EmptyClientId => write!(f, "Client id is empty"),
+            MqttConfigError::InvalidKeepalive => {
+                write!(f, "Keepalive must be at most {} seconds", MAX_KEEPALIVE)
+            }
+            MqttConfigError::QuoteInField(field) => write!(f, "{} contains a quote", field),
+            MqttConfigError::ControlInField(field) => {
+                write!(f, "{} contains a control character", field)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MqttConfigError {}
+
+/// `<err_code>` of `+QMTSTAT: <client_idx>,<err_code>`, why the module dropped the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    /// 1: Connection closed or reset by the peer.
+    ClosedByPeer,
+    /// 2: Sending PINGREQ timed out or failed.
+    PingFailed,
+    /// 3: Sending CONNECT timed out or failed.
+    ConnectFailed,
+    /// 4: Receiving CONNACK timed out or failed.
+    ConnackFailed,
+    /// 5: The server closed the connection after a DISCONNECT.
+    ServerDisconnected,
+    /// 6: The client disconnected because sending packets kept failing.
+    SendFailed,
+    /// 7: The link is down or the server is unavailable.
+    LinkDown,
+    Unknown(u8),
+}
+
+impl DisconnectReason {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => DisconnectReason::ClosedByPeer,
+            2 => DisconnectReason::PingFailed,
+            3 => DisconnectReason::ConnectFailed,
+            4 => DisconnectReason::ConnackFailed,
+            5 => DisconnectReason::ServerDisconnected,
+            6 => DisconnectReason::SendFailed,
+            7 => DisconnectReason::LinkDown,
+            code => DisconnectReason::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::ClosedByPeer => write!(f, "Connection closed by peer"),
+            DisconnectReason::PingFailed => write!(f, "PINGREQ failed"),
+            DisconnectReason::ConnectFailed => write!(f, "CONNECT failed"),
+            DisconnectReason::ConnackFailed => write!(f, "CONNACK not received"),
+            DisconnectReason::ServerDisconnected => write!(f, "Server disconnected"),
+            DisconnectReason::SendFailed => write!(f, "Sending data failed"),
+            DisconnectReason::LinkDown => write!(f, "Link down or server unavailable"),
+            DisconnectReason::Unknown(code) => write!(f, "Unknown error {}", code),
+        }
+    }
+}
+
+/// Broker endpoint and session settings the MQTT commands are rendered from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Seconds, `0` disables keepalive.
+    pub keepalive: u16,
+    pub clean_session: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            client_id: DEFAULT_CLIENT_ID.to_string(),
+            username: Some("username".to_string()),
+            password: Some("password".to_string()),
+            keepalive: DEFAULT_KEEPALIVE,
+            clean_session: true,
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn validate(&self) -> Result<(), MqttConfigError> {
+        if self.host.is_empty() {
+            return Err(MqttConfigError::EmptyHost);
+        }
+        if self.port == 0 {
+            return Err(MqttConfigError::InvalidPort);
+        }
+        if self.client_id.is_empty() {
+            return Err(MqttConfigError::EmptyClientId);
+        }
+        if self.keepalive > MAX_KEEPALIVE {
+            return Err(MqttConfigError::InvalidKeepalive);
+        }
+
+        let fields = [
+            ("host", Some(&self.host)),
+            ("client_id", Some(&self.client_id)),
+            ("username", self.username.as_ref()),
+            ("password", self.password.as_ref()),
+        ];
+        for (name, value) in fields {
+            if value.is_some_and(|x| x.contains('"')) {
+                return Err(MqttConfigError::QuoteInField(name));
+            }
+            if value.is_some_and(|x| x.contains(char::is_control)) {
+                return Err(MqttConfigError::ControlInField(name));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `AT+QMTOPEN=0,"<host>",<port>`
+    pub fn open_command(&self) -> String {
+        format!("AT+QMTOPEN=0,\"{}\",{}\r\n", self.host, self.port)
+    }
+
+    /// `AT+QMTCONN=0,"<clientid>"[,"<username>"[,"<password>"]]`
+    pub fn connect_command(&self) -> String {
+        let mut command = format!("AT+QMTCONN=0,\"{}\"", self.client_id);
+        if let Some(username) = &self.username {
+            command.push_str(&format!(",\"{}\"", username));
+            if let Some(password) = &self.password {
+                command.push_str(&format!(",\"{}\"", password));
+            }
+        }
+        command.push_str("\r\n");
+        command
+    }
+
+    /// `AT+QMTCFG="keepalive",0,<keep_alive_time>`
+    pub fn keepalive_command(&self) -> String {
+        format!("AT+QMTCFG=\"keepalive\",0,{}\r\n", self.keepalive)
+    }
+
+    /// `AT+QMTCFG="session",0,<clean_session>`
+    pub fn session_command(&self) -> String {
+        format!(
+            "AT+QMTCFG=\"session\",0,{}\r\n",
+            if self.clean_session { 1 } else { 0 }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(MqttConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_quotes_in_every_field() {
+        let configs = [
+            (
+                "host",
+                MqttConfig {
+                    host: "broker\".example.com".to_string(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "client_id",
+                MqttConfig {
+                    client_id: "pump\"1".to_string(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "username",
+                MqttConfig {
+                    username: Some("user\"".to_string()),
+                    ..Default::default()
+                },
+            ),
+            (
+                "password",
+                MqttConfig {
+                    password: Some("pa\"ss".to_string()),
+                    ..Default::default()
+                },
+            ),
+        ];
+        for (name, config) in configs {
+            assert_eq!(config.validate(), Err(MqttConfigError::QuoteInField(name)));
+        }
+    }
+
+    #[test]
+    fn rejects_line_breaks_and_control_characters() {
+        for value in ["pass\r\nAT+QPOWD=1", "pass\n", "pass\u{1b}", "pass\0"] {
+            let config = MqttConfig {
+                password: Some(value.to_string()),
+                ..Default::default()
+            };
+            assert_eq!(
+                config.validate(),
+                Err(MqttConfigError::ControlInField("password")),
+                "{:?}",
+                value
+            );
+        }
+        let config = MqttConfig {
+            host: "broker.example.com\r".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(MqttConfigError::ControlInField("host"))
+        );
+    }
+
+    #[test]
+    fn missing_credentials_are_not_checked() {
+        let config = MqttConfig {
+            username: None,
+            password: None,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
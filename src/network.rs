@@ -0,0 +1,150 @@
+use crate::clock::TimeSource;
+
+/// `+COPS: <mode>,<format>,<oper>[,<AcT>]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperatorInfo {
+    pub mode: u8,
+    pub format: u8,
+    pub oper: String,
+    pub act: Option<u8>,
+}
+
+/// `+CSQ: <rssi>,<ber>`, with the RSSI converted to dBm. `None` when the module reports 99.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SignalQuality {
+    pub rssi_dbm: Option<i16>,
+    pub ber: Option<u8>,
+}
+
+impl SignalQuality {
+    pub fn from_csq(rssi: u8, ber: u8) -> Self {
+        let rssi_dbm = match rssi {
+            0..=31 => Some(-113 + 2 * rssi as i16),
+            _ => None,
+        };
+        let ber = match ber {
+            0..=7 => Some(ber),
+            _ => None,
+        };
+        SignalQuality { rssi_dbm, ber }
+    }
+}
+
+/// `+QNWINFO: <AcT>,<oper>,<band>,<channel>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServingCell {
+    pub act: String,
+    pub oper: String,
+    pub band: String,
+    pub channel: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegistrationStatus {
+    NotRegistered,
+    Home,
+    Searching,
+    Denied,
+    Unknown,
+    Roaming,
+}
+
+impl RegistrationStatus {
+    pub fn from_stat(stat: u8) -> Self {
+        match stat {
+            0 => RegistrationStatus::NotRegistered,
+            1 => RegistrationStatus::Home,
+            2 => RegistrationStatus::Searching,
+            3 => RegistrationStatus::Denied,
+            5 => RegistrationStatus::Roaming,
+            _ => RegistrationStatus::Unknown,
+        }
+    }
+
+    /// Stable name for reports, `home`, `roaming`, `denied`, ...
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegistrationStatus::NotRegistered => "not-registered",
+            RegistrationStatus::Home => "home",
+            RegistrationStatus::Searching => "searching",
+            RegistrationStatus::Denied => "denied",
+            RegistrationStatus::Unknown => "unknown",
+            RegistrationStatus::Roaming => "roaming",
+        }
+    }
+
+    pub fn is_registered(&self) -> bool {
+        matches!(self, RegistrationStatus::Home | RegistrationStatus::Roaming)
+    }
+}
+
+/// `+CREG: <n>,<stat>[,<lac>,<ci>[,<AcT>]]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registration {
+    pub n: u8,
+    pub stat: RegistrationStatus,
+    pub lac: Option<String>,
+    pub ci: Option<String>,
+    pub act: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatusUpdate {
+    Operator(OperatorInfo),
+    Signal(SignalQuality),
+    ServingCell(ServingCell),
+    Registration(Registration),
+    /// `AT+GSN`, kept in [`crate::topics::DeviceIdentity`] rather than here.
+    Imei(String),
+    /// `+QCCID: <iccid>`, kept in [`crate::topics::DeviceIdentity`] rather than here.
+    Iccid(String),
+    /// `+CCLK`/`+QNTP` as Unix seconds, kept in [`crate::clock`] rather than here.
+    Time(u64, TimeSource),
+}
+
+/// Latest answer to each of the network status queries.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStatus {
+    pub operator: Option<OperatorInfo>,
+    pub signal: Option<SignalQuality>,
+    pub serving_cell: Option<ServingCell>,
+    pub registration: Option<Registration>,
+}
+
+impl NetworkStatus {
+    pub fn update(&mut self, update: StatusUpdate) {
+        match update {
+            StatusUpdate::Operator(operator) => self.operator = Some(operator),
+            StatusUpdate::Signal(signal) => self.signal = Some(signal),
+            StatusUpdate::ServingCell(serving_cell) => self.serving_cell = Some(serving_cell),
+            StatusUpdate::Registration(registration) => self.registration = Some(registration),
+            StatusUpdate::Imei(_) | StatusUpdate::Iccid(_) | StatusUpdate::Time(..) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csq_edges_map_to_dbm() {
+        assert_eq!(SignalQuality::from_csq(0, 0).rssi_dbm, Some(-113));
+        assert_eq!(SignalQuality::from_csq(1, 0).rssi_dbm, Some(-111));
+        assert_eq!(SignalQuality::from_csq(31, 0).rssi_dbm, Some(-51));
+        assert_eq!(SignalQuality::from_csq(32, 0).rssi_dbm, None);
+    }
+
+    #[test]
+    fn csq_99_is_unknown() {
+        assert_eq!(
+            SignalQuality::from_csq(99, 99),
+            SignalQuality {
+                rssi_dbm: None,
+                ber: None,
+            }
+        );
+        assert_eq!(SignalQuality::from_csq(24, 7).ber, Some(7));
+        assert_eq!(SignalQuality::from_csq(24, 8).ber, None);
+    }
+}
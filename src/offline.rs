@@ -0,0 +1,416 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use crate::clock;
+use crate::constants::{OFFLINE_BATCH_LEN, OFFLINE_BUFFER_LEN, OFFLINE_SPILL_CHUNKS};
+use crate::emon::Measurement;
+use crate::settings::{SettingsError, SettingsStorage};
+
+pub const OFFLINE_PARTITION: &str = "offline";
+pub const OFFLINE_NAMESPACE: &str = "backlog";
+
+/// Bytes of a stored [`Sample`]: timestamp, sequence number, six readings and the alarm flag.
+pub const SAMPLE_LEN: usize = 8 + 4 + 6 * 4 + 1;
+
+/// First byte of every spilled chunk, bumped whenever [`Sample::encode`] changes.
+pub const SPILL_VERSION: u8 = 2;
+
+const KEY_HEAD: &str = "head";
+const KEY_TAIL: &str = "tail";
+
+/// Backlog shared by every run of the main loop, so it outlives a reconnect.
+pub type SharedBacklog = Arc<Mutex<OfflineBuffer>>;
+
+/// A timestamped PZEM reading.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// Seconds since the Unix epoch, `0` if the clock was not set yet.
+    pub timestamp: u64,
+    /// Counts the readings, so gaps show up on the receiving side.
+    pub sequence: u32,
+    pub measurement: Measurement,
+}
+
+impl Sample {
+    pub fn now(sequence: u32, measurement: Measurement) -> Self {
+        Sample {
+            timestamp: clock::now().unwrap_or(0),
+            sequence,
+            measurement,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; SAMPLE_LEN] {
+        let m = &self.measurement;
+        let mut bytes = [0u8; SAMPLE_LEN];
+        bytes[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.sequence.to_le_bytes());
+        for (i, value) in [m.voltage, m.current, m.power, m.energy, m.frequency, m.pf]
+            .iter()
+            .enumerate()
+        {
+            let offset = 12 + i * 4;
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        bytes[SAMPLE_LEN - 1] = m.alarm as u8;
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Sample> {
+        if bytes.len() != SAMPLE_LEN {
+            return None;
+        }
+        let value = |i: usize| {
+            let offset = 12 + i * 4;
+            f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+        Some(Sample {
+            timestamp: u64::from_le_bytes(bytes[..8].try_into().ok()?),
+            sequence: u32::from_le_bytes(bytes[8..12].try_into().ok()?),
+            measurement: Measurement {
+                voltage: value(0),
+                current: value(1),
+                power: value(2),
+                energy: value(3),
+                frequency: value(4),
+                pf: value(5),
+                alarm: bytes[SAMPLE_LEN - 1] != 0,
+            },
+        })
+    }
+}
+
+/// Chunks of [`OFFLINE_BATCH_LEN`] samples kept in flash, numbered from `head` (oldest) to
+/// `tail` (next to write). Survives a reboot.
+///
+/// A chunk is [`SPILL_VERSION`] followed by the encoded samples; one written by another
+/// version, or cut short, is dropped rather than decoded.
+pub struct SpillStore {
+    storage: Box<dyn SettingsStorage + Send>,
+    head: u32,
+    tail: u32,
+    max_chunks: u32,
+}
+
+impl SpillStore {
+    pub fn new(storage: Box<dyn SettingsStorage + Send>) -> Self {
+        let read = |key: &str| match storage.get(key) {
+            Ok(Some(bytes)) => bytes.try_into().map(u32::from_le_bytes).unwrap_or(0),
+            _ => 0,
+        };
+        let (head, tail) = (read(KEY_HEAD), read(KEY_TAIL));
+        let (head, tail) = if head <= tail { (head, tail) } else { (0, 0) };
+        if tail > head {
+            info!("{} backlog chunks in flash", tail - head);
+        }
+        SpillStore {
+            storage,
+            head,
+            tail,
+            max_chunks: OFFLINE_SPILL_CHUNKS,
+        }
+    }
+
+    fn key(chunk: u32) -> String {
+        format!("c{}", chunk)
+    }
+
+    /// Number of the oldest chunk.
+    pub fn head(&self) -> u32 {
+        self.head
+    }
+
+    pub fn chunks(&self) -> u32 {
+        self.tail - self.head
+    }
+
+    /// Appends a chunk, dropping the oldest one if the store is full.
+    pub fn push(&mut self, samples: &[Sample]) -> Result<(), SettingsError> {
+        if self.chunks() >= self.max_chunks {
+            info!("Backlog flash full, dropping the oldest chunk");
+            self.pop()?;
+        }
+        let mut bytes = vec![SPILL_VERSION];
+        bytes.extend(samples.iter().flat_map(|x| x.encode()));
+        self.storage.set(&SpillStore::key(self.tail), &bytes)?;
+        self.tail += 1;
+        self.storage.set(KEY_TAIL, &self.tail.to_le_bytes())
+    }
+
+    /// The oldest readable chunk, without removing it. Unreadable chunks before it are dropped.
+    pub fn front(&mut self) -> Option<Vec<Sample>> {
+        while self.chunks() > 0 {
+            match self.storage.get(&SpillStore::key(self.head)) {
+                Ok(Some(bytes)) => match bytes.split_first() {
+                    Some((&SPILL_VERSION, samples)) if samples.len() % SAMPLE_LEN == 0 => {
+                        return Some(
+                            samples
+                                .chunks_exact(SAMPLE_LEN)
+                                .filter_map(Sample::decode)
+                                .collect(),
+                        );
+                    }
+                    _ => info!("Dropping unreadable backlog chunk {}", self.head),
+                },
+                Ok(None) => info!("Backlog chunk {} is missing", self.head),
+                Err(e) => {
+                    info!("Error reading backlog chunk {}: {}", self.head, e);
+                    return None;
+                }
+            }
+            if let Err(e) = self.pop() {
+                info!("Error removing backlog chunk: {}", e);
+                return None;
+            }
+        }
+        None
+    }
+
+    pub fn pop(&mut self) -> Result<(), SettingsError> {
+        if self.chunks() == 0 {
+            return Ok(());
+        }
+        self.storage.remove(&SpillStore::key(self.head))?;
+        self.head += 1;
+        if self.head == self.tail {
+            self.head = 0;
+            self.tail = 0;
+            self.storage.set(KEY_TAIL, &self.tail.to_le_bytes())?;
+        }
+        self.storage.set(KEY_HEAD, &self.head.to_le_bytes())
+    }
+}
+
+/// Where the batch being published was read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BatchSource {
+    /// The chunk with this number.
+    Spill(u32),
+    /// The first samples in RAM.
+    Ram(usize),
+}
+
+/// Samples recorded while offline, replayed oldest first once connected.
+///
+/// The newest samples sit in a RAM ring of `capacity`; when it fills up, the oldest
+/// [`OFFLINE_BATCH_LEN`] are moved to the [`SpillStore`] if there is one, otherwise dropped.
+///
+/// A batch stays in the buffer until its publish is acknowledged: [`OfflineBuffer::next_batch`]
+/// only reads it and [`OfflineBuffer::commit`] removes it once the message id came back sent.
+pub struct OfflineBuffer {
+    capacity: usize,
+    ram: VecDeque<Sample>,
+    spill: Option<SpillStore>,
+    /// The batch in flight and its message id, set by [`OfflineBuffer::set_pending`].
+    pending: Option<(u16, BatchSource)>,
+    /// Read by the last [`OfflineBuffer::next_batch`], not published yet.
+    peeked: Option<BatchSource>,
+}
+
+impl OfflineBuffer {
+    pub fn new(capacity: usize, spill: Option<SpillStore>) -> Self {
+        OfflineBuffer {
+            capacity,
+            ram: VecDeque::new(),
+            spill,
+            pending: None,
+            peeked: None,
+        }
+    }
+
+    pub fn push(&mut self, sample: Sample) {
+        if self.ram.len() >= self.capacity {
+            let count = OFFLINE_BATCH_LEN.min(self.ram.len());
+            let oldest = self.ram.drain(..count).collect::<Vec<Sample>>();
+            // The batch in flight may have just moved, it is sent again rather than lost.
+            self.forget_ram_batch();
+            match self.spill.as_mut() {
+                Some(spill) => {
+                    if let Err(e) = spill.push(&oldest) {
+                        info!("Error spilling backlog: {}", e);
+                    }
+                }
+                None => info!("Backlog full, dropping {} samples", count),
+            }
+        }
+        self.ram.push_back(sample);
+    }
+
+    /// Up to [`OFFLINE_BATCH_LEN`] of the oldest samples, left in the buffer until
+    /// [`OfflineBuffer::commit`].
+    pub fn next_batch(&mut self) -> Vec<Sample> {
+        self.pending = None;
+        if let Some(spill) = self.spill.as_mut() {
+            if let Some(batch) = spill.front() {
+                self.peeked = Some(BatchSource::Spill(spill.head()));
+                return batch;
+            }
+        }
+        let count = OFFLINE_BATCH_LEN.min(self.ram.len());
+        self.peeked = Some(BatchSource::Ram(count));
+        self.ram.iter().take(count).copied().collect()
+    }
+
+    /// Ties the batch read by the last [`OfflineBuffer::next_batch`] to the message publishing
+    /// it.
+    pub fn set_pending(&mut self, msg_id: u16) {
+        self.pending = self.peeked.take().map(|source| (msg_id, source));
+    }
+
+    /// Removes the pending batch once `msg_id` was published. A batch that moved in the
+    /// meantime stays, to be sent again.
+    pub fn commit(&mut self, msg_id: u16) {
+        let source = match self.pending {
+            Some((pending, source)) if pending == msg_id => source,
+            _ => return,
+        };
+        self.pending = None;
+        match source {
+            BatchSource::Spill(head) => {
+                if let Some(spill) = self.spill.as_mut().filter(|x| x.head() == head) {
+                    if let Err(e) = spill.pop() {
+                        info!("Error removing backlog chunk: {}", e);
+                    }
+                }
+            }
+            BatchSource::Ram(count) => {
+                self.ram.drain(..count.min(self.ram.len()));
+            }
+        }
+    }
+
+    fn forget_ram_batch(&mut self) {
+        if matches!(self.pending, Some((_, BatchSource::Ram(_)))) {
+            self.pending = None;
+        }
+        if matches!(self.peeked, Some(BatchSource::Ram(_))) {
+            self.peeked = None;
+        }
+    }
+
+    /// Moves what is in RAM to flash, before a reboot.
+    pub fn flush(&mut self) {
+        if self.spill.is_none() {
+            return;
+        }
+        self.forget_ram_batch();
+        let spill = match self.spill.as_mut() {
+            Some(spill) => spill,
+            None => return,
+        };
+        while !self.ram.is_empty() {
+            let count = OFFLINE_BATCH_LEN.min(self.ram.len());
+            let batch = self.ram.drain(..count).collect::<Vec<Sample>>();
+            if let Err(e) = spill.push(&batch) {
+                info!("Error spilling backlog: {}", e);
+                return;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ram.is_empty() && self.spill.as_ref().map_or(true, |x| x.chunks() == 0)
+    }
+}
+
+impl Default for OfflineBuffer {
+    fn default() -> Self {
+        OfflineBuffer::new(OFFLINE_BUFFER_LEN, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::MemoryStorage;
+
+    fn sample(sequence: u32) -> Sample {
+        Sample {
+            timestamp: 0,
+            sequence,
+            measurement: Measurement::default(),
+        }
+    }
+
+    fn sequences(batch: &[Sample]) -> Vec<u32> {
+        batch.iter().map(|x| x.sequence).collect()
+    }
+
+    fn spilled() -> OfflineBuffer {
+        let spill = SpillStore::new(Box::new(MemoryStorage::new()));
+        OfflineBuffer::new(OFFLINE_BATCH_LEN, Some(spill))
+    }
+
+    #[test]
+    fn batch_stays_until_its_message_is_sent() {
+        let mut buffer = OfflineBuffer::new(8, None);
+        buffer.push(sample(1));
+        buffer.push(sample(2));
+
+        assert_eq!(sequences(&buffer.next_batch()), vec![1, 2]);
+        buffer.set_pending(7);
+        buffer.commit(8);
+        assert_eq!(sequences(&buffer.next_batch()), vec![1, 2]);
+
+        buffer.set_pending(9);
+        buffer.commit(9);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn spilled_chunk_is_removed_on_commit() {
+        let mut buffer = spilled();
+        for sequence in 0..=OFFLINE_BATCH_LEN as u32 {
+            buffer.push(sample(sequence));
+        }
+
+        let batch = buffer.next_batch();
+        assert_eq!(batch.len(), OFFLINE_BATCH_LEN);
+        assert_eq!(batch[0].sequence, 0);
+        buffer.set_pending(1);
+        buffer.commit(1);
+        assert_eq!(
+            sequences(&buffer.next_batch()),
+            vec![OFFLINE_BATCH_LEN as u32]
+        );
+    }
+
+    #[test]
+    fn drops_chunks_of_another_version_or_length() {
+        let mut storage = MemoryStorage::new();
+        let old = sample(1).encode();
+        storage.set("c0", &old).unwrap();
+        let mut short = vec![SPILL_VERSION];
+        short.extend_from_slice(&old[..SAMPLE_LEN - 4]);
+        storage.set("c1", &short).unwrap();
+        let mut current = vec![SPILL_VERSION];
+        current.extend_from_slice(&sample(3).encode());
+        storage.set("c2", &current).unwrap();
+        storage.set(KEY_TAIL, &3u32.to_le_bytes()).unwrap();
+
+        let mut spill = SpillStore::new(Box::new(storage));
+        assert_eq!(spill.front().map(|x| sequences(&x)), Some(vec![3]));
+        assert_eq!(spill.head(), 2);
+        spill.pop().unwrap();
+        assert!(spill.front().is_none());
+    }
+
+    #[test]
+    fn ram_batch_moved_to_flash_is_sent_again() {
+        let mut buffer = spilled();
+        for sequence in 0..OFFLINE_BATCH_LEN as u32 {
+            buffer.push(sample(sequence));
+        }
+        buffer.next_batch();
+        buffer.set_pending(1);
+
+        // Full, the batch in flight moves to flash and the acknowledgement removes nothing.
+        buffer.push(sample(OFFLINE_BATCH_LEN as u32));
+        buffer.commit(1);
+        let batch = buffer.next_batch();
+        assert_eq!(batch.len(), OFFLINE_BATCH_LEN);
+        assert_eq!(batch[0].sequence, 0);
+    }
+}
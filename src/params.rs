@@ -0,0 +1,107 @@
+use core::fmt;
+
+/// One parameter of a result code, borrowed from the line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Param<'a> {
+    /// `"<text>"`, without the quotes. Quectel does not escape quotes inside.
+    Quoted(&'a str),
+    /// A number or other bare value, trimmed.
+    Unquoted(&'a str),
+}
+
+impl<'a> Param<'a> {
+    pub fn as_str(&self) -> &'a str {
+        match self {
+            Param::Quoted(x) | Param::Unquoted(x) => x,
+        }
+    }
+
+    pub fn parse<T: core::str::FromStr>(&self) -> Option<T> {
+        match self {
+            Param::Unquoted(x) => x.parse().ok(),
+            Param::Quoted(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamError {
+    /// A quoted parameter without its closing quote.
+    UnterminatedQuote,
+    /// Something other than `,` after a closing quote.
+    ExpectedComma,
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::UnterminatedQuote => write!(f, "Unterminated quoted parameter"),
+            ParamError::ExpectedComma => write!(f, "Expected , after a quoted parameter"),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+/// Reads the comma separated parameters of `+<name>: <p1>,"<p2>",...` one at a time, without
+/// copying. A `,` or `:` inside quotes is part of the parameter.
+///
+/// What has not been read stays available through [`Params::rest`], for a trailing field that
+/// is not a parameter, such as the payload of `+QMTRECV`.
+#[derive(Debug, Clone, Copy)]
+pub struct Params<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Params<'a> {
+    pub fn new(params: &'a str) -> Self {
+        Params { rest: params }
+    }
+
+    /// Splits `line` into the name, `+QMTRECV`, and its parameters. `None` if it is not a
+    /// result code.
+    pub fn of(line: &'a str) -> Option<(&'a str, Params<'a>)> {
+        let line = line.trim_start();
+        if !line.starts_with('+') {
+            return None;
+        }
+        let (name, params) = line.split_once(':')?;
+        Some((name, Params::new(params)))
+    }
+
+    /// The unread part, leading spaces removed.
+    pub fn rest(&self) -> &'a str {
+        self.rest.trim_start_matches(' ')
+    }
+
+    pub fn next_param(&mut self) -> Result<Option<Param<'a>>, ParamError> {
+        let rest = self.rest();
+        if rest.is_empty() {
+            self.rest = rest;
+            return Ok(None);
+        }
+
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or(ParamError::UnterminatedQuote)?;
+            let after = quoted[end + 1..].trim_start_matches(' ');
+            self.rest = match after.strip_prefix(',') {
+                Some(after) => after,
+                None if after.trim_end().is_empty() => "",
+                None => return Err(ParamError::ExpectedComma),
+            };
+            return Ok(Some(Param::Quoted(&quoted[..end])));
+        }
+
+        let (value, after) = rest.split_once(',').unwrap_or((rest, ""));
+        self.rest = after;
+        Ok(Some(Param::Unquoted(value.trim())))
+    }
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = Result<Param<'a>, ParamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_param().transpose()
+    }
+}
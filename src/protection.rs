@@ -0,0 +1,371 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::emon::Measurement;
+
+/// Why the protection stopped the load.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TripReason {
+    OverVoltage,
+    UnderVoltage,
+    OverCurrent,
+    /// Power below the threshold while ON, a pump running without water.
+    DryRun,
+    Frequency,
+}
+
+impl TripReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TripReason::OverVoltage => "over-voltage",
+            TripReason::UnderVoltage => "under-voltage",
+            TripReason::OverCurrent => "over-current",
+            TripReason::DryRun => "dry-run",
+            TripReason::Frequency => "frequency",
+        }
+    }
+
+    /// Conditions of the supply, which can be checked before the load is started again. The
+    /// others are faults of the load and stay latched until a command switches the relay.
+    fn is_supply(&self) -> bool {
+        matches!(
+            self,
+            TripReason::OverVoltage | TripReason::UnderVoltage | TripReason::Frequency
+        )
+    }
+}
+
+/// Thresholds the PZEM readings are checked against while the relay is ON. A `0` disables the
+/// check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProtectionLimits {
+    /// Volts.
+    pub over_voltage: u16,
+    /// Volts.
+    pub under_voltage: u16,
+    /// Milliamps.
+    pub over_current: u32,
+    /// Watts.
+    pub dry_run_power: u32,
+    /// Tenths of a hertz.
+    pub min_frequency: u16,
+    /// Tenths of a hertz.
+    pub max_frequency: u16,
+    /// Milliseconds a condition must hold before the relay trips.
+    pub debounce: u32,
+    /// Milliseconds after a supply trip until the load is started again, `0` to wait for a
+    /// command. Over-current and dry-run trips always wait for a command.
+    pub restart_delay: u32,
+}
+
+impl ProtectionLimits {
+    /// The first limit `measurement` violates, with the reading and the limit in the units of
+    /// [`Measurement`].
+    pub fn violation(&self, measurement: &Measurement) -> Option<(TripReason, f32, f32)> {
+        let m = measurement;
+        let over_voltage = self.over_voltage as f32;
+        let under_voltage = self.under_voltage as f32;
+        let over_current = self.over_current as f32 / 1000.0;
+        let dry_run_power = self.dry_run_power as f32;
+        let min_frequency = self.min_frequency as f32 / 10.0;
+        let max_frequency = self.max_frequency as f32 / 10.0;
+
+        if self.over_voltage > 0 && m.voltage > over_voltage {
+            return Some((TripReason::OverVoltage, m.voltage, over_voltage));
+        }
+        if self.under_voltage > 0 && m.voltage < under_voltage {
+            return Some((TripReason::UnderVoltage, m.voltage, under_voltage));
+        }
+        if self.min_frequency > 0 && m.frequency < min_frequency {
+            return Some((TripReason::Frequency, m.frequency, min_frequency));
+        }
+        if self.max_frequency > 0 && m.frequency > max_frequency {
+            return Some((TripReason::Frequency, m.frequency, max_frequency));
+        }
+        if self.over_current > 0 && m.current > over_current {
+            return Some((TripReason::OverCurrent, m.current, over_current));
+        }
+        if self.dry_run_power > 0 && m.power < dry_run_power {
+            return Some((TripReason::DryRun, m.power, dry_run_power));
+        }
+        None
+    }
+}
+
+/// A protection trip and the reading that caused it.
+#[derive(Debug, Clone, Copy)]
+pub struct Trip {
+    pub reason: TripReason,
+    pub value: f32,
+    pub limit: f32,
+    pub measurement: Measurement,
+    pub at: Instant,
+}
+
+impl Trip {
+    /// Payload for `RTONE/fault`:
+    /// `TRP:<reason>,<value>,<limit>,<voltage>,<current>,<power>,<frequency>`.
+    pub fn report(&self) -> String {
+        let m = &self.measurement;
+        format!(
+            "TRP:{},{},{},{},{},{},{}",
+            self.reason.as_str(),
+            self.value,
+            self.limit,
+            m.voltage,
+            m.current,
+            m.power,
+            m.frequency
+        )
+    }
+}
+
+/// Debounces limit violations into trips and times the restart after one.
+#[derive(Debug, Clone, Default)]
+pub struct Protection {
+    /// Condition seen on the last reading and since when.
+    pending: Option<(TripReason, Instant)>,
+    tripped: Option<Trip>,
+}
+
+impl Protection {
+    pub fn new() -> Self {
+        Protection::default()
+    }
+
+    /// Feeds a reading taken while the relay is ON. Returns the trip once the same condition
+    /// has held for the debounce time.
+    pub fn check(&mut self, limits: &ProtectionLimits, measurement: &Measurement) -> Option<Trip> {
+        let (reason, value, limit) = match limits.violation(measurement) {
+            Some(violation) => violation,
+            None => {
+                self.pending = None;
+                return None;
+            }
+        };
+
+        let since = match self.pending {
+            Some((pending, since)) if pending == reason => since,
+            _ => {
+                info!(
+                    "Protection: {} ({} against {})",
+                    reason.as_str(),
+                    value,
+                    limit
+                );
+                let now = Instant::now();
+                self.pending = Some((reason, now));
+                now
+            }
+        };
+        if since.elapsed() < Duration::from_millis(limits.debounce as u64) {
+            return None;
+        }
+
+        let trip = Trip {
+            reason,
+            value,
+            limit,
+            measurement: *measurement,
+            at: Instant::now(),
+        };
+        self.pending = None;
+        self.tripped = Some(trip);
+        Some(trip)
+    }
+
+    /// `true` once a supply trip is older than the restart delay. The restart still waits for
+    /// a reading within the supply limits, see [`Protection::restart`].
+    pub fn restart_pending(&self, limits: &ProtectionLimits) -> bool {
+        match self.tripped {
+            Some(trip) if limits.restart_delay > 0 && trip.reason.is_supply() => {
+                trip.at.elapsed() >= Duration::from_millis(limits.restart_delay as u64)
+            }
+            _ => false,
+        }
+    }
+
+    /// Clears the trip and returns it if the load can be started again on `measurement`. Only
+    /// the supply is checked, the load draws nothing while stopped.
+    pub fn restart(
+        &mut self,
+        limits: &ProtectionLimits,
+        measurement: &Measurement,
+    ) -> Option<Trip> {
+        if !self.restart_pending(limits) {
+            return None;
+        }
+        if let Some((reason, _, _)) = limits.violation(measurement) {
+            if reason.is_supply() {
+                return None;
+            }
+        }
+        self.tripped.take()
+    }
+
+    /// Forgets the trip, when the relay is switched by a command.
+    pub fn clear(&mut self) {
+        self.pending = None;
+        self.tripped = None;
+    }
+
+    pub fn tripped(&self) -> Option<&Trip> {
+        self.tripped.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ProtectionLimits {
+        ProtectionLimits {
+            over_voltage: 260,
+            under_voltage: 180,
+            over_current: 10000,
+            dry_run_power: 100,
+            min_frequency: 450,
+            max_frequency: 550,
+            debounce: 0,
+            restart_delay: 1000,
+        }
+    }
+
+    fn reading(voltage: f32, current: f32, power: f32, frequency: f32) -> Measurement {
+        Measurement {
+            voltage,
+            current,
+            power,
+            frequency,
+            ..Default::default()
+        }
+    }
+
+    /// Ages the trip past `limits().restart_delay`.
+    fn age_trip(protection: &mut Protection) {
+        let trip = protection.tripped.as_mut().unwrap();
+        trip.at -= Duration::from_millis(limits().restart_delay as u64);
+    }
+
+    #[test]
+    fn violation_reports_the_first_limit_broken() {
+        let limits = limits();
+        assert_eq!(limits.violation(&reading(230.0, 5.0, 1000.0, 50.0)), None);
+        assert_eq!(
+            limits.violation(&reading(270.0, 5.0, 1000.0, 50.0)),
+            Some((TripReason::OverVoltage, 270.0, 260.0))
+        );
+        assert_eq!(
+            limits.violation(&reading(170.0, 5.0, 1000.0, 50.0)),
+            Some((TripReason::UnderVoltage, 170.0, 180.0))
+        );
+        assert_eq!(
+            limits.violation(&reading(230.0, 5.0, 1000.0, 44.0)),
+            Some((TripReason::Frequency, 44.0, 45.0))
+        );
+        assert_eq!(
+            limits.violation(&reading(230.0, 11.0, 1000.0, 50.0)),
+            Some((TripReason::OverCurrent, 11.0, 10.0))
+        );
+        assert_eq!(
+            limits.violation(&reading(230.0, 0.2, 40.0, 50.0)),
+            Some((TripReason::DryRun, 40.0, 100.0))
+        );
+        // The supply is checked before the load.
+        assert_eq!(
+            limits
+                .violation(&reading(170.0, 11.0, 40.0, 50.0))
+                .map(|x| x.0),
+            Some(TripReason::UnderVoltage)
+        );
+    }
+
+    #[test]
+    fn zero_disables_a_limit() {
+        let limits = ProtectionLimits {
+            over_voltage: 0,
+            dry_run_power: 0,
+            ..limits()
+        };
+        assert_eq!(limits.violation(&reading(300.0, 0.0, 0.0, 50.0)), None);
+    }
+
+    #[test]
+    fn trips_only_after_the_debounce_time() {
+        let limits = ProtectionLimits {
+            debounce: 60_000,
+            ..limits()
+        };
+        let mut protection = Protection::new();
+        let high = reading(270.0, 5.0, 1000.0, 50.0);
+        assert!(protection.check(&limits, &high).is_none());
+
+        let (_, since) = protection.pending.as_mut().unwrap();
+        *since -= Duration::from_millis(limits.debounce as u64);
+        let trip = protection.check(&limits, &high).unwrap();
+        assert_eq!(trip.reason, TripReason::OverVoltage);
+        assert_eq!(
+            protection.tripped().map(|x| x.reason),
+            Some(TripReason::OverVoltage)
+        );
+    }
+
+    #[test]
+    fn a_good_reading_resets_the_debounce() {
+        let limits = ProtectionLimits {
+            debounce: 60_000,
+            ..limits()
+        };
+        let mut protection = Protection::new();
+        assert!(protection
+            .check(&limits, &reading(270.0, 5.0, 1000.0, 50.0))
+            .is_none());
+        assert!(protection
+            .check(&limits, &reading(230.0, 5.0, 1000.0, 50.0))
+            .is_none());
+        assert!(protection.pending.is_none());
+    }
+
+    #[test]
+    fn supply_trip_restarts_once_the_supply_is_back() {
+        let limits = limits();
+        let mut protection = Protection::new();
+        assert!(protection
+            .check(&limits, &reading(270.0, 5.0, 1000.0, 50.0))
+            .is_some());
+        let idle = reading(230.0, 0.0, 0.0, 50.0);
+        assert!(!protection.restart_pending(&limits));
+        assert!(protection.restart(&limits, &idle).is_none());
+
+        age_trip(&mut protection);
+        assert!(protection
+            .restart(&limits, &reading(270.0, 0.0, 0.0, 50.0))
+            .is_none());
+        let trip = protection.restart(&limits, &idle).unwrap();
+        assert_eq!(trip.reason, TripReason::OverVoltage);
+        assert!(protection.tripped().is_none());
+    }
+
+    #[test]
+    fn load_faults_stay_latched_until_cleared() {
+        let limits = limits();
+        let mut protection = Protection::new();
+        assert!(protection
+            .check(&limits, &reading(230.0, 0.2, 40.0, 50.0))
+            .is_some());
+
+        age_trip(&mut protection);
+        assert!(!protection.restart_pending(&limits));
+        assert!(protection
+            .restart(&limits, &reading(230.0, 0.0, 0.0, 50.0))
+            .is_none());
+        assert_eq!(
+            protection.tripped().map(|x| x.reason),
+            Some(TripReason::DryRun)
+        );
+
+        protection.clear();
+        assert!(protection.tripped().is_none());
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::VecDeque;
+
+use log::info;
+
+use crate::constants::{PUBLISH_MAX_ATTEMPTS, PUBLISH_QUEUE_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+/// What to give up when a message is queued while the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+}
+
+impl OverflowPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropOldest => "oldest",
+            OverflowPolicy::DropNewest => "newest",
+        }
+    }
+
+    pub fn from_str(policy: &str) -> Option<OverflowPolicy> {
+        match policy {
+            "oldest" => Some(OverflowPolicy::DropOldest),
+            "newest" => Some(OverflowPolicy::DropNewest),
+            _ => None,
+        }
+    }
+}
+
+/// `<result>` of `+QMTPUBEX: <client_idx>,<msgID>,<result>[,<value>]`, or the command failing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PublishResult {
+    /// 0: Sent, and acknowledged for QoS 1/2.
+    Sent,
+    /// 1: The module is retransmitting the packet.
+    Retransmission,
+    /// 2, `ERROR` or no answer.
+    Failed,
+}
+
+impl PublishResult {
+    pub fn from_result(result: u8) -> Self {
+        match result {
+            0 => PublishResult::Sent,
+            1 => PublishResult::Retransmission,
+            _ => PublishResult::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboundMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    /// `0` for QoS 0, as `AT+QMTPUBEX` requires.
+    pub msg_id: u16,
+    pub attempts: u8,
+}
+
+impl OutboundMessage {
+    /// `AT+QMTPUBEX=0,<msgID>,<qos>,<retain>,"<topic>",<msg_length>`
+    pub fn command(&self) -> String {
+        format!(
+            "AT+QMTPUBEX=0,{},{},{},\"{}\",{}\r\n",
+            self.msg_id,
+            self.qos as u8,
+            self.retain as u8,
+            self.topic,
+            self.payload.len()
+        )
+    }
+}
+
+/// Bounded FIFO of messages waiting to be published.
+///
+/// Messages go out one at a time through the `AT+QMTPUBEX` → `>` → payload → `+QMTPUBEX`
+/// cycle: [`PublishQueue::start`] takes the next one in flight and [`PublishQueue::on_result`]
+/// settles it. A QoS 1/2 message that fails is put back at the front until it has been tried
+/// `max_attempts` times; a QoS 0 message is dropped.
+#[derive(Debug, Clone)]
+pub struct PublishQueue {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    pub max_attempts: u8,
+    queue: VecDeque<OutboundMessage>,
+    in_flight: Option<OutboundMessage>,
+    next_msg_id: u16,
+}
+
+impl PublishQueue {
+    pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        PublishQueue {
+            capacity,
+            overflow,
+            max_attempts: PUBLISH_MAX_ATTEMPTS,
+            queue: VecDeque::new(),
+            in_flight: None,
+            next_msg_id: 1,
+        }
+    }
+
+    /// Queues a message, returning `false` if it was dropped because the queue is full.
+    pub fn push(&mut self, topic: String, payload: Vec<u8>, qos: QoS, retain: bool) -> bool {
+        if self.queue.len() >= self.capacity {
+            match self.overflow {
+                OverflowPolicy::DropNewest => {
+                    info!("Publish queue full, dropping message for {}", topic);
+                    return false;
+                }
+                OverflowPolicy::DropOldest => {
+                    if let Some(dropped) = self.queue.pop_front() {
+                        info!("Publish queue full, dropping message for {}", dropped.topic);
+                    }
+                }
+            }
+        }
+
+        let msg_id = match qos {
+            QoS::AtMostOnce => 0,
+            _ => self.next_msg_id(),
+        };
+        self.queue.push_back(OutboundMessage {
+            topic,
+            payload,
+            qos,
+            retain,
+            msg_id,
+            attempts: 0,
+        });
+        true
+    }
+
+    fn next_msg_id(&mut self) -> u16 {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.checked_add(1).unwrap_or(1);
+        msg_id
+    }
+
+    /// Takes the next message in flight, `None` if one already is or nothing is queued.
+    pub fn start(&mut self) -> Option<&OutboundMessage> {
+        if self.in_flight.is_some() {
+            return None;
+        }
+        let mut message = self.queue.pop_front()?;
+        message.attempts += 1;
+        self.in_flight = Some(message);
+        self.in_flight.as_ref()
+    }
+
+    pub fn in_flight(&self) -> Option<&OutboundMessage> {
+        self.in_flight.as_ref()
+    }
+
+    /// Settles the message in flight, returning its message id if it was sent.
+    pub fn on_result(&mut self, result: PublishResult) -> Option<u16> {
+        let message = self.in_flight.take()?;
+
+        match result {
+            PublishResult::Sent => {
+                info!("Published {} ({})", message.topic, message.msg_id);
+                return Some(message.msg_id);
+            }
+            _ if message.qos != QoS::AtMostOnce && message.attempts < self.max_attempts => {
+                info!(
+                    "Publishing {} ({}) failed: {:?}, attempt {}/{}",
+                    message.topic, message.msg_id, result, message.attempts, self.max_attempts
+                );
+                self.queue.push_front(message);
+            }
+            _ => {
+                info!(
+                    "Publishing {} ({}) failed: {:?}, dropping",
+                    message.topic, message.msg_id, result
+                );
+            }
+        }
+        None
+    }
+
+    /// Puts the message in flight back at the front without counting the attempt, used when
+    /// the connection dropped underneath it.
+    pub fn requeue(&mut self) {
+        if let Some(mut message) = self.in_flight.take() {
+            message.attempts = message.attempts.saturating_sub(1);
+            self.queue.push_front(message);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl Default for PublishQueue {
+    fn default() -> Self {
+        PublishQueue::new(PUBLISH_QUEUE_LEN, OverflowPolicy::DropOldest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(queue: &mut PublishQueue, topic: &str, qos: QoS) -> bool {
+        queue.push(topic.to_string(), b"1".to_vec(), qos, false)
+    }
+
+    fn topics(queue: &mut PublishQueue) -> Vec<String> {
+        let mut topics = Vec::new();
+        while let Some(message) = queue.start() {
+            topics.push(message.topic.clone());
+            queue.on_result(PublishResult::Sent);
+        }
+        topics
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest() {
+        let mut queue = PublishQueue::new(2, OverflowPolicy::DropOldest);
+        assert!(push(&mut queue, "a", QoS::AtLeastOnce));
+        assert!(push(&mut queue, "b", QoS::AtLeastOnce));
+        assert!(push(&mut queue, "c", QoS::AtLeastOnce));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(topics(&mut queue), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn full_queue_drops_the_newest() {
+        let mut queue = PublishQueue::new(2, OverflowPolicy::DropNewest);
+        assert!(push(&mut queue, "a", QoS::AtLeastOnce));
+        assert!(push(&mut queue, "b", QoS::AtLeastOnce));
+        assert!(!push(&mut queue, "c", QoS::AtLeastOnce));
+        assert_eq!(topics(&mut queue), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn qos1_is_retried_on_result_1_and_2() {
+        let mut queue = PublishQueue::new(4, OverflowPolicy::DropOldest);
+        queue.max_attempts = 3;
+        push(&mut queue, "a", QoS::AtLeastOnce);
+        let msg_id = queue.start().unwrap().msg_id;
+        assert_ne!(msg_id, 0);
+
+        assert_eq!(queue.on_result(PublishResult::from_result(1)), None);
+        assert_eq!(
+            queue.start().map(|x| (x.msg_id, x.attempts)),
+            Some((msg_id, 2))
+        );
+        assert_eq!(queue.on_result(PublishResult::from_result(2)), None);
+        assert_eq!(queue.start().map(|x| x.attempts), Some(3));
+
+        // Out of attempts.
+        assert_eq!(queue.on_result(PublishResult::from_result(2)), None);
+        assert!(queue.start().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn qos0_is_not_retried() {
+        let mut queue = PublishQueue::new(4, OverflowPolicy::DropOldest);
+        push(&mut queue, "a", QoS::AtMostOnce);
+        assert_eq!(queue.start().map(|x| x.msg_id), Some(0));
+        assert_eq!(queue.on_result(PublishResult::Failed), None);
+        assert!(queue.start().is_none());
+    }
+
+    #[test]
+    fn requeue_does_not_count_the_attempt() {
+        let mut queue = PublishQueue::new(4, OverflowPolicy::DropOldest);
+        push(&mut queue, "a", QoS::AtLeastOnce);
+        queue.start();
+        queue.requeue();
+        assert_eq!(queue.start().map(|x| x.attempts), Some(1));
+        assert_eq!(queue.on_result(PublishResult::Sent), Some(1));
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::atcommands::AtCommand;
+use crate::constants::{
+    AT_MAX_RETRIES, AT_RECONNECT_BACKOFF, AT_RECONNECT_MAX_BACKOFF, AT_RETRY_BACKOFF,
+    AT_RETRY_MAX_BACKOFF,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Times a command is re-sent after a timeout before escalating.
+    pub max_retries: u8,
+    /// Delay before the first retry in milliseconds, doubled on every further attempt.
+    pub backoff: u64,
+    pub max_backoff: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: AT_MAX_RETRIES,
+            backoff: AT_RETRY_BACKOFF,
+            max_backoff: AT_RETRY_MAX_BACKOFF,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Escalation {
+    /// Send the command again once [`RetryState::due`] returns it.
+    Retry(AtCommand),
+    /// Retries are exhausted, start over with `STATUS_COMMAND_SEQUENCE`.
+    RestartSequence,
+    /// The module did not recover after restarting the sequence either.
+    HardwareReset,
+}
+
+/// Tracks timeouts and errors of the command in flight and decides what to do about them.
+///
+/// A command that times out or ends in `ERROR` is retried up to `max_retries` times with exponential backoff. After
+/// that the status sequence is re-run once; if the same command times out again, the module is
+/// reset through its power key.
+#[derive(Debug, Clone)]
+pub struct RetryState {
+    pub policy: RetryPolicy,
+    command: Option<AtCommand>,
+    attempts: u8,
+    escalated: Option<AtCommand>,
+    scheduled: Option<(AtCommand, Instant)>,
+    /// A module reset that could not be carried out yet.
+    reset_pending: bool,
+}
+
+impl RetryState {
+    pub fn new(policy: RetryPolicy) -> Self {
+        RetryState {
+            policy,
+            command: None,
+            attempts: 0,
+            escalated: None,
+            scheduled: None,
+            reset_pending: false,
+        }
+    }
+
+    pub fn on_timeout(&mut self, command: AtCommand) -> Escalation {
+        self.on_failure(command, "timed out")
+    }
+
+    /// An `ERROR` result counts against the same retries as a timeout.
+    pub fn on_error(&mut self, command: AtCommand) -> Escalation {
+        self.on_failure(command, "failed")
+    }
+
+    fn on_failure(&mut self, command: AtCommand, what: &str) -> Escalation {
+        if self.command != Some(command) {
+            self.command = Some(command);
+            self.attempts = 0;
+        }
+        self.attempts += 1;
+
+        if self.attempts <= self.policy.max_retries {
+            let delay = self
+                .policy
+                .backoff
+                .saturating_mul(1 << (self.attempts - 1).min(16))
+                .min(self.policy.max_backoff);
+            info!(
+                "{:?} {}, retry {}/{} in {}ms",
+                command, what, self.attempts, self.policy.max_retries, delay
+            );
+            self.schedule(command, delay);
+            return Escalation::Retry(command);
+        }
+
+        self.command = None;
+        self.attempts = 0;
+        if self.escalated == Some(command) {
+            info!("{:?} still {}, resetting the module", command, what);
+            self.escalated = None;
+            return Escalation::HardwareReset;
+        }
+
+        info!(
+            "{:?} retries exhausted, restarting status sequence",
+            command
+        );
+        self.escalated = Some(command);
+        Escalation::RestartSequence
+    }
+
+    pub fn on_success(&mut self, command: AtCommand) {
+        if self.command == Some(command) {
+            self.command = None;
+            self.attempts = 0;
+        }
+        if self.escalated == Some(command) {
+            self.escalated = None;
+        }
+    }
+
+    /// Sends `command` once `delay` milliseconds have passed.
+    pub fn schedule(&mut self, command: AtCommand, delay: u64) {
+        self.scheduled = Some((command, Instant::now() + Duration::from_millis(delay)));
+    }
+
+    pub fn cancel(&mut self) {
+        self.scheduled = None;
+    }
+
+    /// Asks for the [`Escalation::HardwareReset`] to be tried again, see [`RetryState::take_reset`].
+    pub fn defer_reset(&mut self) {
+        self.reset_pending = true;
+    }
+
+    /// `true` once after [`RetryState::defer_reset`].
+    pub fn take_reset(&mut self) -> bool {
+        core::mem::take(&mut self.reset_pending)
+    }
+
+    /// Takes the scheduled command if its time has come.
+    pub fn due(&mut self) -> Option<AtCommand> {
+        match self.scheduled {
+            Some((command, at)) if Instant::now() >= at => {
+                self.scheduled = None;
+                Some(command)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for RetryState {
+    fn default() -> Self {
+        RetryState::new(RetryPolicy::default())
+    }
+}
+
+/// Delay between attempts to get back to the broker after it dropped the connection, doubled on
+/// every failed attempt up to `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct ReconnectBackoff {
+    /// Delay before the first attempt in milliseconds.
+    pub backoff: u64,
+    pub max_backoff: u64,
+    attempts: u32,
+}
+
+impl ReconnectBackoff {
+    pub fn new(backoff: u64, max_backoff: u64) -> Self {
+        ReconnectBackoff {
+            backoff,
+            max_backoff,
+            attempts: 0,
+        }
+    }
+
+    /// Delay before the next attempt, counting it.
+    pub fn next_delay(&mut self) -> u64 {
+        let delay = self
+            .backoff
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(self.max_backoff);
+        self.attempts += 1;
+        delay
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        ReconnectBackoff::new(AT_RECONNECT_BACKOFF, AT_RECONNECT_MAX_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalates_after_retries_then_resets() {
+        let mut retry = RetryState::new(RetryPolicy {
+            max_retries: 1,
+            backoff: 10,
+            max_backoff: 10,
+        });
+        assert_eq!(
+            retry.on_timeout(AtCommand::QMTOPEN),
+            Escalation::Retry(AtCommand::QMTOPEN)
+        );
+        assert_eq!(
+            retry.on_timeout(AtCommand::QMTOPEN),
+            Escalation::RestartSequence
+        );
+        assert_eq!(
+            retry.on_timeout(AtCommand::QMTOPEN),
+            Escalation::Retry(AtCommand::QMTOPEN)
+        );
+        assert_eq!(
+            retry.on_timeout(AtCommand::QMTOPEN),
+            Escalation::HardwareReset
+        );
+    }
+
+    #[test]
+    fn errors_and_timeouts_share_the_retries() {
+        let mut retry = RetryState::new(RetryPolicy {
+            max_retries: 1,
+            backoff: 10,
+            max_backoff: 10,
+        });
+        assert_eq!(
+            retry.on_error(AtCommand::SIMInit),
+            Escalation::Retry(AtCommand::SIMInit)
+        );
+        assert_eq!(
+            retry.on_timeout(AtCommand::SIMInit),
+            Escalation::RestartSequence
+        );
+    }
+
+    #[test]
+    fn deferred_reset_is_taken_once() {
+        let mut retry = RetryState::default();
+        assert!(!retry.take_reset());
+        retry.defer_reset();
+        assert!(retry.take_reset());
+        assert!(!retry.take_reset());
+    }
+}
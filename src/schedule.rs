@@ -0,0 +1,376 @@
+use log::info;
+
+use crate::constants::SCHEDULE_MAX_WINDOWS;
+use crate::controller::ControllerState;
+use crate::settings::{self, SettingValue, SettingsError, SettingsStorage};
+
+// Kept next to the settings, see `settings::SETTINGS_NAMESPACE`.
+const KEY_SCHEDULE: &str = "schedule";
+const KEY_OVERRIDE: &str = "sched_override";
+
+/// Bytes of a stored [`Window`]: id, days, start and end.
+const WINDOW_LEN: usize = 1 + 1 + 2 + 2;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const DAILY: u8 = 0x7f;
+const WEEKDAYS: u8 = 0x1f;
+const WEEKENDS: u8 = 0x60;
+
+/// A weekly time window the relay is ON in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub id: u8,
+    /// Bit 0 is Monday, bit 6 Sunday.
+    pub days: u8,
+    /// Minutes after local midnight.
+    pub start: u16,
+    /// Minutes after local midnight. Before `start` when the window runs past midnight, it then
+    /// ends on the following day.
+    pub end: u16,
+}
+
+impl Window {
+    /// Parses `<days> <HH:MM>-<HH:MM>`, where days is `daily`, `weekdays`, `weekends` or a list
+    /// such as `mon,wed,fri`: `weekdays 06:00-06:45`.
+    pub fn parse(id: u8, spec: &str) -> Result<Window, &'static str> {
+        let (days, time) = spec
+            .trim()
+            .split_once(' ')
+            .ok_or("Expected <days> <HH:MM>-<HH:MM>")?;
+        let days = match days.trim() {
+            "daily" => DAILY,
+            "weekdays" => WEEKDAYS,
+            "weekends" => WEEKENDS,
+            list => list.split(',').try_fold(0u8, |mask, day| {
+                match DAY_NAMES.iter().position(|x| *x == day.trim()) {
+                    Some(i) => Ok(mask | 1 << i),
+                    None => Err("Unknown day"),
+                }
+            })?,
+        };
+
+        let (start, end) = time
+            .trim()
+            .split_once('-')
+            .ok_or("Expected <HH:MM>-<HH:MM>")?;
+        let start = Window::parse_time(start).ok_or("Invalid start time")?;
+        let end = Window::parse_time(end).ok_or("Invalid end time")?;
+        if start == end {
+            return Err("Empty window");
+        }
+
+        Ok(Window {
+            id,
+            days,
+            start,
+            end,
+        })
+    }
+
+    fn parse_time(time: &str) -> Option<u16> {
+        let (hour, minute) = time.trim().split_once(':')?;
+        let (hour, minute) = (hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?);
+        if hour > 23 || minute > 59 {
+            return None;
+        }
+        Some(hour * 60 + minute)
+    }
+
+    /// `true` if the window is open at `minute` of the day `weekday` (0 is Monday).
+    pub fn contains(&self, weekday: u8, minute: u16) -> bool {
+        let on = |day: u8| self.days & (1 << (day % 7)) != 0;
+        if self.start < self.end {
+            return on(weekday) && self.start <= minute && minute < self.end;
+        }
+        (on(weekday) && minute >= self.start) || (on(weekday + 6) && minute < self.end)
+    }
+
+    /// The spec [`Window::parse`] accepts, `weekdays 06:00-06:45`.
+    pub fn spec(&self) -> String {
+        let days = match self.days {
+            DAILY => "daily".to_string(),
+            WEEKDAYS => "weekdays".to_string(),
+            WEEKENDS => "weekends".to_string(),
+            days => DAY_NAMES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| days & (1 << i) != 0)
+                .map(|(_, x)| *x)
+                .collect::<Vec<&str>>()
+                .join(","),
+        };
+        format!(
+            "{} {:02}:{:02}-{:02}:{:02}",
+            days,
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// The windows, persisted as one blob.
+impl SettingValue for Vec<Window> {
+    fn encode(&self) -> Vec<u8> {
+        self.iter()
+            .flat_map(|x| {
+                let mut bytes = [0u8; WINDOW_LEN];
+                bytes[0] = x.id;
+                bytes[1] = x.days;
+                bytes[2..4].copy_from_slice(&x.start.to_le_bytes());
+                bytes[4..6].copy_from_slice(&x.end.to_le_bytes());
+                bytes
+            })
+            .collect()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() % WINDOW_LEN != 0 {
+            return None;
+        }
+        Some(
+            bytes
+                .chunks_exact(WINDOW_LEN)
+                .map(|x| Window {
+                    id: x[0],
+                    days: x[1],
+                    start: u16::from_le_bytes([x[2], x[3]]),
+                    end: u16::from_le_bytes([x[4], x[5]]),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The opening of a window a manual command overrode, so the override outlives a reboot
+/// inside that window but not the next opening.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Suppressed {
+    pub window: u8,
+    /// Local time the window opened, in seconds since the epoch shifted by the UTC offset.
+    pub opened: u64,
+}
+
+impl SettingValue for Suppressed {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.window];
+        bytes.extend_from_slice(&self.opened.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (&window, opened) = bytes.split_first()?;
+        Some(Suppressed {
+            window,
+            opened: u64::from_le_bytes(opened.try_into().ok()?),
+        })
+    }
+}
+
+/// Switches the relay on the weekly windows, from the device clock so it keeps running while
+/// the broker is unreachable.
+///
+/// The relay is switched on the edges only: ON when a window opens, OFF when the last open one
+/// closes. A manual command overrides the schedule until the next window opens; one given
+/// while a window is open is persisted as [`Suppressed`].
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    windows: Vec<Window>,
+    /// Whether a window was open on the last tick, `None` before the first one.
+    active: Option<bool>,
+    overridden: bool,
+    suppressed: Option<Suppressed>,
+}
+
+impl Scheduler {
+    pub fn load(storage: &dyn SettingsStorage) -> Scheduler {
+        let windows: Vec<Window> = settings::read(storage, KEY_SCHEDULE).unwrap_or_default();
+        if !windows.is_empty() {
+            info!("{} schedule windows", windows.len());
+        }
+        Scheduler {
+            windows,
+            active: None,
+            overridden: false,
+            suppressed: settings::read(storage, KEY_OVERRIDE),
+        }
+    }
+
+    pub fn save(&self, storage: &mut dyn SettingsStorage) -> Result<(), SettingsError> {
+        settings::write(storage, KEY_SCHEDULE, &self.windows)
+    }
+
+    pub fn suppressed(&self) -> Option<Suppressed> {
+        self.suppressed
+    }
+
+    pub fn save_override(&self, storage: &mut dyn SettingsStorage) -> Result<(), SettingsError> {
+        match &self.suppressed {
+            Some(suppressed) => settings::write(storage, KEY_OVERRIDE, suppressed),
+            None => storage.remove(KEY_OVERRIDE),
+        }
+    }
+
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+
+    /// Adds the window `spec` under the lowest free id.
+    pub fn add(&mut self, spec: &str) -> Result<Window, &'static str> {
+        if self.windows.len() >= SCHEDULE_MAX_WINDOWS {
+            return Err("Schedule full");
+        }
+        let id = (1..=u8::MAX)
+            .find(|id| self.windows.iter().all(|x| x.id != *id))
+            .ok_or("Schedule full")?;
+        let window = Window::parse(id, spec)?;
+        self.windows.push(window);
+        Ok(window)
+    }
+
+    pub fn delete(&mut self, id: u8) -> Result<Window, &'static str> {
+        let index = self
+            .windows
+            .iter()
+            .position(|x| x.id == id)
+            .ok_or("Unknown window")?;
+        Ok(self.windows.remove(index))
+    }
+
+    /// Puts back a window [`Scheduler::delete`] removed.
+    pub fn restore(&mut self, window: Window) {
+        self.windows.push(window);
+    }
+
+    /// Called when the relay is switched by hand, at local time `local` if the clock is set.
+    pub fn set_override(&mut self, local: Option<u64>) {
+        if !self.windows.is_empty() {
+            info!("Schedule overridden until the next window");
+        }
+        self.overridden = true;
+        self.suppressed = local.and_then(|x| self.open_window(x));
+    }
+
+    /// The first window open at `local` and when it opened.
+    fn open_window(&self, local: u64) -> Option<Suppressed> {
+        let (weekday, minute) = weekday_minute(local);
+        let window = self.windows.iter().find(|x| x.contains(weekday, minute))?;
+        let midnight = local - local % 86400;
+        let opened = midnight + window.start as u64 * 60;
+        // Opened the day before when it runs past midnight.
+        let opened = match minute < window.start {
+            true => opened.checked_sub(86400)?,
+            false => opened,
+        };
+        Some(Suppressed {
+            window: window.id,
+            opened,
+        })
+    }
+
+    /// The state to switch to at local time `local` (seconds since the epoch, shifted by the
+    /// UTC offset), if any. `by_schedule` tells whether the schedule made the last change, so
+    /// a window that closed while the device was down still switches the relay off.
+    ///
+    /// On the first tick a persisted override still holds if the window it suppressed is the
+    /// one open now.
+    pub fn tick(&mut self, local: u64, by_schedule: bool) -> Option<ControllerState> {
+        let (weekday, minute) = weekday_minute(local);
+        let active = self.windows.iter().any(|x| x.contains(weekday, minute));
+
+        let previous = self.active.replace(active);
+        if previous.is_none() && self.suppressed.is_some() {
+            if self.suppressed == self.open_window(local) {
+                info!("Schedule still overridden after the restart");
+                self.overridden = true;
+            } else {
+                self.suppressed = None;
+            }
+        }
+        match (previous, active) {
+            (Some(previous), active) if previous == active => None,
+            (Some(_), true) => {
+                self.overridden = false;
+                self.suppressed = None;
+                Some(ControllerState::ON)
+            }
+            (None, true) if self.overridden => None,
+            (None, true) => Some(ControllerState::ON),
+            (_, false) if self.overridden => None,
+            (Some(_), false) => Some(ControllerState::OFF),
+            (None, false) if by_schedule => Some(ControllerState::OFF),
+            (None, false) => None,
+        }
+    }
+}
+
+/// Day of the week (0 is Monday) and minute of the day of local time `local`.
+fn weekday_minute(local: u64) -> (u8, u16) {
+    // 1970-01-01 was a Thursday.
+    (((local / 86400 + 3) % 7) as u8, (local % 86400 / 60) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::MemoryStorage;
+
+    /// Monday 2024-01-01 at `hour`:`minute`.
+    fn monday(hour: u64, minute: u64) -> u64 {
+        1_704_067_200 + hour * 3600 + minute * 60
+    }
+
+    fn scheduler(storage: &mut MemoryStorage) -> Scheduler {
+        let mut scheduler = Scheduler::load(storage);
+        scheduler.add("daily 06:00-07:00").unwrap();
+        scheduler.add("mon 23:00-01:00").unwrap();
+        scheduler.save(storage).unwrap();
+        scheduler
+    }
+
+    #[test]
+    fn override_survives_a_restart_inside_its_window() {
+        let mut storage = MemoryStorage::new();
+        let mut before = scheduler(&mut storage);
+        assert_eq!(before.tick(monday(6, 0), false), Some(ControllerState::ON));
+        before.set_override(Some(monday(6, 10)));
+        before.save_override(&mut storage).unwrap();
+
+        let mut after = Scheduler::load(&storage);
+        assert_eq!(after.tick(monday(6, 20), false), None);
+        assert_eq!(after.tick(monday(7, 0), false), None);
+        assert_eq!(after.tick(monday(23, 0), false), Some(ControllerState::ON));
+        assert_eq!(after.suppressed(), None);
+    }
+
+    #[test]
+    fn override_ends_with_its_window() {
+        let mut storage = MemoryStorage::new();
+        let mut before = scheduler(&mut storage);
+        before.set_override(Some(monday(6, 10)));
+        before.save_override(&mut storage).unwrap();
+
+        // Down until the next day's window.
+        let mut after = Scheduler::load(&storage);
+        assert_eq!(
+            after.tick(monday(24 + 6, 10), false),
+            Some(ControllerState::ON)
+        );
+        assert_eq!(after.suppressed(), None);
+    }
+
+    #[test]
+    fn window_past_midnight_opened_the_day_before() {
+        let mut storage = MemoryStorage::new();
+        let mut scheduler = scheduler(&mut storage);
+        scheduler.set_override(Some(monday(24, 30)));
+        assert_eq!(
+            scheduler.suppressed(),
+            Some(Suppressed {
+                window: 2,
+                opened: monday(23, 0),
+            })
+        );
+    }
+}
@@ -0,0 +1,1030 @@
+use core::fmt;
+use core::str::FromStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{
+    EspCustomNvsPartition, EspDefaultNvsPartition, EspNvs, NvsCustom, NvsDefault, NvsPartitionId,
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::EspError;
+use log::info;
+
+use crate::constants::{
+    ATREAD, ATREAD_RANGE, ATRESTART, ATRESTART_RANGE, ATSTATUS, ATSTATUS_RANGE, AUTH_SECRET,
+    RELAY_CHANGE_DELAY, RELAY_MAX_RUN, RELAY_OFF_CURRENT, RELAY_ON_CURRENT, RELAY_VERIFY_DELAY,
+    TRIP_DEBOUNCE, TRIP_MAX_FREQUENCY, TRIP_MIN_FREQUENCY, TRIP_OVER_CURRENT, TRIP_OVER_VOLTAGE,
+    TRIP_UNDER_VOLTAGE,
+};
+use crate::controller::RestorePolicy;
+use crate::emon;
+use crate::encoder::MeasurementFormat;
+use crate::mqtt::MqttConfig;
+use crate::protection::ProtectionLimits;
+use crate::publish::OverflowPolicy;
+use crate::retry::RetryPolicy;
+use crate::topics::{self, DEFAULT_PUBLISH_TEMPLATE, DEFAULT_SUBSCRIBE_TEMPLATE};
+
+pub const SETTINGS_NAMESPACE: &str = "atcontroller";
+/// Layout version of the stored settings. Only bumped when a stored key changes meaning or
+/// encoding: keys added since version 1 (the relay, verification, protection, schedule, clock,
+/// auth and retry settings) need no migration, as a key missing from storage loads with its
+/// default.
+pub const SCHEMA_VERSION: u16 = 1;
+pub const DEFAULT_TOPIC_PREFIX: &str = "RTONE";
+pub const DEFAULT_PZEM_THRESHOLD: u16 = 2300;
+pub const MIN_AUTH_SECRET_LEN: usize = 16;
+
+// NVS keys are limited to 15 characters.
+const KEY_VERSION: &str = "version";
+const KEY_MQTT_HOST: &str = "mqtt_host";
+const KEY_MQTT_PORT: &str = "mqtt_port";
+const KEY_MQTT_CLIENT: &str = "mqtt_client";
+const KEY_MQTT_USER: &str = "mqtt_user";
+const KEY_MQTT_PASS: &str = "mqtt_pass";
+const KEY_MQTT_KEEPALIVE: &str = "mqtt_keepalive";
+const KEY_MQTT_CLEAN: &str = "mqtt_clean";
+const KEY_TOPIC_PREFIX: &str = "topic_prefix";
+const KEY_PUBLISH_TEMPLATE: &str = "pub_template";
+const KEY_SUBSCRIBE_TEMPLATE: &str = "sub_template";
+const KEY_PUBLISH_OVERFLOW: &str = "pub_overflow";
+const KEY_MEASUREMENT_FORMAT: &str = "meas_format";
+const KEY_PZEM_ADDR: &str = "pzem_addr";
+const KEY_PZEM_THRESHOLD: &str = "pzem_threshold";
+const KEY_RELAY_DELAY: &str = "relay_delay";
+const KEY_RELAY_RESTORE: &str = "relay_restore";
+const KEY_MAX_RUN: &str = "max_run_min";
+const KEY_VERIFY_DELAY: &str = "verify_ms";
+const KEY_VERIFY_ON_CURRENT: &str = "verify_on_ma";
+const KEY_VERIFY_OFF_CURRENT: &str = "verify_off_ma";
+const KEY_TRIP_OVER_VOLTAGE: &str = "trip_over_v";
+const KEY_TRIP_UNDER_VOLTAGE: &str = "trip_under_v";
+const KEY_TRIP_OVER_CURRENT: &str = "trip_over_ma";
+const KEY_TRIP_DRY_RUN: &str = "trip_dry_w";
+const KEY_TRIP_MIN_FREQUENCY: &str = "trip_min_dhz";
+const KEY_TRIP_MAX_FREQUENCY: &str = "trip_max_dhz";
+const KEY_TRIP_DEBOUNCE: &str = "trip_debounce";
+const KEY_TRIP_RESTART: &str = "trip_restart";
+const KEY_STATUS_INTERVAL: &str = "status_ms";
+const KEY_READ_INTERVAL: &str = "read_ms";
+const KEY_RESTART_INTERVAL: &str = "restart_ms";
+const KEY_NTP_SERVER: &str = "ntp_server";
+const KEY_UTC_OFFSET: &str = "utc_offset";
+const KEY_AUTH_SECRET: &str = "auth_secret";
+const KEY_AT_RETRIES: &str = "at_retries";
+const KEY_AT_BACKOFF: &str = "at_backoff_ms";
+const KEY_AT_MAX_BACKOFF: &str = "at_backoff_max";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingsError {
+    Storage(String),
+    Invalid(&'static str),
+    UnknownKey(String),
+    /// A setting that cannot be changed over MQTT.
+    LocalOnly(&'static str),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Storage(e) => write!(f, "Settings storage error: {}", e),
+            SettingsError::Invalid(key) => write!(f, "Invalid value for {}", key),
+            SettingsError::UnknownKey(key) => write!(f, "Unknown setting {}", key),
+            SettingsError::LocalOnly(key) => write!(f, "{} can only be set locally", key),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+#[cfg(target_os = "espidf")]
+impl From<EspError> for SettingsError {
+    fn from(e: EspError) -> Self {
+        SettingsError::Storage(e.to_string())
+    }
+}
+
+/// Key/value store the settings are persisted in.
+pub trait SettingsStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, SettingsError>;
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), SettingsError>;
+    fn remove(&mut self, key: &str) -> Result<(), SettingsError>;
+}
+
+/// Storage handle shared between the main loop and the handlers that change settings.
+pub type SharedStorage = Arc<Mutex<dyn SettingsStorage + Send>>;
+
+/// Values kept in an NVS partition, the settings in the default `nvs` one.
+#[cfg(target_os = "espidf")]
+pub struct NvsStorage<P: NvsPartitionId = NvsDefault> {
+    nvs: EspNvs<P>,
+}
+
+#[cfg(target_os = "espidf")]
+impl NvsStorage {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, SettingsError> {
+        let nvs = EspNvs::new(partition, SETTINGS_NAMESPACE, true)?;
+        Ok(NvsStorage { nvs })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl NvsStorage<NvsCustom> {
+    /// `namespace` of a partition other than `nvs`, see `part.csv`.
+    pub fn custom(
+        partition: EspCustomNvsPartition,
+        namespace: &str,
+    ) -> Result<Self, SettingsError> {
+        let nvs = EspNvs::new(partition, namespace, true)?;
+        Ok(NvsStorage { nvs })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl<P: NvsPartitionId> SettingsStorage for NvsStorage<P> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, SettingsError> {
+        let len = match self.nvs.blob_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buffer = vec![0u8; len];
+        Ok(self.nvs.get_raw(key, &mut buffer)?.map(|x| x.to_vec()))
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), SettingsError> {
+        self.nvs.set_raw(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), SettingsError> {
+        self.nvs.remove(key)?;
+        Ok(())
+    }
+}
+
+/// Settings kept in RAM, for host builds.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    values: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            values: HashMap::new(),
+        }
+    }
+}
+
+impl SettingsStorage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, SettingsError> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), SettingsError> {
+        self.values.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), SettingsError> {
+        self.values.remove(key);
+        Ok(())
+    }
+}
+
+/// How a setting is laid out in storage.
+pub trait SettingValue: Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl SettingValue for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl SettingValue for bool {
+    fn encode(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! setting_value_int {
+    ($($t:ty),*) => {
+        $(
+            impl SettingValue for $t {
+                fn encode(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    Some(<$t>::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+setting_value_int!(u8, u16, u32, u64, i16);
+
+impl SettingValue for OverflowPolicy {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        OverflowPolicy::from_str(std::str::from_utf8(bytes).ok()?)
+    }
+}
+
+impl SettingValue for RestorePolicy {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        RestorePolicy::from_str(std::str::from_utf8(bytes).ok()?)
+    }
+}
+
+impl SettingValue for MeasurementFormat {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        MeasurementFormat::from_str(std::str::from_utf8(bytes).ok()?)
+    }
+}
+
+pub fn read<T: SettingValue>(storage: &dyn SettingsStorage, key: &str) -> Option<T> {
+    match storage.get(key) {
+        Ok(Some(bytes)) => {
+            let value = T::decode(&bytes);
+            if value.is_none() {
+                info!("Ignoring malformed setting {}", key);
+            }
+            value
+        }
+        Ok(None) => None,
+        Err(e) => {
+            info!("Error reading setting {}: {}", key, e);
+            None
+        }
+    }
+}
+
+pub fn write<T: SettingValue>(
+    storage: &mut dyn SettingsStorage,
+    key: &str,
+    value: &T,
+) -> Result<(), SettingsError> {
+    storage.set(key, &value.encode())
+}
+
+/// Device configuration that survives a reboot.
+///
+/// Every field falls back to its compiled-in default when it is missing from storage, so a blank
+/// partition and settings written by an older schema both load cleanly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub mqtt: MqttConfig,
+    pub topic_prefix: String,
+    /// Template of the reply topics, see [`topics::Topics`].
+    pub publish_template: String,
+    /// Template of the command topics, see [`topics::Topics`].
+    pub subscribe_template: String,
+    /// Which message a full publish queue gives up.
+    pub publish_overflow: OverflowPolicy,
+    /// Encoding of the power readings.
+    pub measurement_format: MeasurementFormat,
+    pub pzem_address: u8,
+    /// Power alarm threshold in watts. Only written to the sensor when changed remotely.
+    pub pzem_threshold: u16,
+    /// Length of the relay start/stop pulse in milliseconds.
+    pub relay_change_delay: u32,
+    /// What the relay is set to at boot.
+    pub relay_restore: RestorePolicy,
+    /// Minutes after which a started load is stopped even without a timed run, `0` for no
+    /// limit.
+    pub max_run: u32,
+    /// Milliseconds after a relay change until the PZEM reading must confirm it, `0` to not
+    /// check.
+    pub verify_delay: u32,
+    /// Current in mA a started load draws at least.
+    pub verify_on_current: u32,
+    /// Current in mA a stopped load draws at most.
+    pub verify_off_current: u32,
+    /// Limits the load is stopped on, see [`ProtectionLimits`].
+    pub protection: ProtectionLimits,
+    /// How AT commands that time out are retried, see [`RetryPolicy`].
+    pub retry: RetryPolicy,
+    /// Milliseconds between power reports.
+    pub status_interval: u64,
+    /// Milliseconds between UART reads.
+    pub read_interval: u64,
+    /// Milliseconds until the scheduled reboot.
+    pub restart_interval: u64,
+    /// Server `AT+QNTP` syncs the clock with. Empty to use the network time only.
+    pub ntp_server: String,
+    /// Minutes local time is ahead of UTC, for the schedule windows.
+    pub utc_offset: i16,
+    /// Key the control payloads are signed with, see [`crate::auth::Authenticator`]. Control
+    /// payloads are rejected while it is not set. Provisioned per device into NVS with
+    /// `scripts/provision.sh`, or built into the image with [`AUTH_SECRET`], which then wins;
+    /// never over MQTT, see [`Settings::check_remote`].
+    pub auth_secret: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            mqtt: MqttConfig::default(),
+            topic_prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            publish_template: DEFAULT_PUBLISH_TEMPLATE.to_string(),
+            subscribe_template: DEFAULT_SUBSCRIBE_TEMPLATE.to_string(),
+            publish_overflow: OverflowPolicy::DropOldest,
+            measurement_format: MeasurementFormat::Csv,
+            pzem_address: emon::ADDR_DEFAULT,
+            pzem_threshold: DEFAULT_PZEM_THRESHOLD,
+            relay_change_delay: RELAY_CHANGE_DELAY,
+            relay_restore: RestorePolicy::RestoreIfPlanned,
+            max_run: RELAY_MAX_RUN,
+            verify_delay: RELAY_VERIFY_DELAY,
+            verify_on_current: RELAY_ON_CURRENT,
+            verify_off_current: RELAY_OFF_CURRENT,
+            protection: ProtectionLimits {
+                over_voltage: TRIP_OVER_VOLTAGE,
+                under_voltage: TRIP_UNDER_VOLTAGE,
+                over_current: TRIP_OVER_CURRENT,
+                dry_run_power: 0,
+                min_frequency: TRIP_MIN_FREQUENCY,
+                max_frequency: TRIP_MAX_FREQUENCY,
+                debounce: TRIP_DEBOUNCE,
+                restart_delay: 0,
+            },
+            retry: RetryPolicy::default(),
+            status_interval: ATSTATUS,
+            read_interval: ATREAD,
+            restart_interval: ATRESTART,
+            ntp_server: String::new(),
+            utc_offset: 0,
+            auth_secret: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Refuses the keys that cannot be set over MQTT: an unsigned config could otherwise set
+    /// the key the other messages are checked with.
+    pub fn check_remote(key: &str) -> Result<(), SettingsError> {
+        match key {
+            KEY_AUTH_SECRET => Err(SettingsError::LocalOnly(KEY_AUTH_SECRET)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn load(storage: &dyn SettingsStorage) -> Settings {
+        Settings::load_with_secret(storage, AUTH_SECRET)
+    }
+
+    /// The secret is read on its own, so one provisioned into an otherwise empty partition is
+    /// used, and `compiled` replaces it when the image was built with one.
+    fn load_with_secret(storage: &dyn SettingsStorage, compiled: Option<&str>) -> Settings {
+        let mut settings = Settings::load_stored(storage);
+        settings.auth_secret = match compiled {
+            Some(secret) => Some(secret.to_string()),
+            None => read::<String>(storage, KEY_AUTH_SECRET).filter(|x| !x.is_empty()),
+        };
+        if let Err(e) = settings.validate() {
+            info!("Ignoring the auth secret: {}", e);
+            settings.auth_secret = None;
+        }
+        settings
+    }
+
+    fn load_stored(storage: &dyn SettingsStorage) -> Settings {
+        let defaults = Settings::default();
+
+        match read::<u16>(storage, KEY_VERSION) {
+            None => {
+                info!("No stored settings, using defaults");
+                return defaults;
+            }
+            Some(version) if version > SCHEMA_VERSION => {
+                info!(
+                    "Stored settings schema {} is newer than {}, using defaults",
+                    version, SCHEMA_VERSION
+                );
+                return defaults;
+            }
+            Some(version) => {
+                info!("Loading settings schema {}", version);
+            }
+        }
+
+        let optional = |key: &str, default: Option<String>| match read::<String>(storage, key) {
+            Some(value) if value.is_empty() => None,
+            Some(value) => Some(value),
+            None => default,
+        };
+
+        let mqtt = MqttConfig {
+            host: read(storage, KEY_MQTT_HOST).unwrap_or(defaults.mqtt.host.clone()),
+            port: read(storage, KEY_MQTT_PORT).unwrap_or(defaults.mqtt.port),
+            client_id: read(storage, KEY_MQTT_CLIENT).unwrap_or(defaults.mqtt.client_id.clone()),
+            username: optional(KEY_MQTT_USER, defaults.mqtt.username.clone()),
+            password: optional(KEY_MQTT_PASS, defaults.mqtt.password.clone()),
+            keepalive: read(storage, KEY_MQTT_KEEPALIVE).unwrap_or(defaults.mqtt.keepalive),
+            clean_session: read(storage, KEY_MQTT_CLEAN).unwrap_or(defaults.mqtt.clean_session),
+        };
+
+        let protection = ProtectionLimits {
+            over_voltage: read(storage, KEY_TRIP_OVER_VOLTAGE)
+                .unwrap_or(defaults.protection.over_voltage),
+            under_voltage: read(storage, KEY_TRIP_UNDER_VOLTAGE)
+                .unwrap_or(defaults.protection.under_voltage),
+            over_current: read(storage, KEY_TRIP_OVER_CURRENT)
+                .unwrap_or(defaults.protection.over_current),
+            dry_run_power: read(storage, KEY_TRIP_DRY_RUN)
+                .unwrap_or(defaults.protection.dry_run_power),
+            min_frequency: read(storage, KEY_TRIP_MIN_FREQUENCY)
+                .unwrap_or(defaults.protection.min_frequency),
+            max_frequency: read(storage, KEY_TRIP_MAX_FREQUENCY)
+                .unwrap_or(defaults.protection.max_frequency),
+            debounce: read(storage, KEY_TRIP_DEBOUNCE).unwrap_or(defaults.protection.debounce),
+            restart_delay: read(storage, KEY_TRIP_RESTART)
+                .unwrap_or(defaults.protection.restart_delay),
+        };
+
+        let retry = RetryPolicy {
+            max_retries: read(storage, KEY_AT_RETRIES).unwrap_or(defaults.retry.max_retries),
+            backoff: read(storage, KEY_AT_BACKOFF).unwrap_or(defaults.retry.backoff),
+            max_backoff: read(storage, KEY_AT_MAX_BACKOFF).unwrap_or(defaults.retry.max_backoff),
+        };
+
+        let settings = Settings {
+            mqtt,
+            topic_prefix: read(storage, KEY_TOPIC_PREFIX).unwrap_or(defaults.topic_prefix.clone()),
+            publish_template: read(storage, KEY_PUBLISH_TEMPLATE)
+                .unwrap_or(defaults.publish_template.clone()),
+            subscribe_template: read(storage, KEY_SUBSCRIBE_TEMPLATE)
+                .unwrap_or(defaults.subscribe_template.clone()),
+            publish_overflow: read(storage, KEY_PUBLISH_OVERFLOW)
+                .unwrap_or(defaults.publish_overflow),
+            measurement_format: read(storage, KEY_MEASUREMENT_FORMAT)
+                .unwrap_or(defaults.measurement_format),
+            pzem_address: read(storage, KEY_PZEM_ADDR).unwrap_or(defaults.pzem_address),
+            pzem_threshold: read(storage, KEY_PZEM_THRESHOLD).unwrap_or(defaults.pzem_threshold),
+            relay_change_delay: read(storage, KEY_RELAY_DELAY)
+                .unwrap_or(defaults.relay_change_delay),
+            relay_restore: read(storage, KEY_RELAY_RESTORE).unwrap_or(defaults.relay_restore),
+            max_run: read(storage, KEY_MAX_RUN).unwrap_or(defaults.max_run),
+            verify_delay: read(storage, KEY_VERIFY_DELAY).unwrap_or(defaults.verify_delay),
+            verify_on_current: read(storage, KEY_VERIFY_ON_CURRENT)
+                .unwrap_or(defaults.verify_on_current),
+            verify_off_current: read(storage, KEY_VERIFY_OFF_CURRENT)
+                .unwrap_or(defaults.verify_off_current),
+            protection,
+            retry,
+            status_interval: read(storage, KEY_STATUS_INTERVAL).unwrap_or(defaults.status_interval),
+            read_interval: read(storage, KEY_READ_INTERVAL).unwrap_or(defaults.read_interval),
+            restart_interval: read(storage, KEY_RESTART_INTERVAL)
+                .unwrap_or(defaults.restart_interval),
+            ntp_server: read(storage, KEY_NTP_SERVER).unwrap_or(defaults.ntp_server.clone()),
+            utc_offset: read(storage, KEY_UTC_OFFSET).unwrap_or(defaults.utc_offset),
+            auth_secret: None,
+        };
+
+        match settings.validate() {
+            Ok(()) => settings,
+            Err(e) => {
+                info!("Stored settings rejected ({}), using defaults", e);
+                defaults
+            }
+        }
+    }
+
+    pub fn save(&self, storage: &mut dyn SettingsStorage) -> Result<(), SettingsError> {
+        self.save_with_secret(storage, AUTH_SECRET)
+    }
+
+    /// A secret built into the image is not stored, so the provisioned one is back when an
+    /// image without it is flashed. No secret removes the key rather than storing `""`.
+    fn save_with_secret(
+        &self,
+        storage: &mut dyn SettingsStorage,
+        compiled: Option<&str>,
+    ) -> Result<(), SettingsError> {
+        self.validate()?;
+
+        write(storage, KEY_MQTT_HOST, &self.mqtt.host)?;
+        write(storage, KEY_MQTT_PORT, &self.mqtt.port)?;
+        write(storage, KEY_MQTT_CLIENT, &self.mqtt.client_id)?;
+        write(
+            storage,
+            KEY_MQTT_USER,
+            &self.mqtt.username.clone().unwrap_or_default(),
+        )?;
+        write(
+            storage,
+            KEY_MQTT_PASS,
+            &self.mqtt.password.clone().unwrap_or_default(),
+        )?;
+        write(storage, KEY_MQTT_KEEPALIVE, &self.mqtt.keepalive)?;
+        write(storage, KEY_MQTT_CLEAN, &self.mqtt.clean_session)?;
+        write(storage, KEY_TOPIC_PREFIX, &self.topic_prefix)?;
+        write(storage, KEY_PUBLISH_TEMPLATE, &self.publish_template)?;
+        write(storage, KEY_SUBSCRIBE_TEMPLATE, &self.subscribe_template)?;
+        write(storage, KEY_PUBLISH_OVERFLOW, &self.publish_overflow)?;
+        write(storage, KEY_MEASUREMENT_FORMAT, &self.measurement_format)?;
+        write(storage, KEY_PZEM_ADDR, &self.pzem_address)?;
+        write(storage, KEY_PZEM_THRESHOLD, &self.pzem_threshold)?;
+        write(storage, KEY_RELAY_DELAY, &self.relay_change_delay)?;
+        write(storage, KEY_RELAY_RESTORE, &self.relay_restore)?;
+        write(storage, KEY_MAX_RUN, &self.max_run)?;
+        write(storage, KEY_VERIFY_DELAY, &self.verify_delay)?;
+        write(storage, KEY_VERIFY_ON_CURRENT, &self.verify_on_current)?;
+        write(storage, KEY_VERIFY_OFF_CURRENT, &self.verify_off_current)?;
+        write(
+            storage,
+            KEY_TRIP_OVER_VOLTAGE,
+            &self.protection.over_voltage,
+        )?;
+        write(
+            storage,
+            KEY_TRIP_UNDER_VOLTAGE,
+            &self.protection.under_voltage,
+        )?;
+        write(
+            storage,
+            KEY_TRIP_OVER_CURRENT,
+            &self.protection.over_current,
+        )?;
+        write(storage, KEY_TRIP_DRY_RUN, &self.protection.dry_run_power)?;
+        write(
+            storage,
+            KEY_TRIP_MIN_FREQUENCY,
+            &self.protection.min_frequency,
+        )?;
+        write(
+            storage,
+            KEY_TRIP_MAX_FREQUENCY,
+            &self.protection.max_frequency,
+        )?;
+        write(storage, KEY_TRIP_DEBOUNCE, &self.protection.debounce)?;
+        write(storage, KEY_TRIP_RESTART, &self.protection.restart_delay)?;
+        write(storage, KEY_AT_RETRIES, &self.retry.max_retries)?;
+        write(storage, KEY_AT_BACKOFF, &self.retry.backoff)?;
+        write(storage, KEY_AT_MAX_BACKOFF, &self.retry.max_backoff)?;
+        write(storage, KEY_STATUS_INTERVAL, &self.status_interval)?;
+        write(storage, KEY_READ_INTERVAL, &self.read_interval)?;
+        write(storage, KEY_RESTART_INTERVAL, &self.restart_interval)?;
+        write(storage, KEY_NTP_SERVER, &self.ntp_server)?;
+        write(storage, KEY_UTC_OFFSET, &self.utc_offset)?;
+        match (compiled, &self.auth_secret) {
+            (Some(_), _) => {}
+            (None, Some(secret)) => write(storage, KEY_AUTH_SECRET, secret)?,
+            (None, None) => storage.remove(KEY_AUTH_SECRET)?,
+        }
+        // Written last, so an interrupted save still loads with the previous schema.
+        write(storage, KEY_VERSION, &SCHEMA_VERSION)
+    }
+
+    /// Drops everything stored, the next load returns the defaults.
+    pub fn reset(storage: &mut dyn SettingsStorage) -> Result<(), SettingsError> {
+        storage.remove(KEY_VERSION)
+    }
+
+    /// Changes one setting from its text form, the way it arrives over MQTT. Keys are the ones
+    /// used in storage. The result is not validated as a whole, see [`Settings::validate`].
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SettingsError> {
+        fn parse<T: FromStr>(key: &'static str, value: &str) -> Result<T, SettingsError> {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| SettingsError::Invalid(key))
+        }
+
+        fn parse_bool(key: &'static str, value: &str) -> Result<bool, SettingsError> {
+            match value.trim() {
+                "1" | "true" => Ok(true),
+                "0" | "false" => Ok(false),
+                _ => Err(SettingsError::Invalid(key)),
+            }
+        }
+
+        let optional = |value: &str| match value {
+            "" => None,
+            value => Some(value.to_string()),
+        };
+
+        match key {
+            KEY_MQTT_HOST => self.mqtt.host = value.to_string(),
+            KEY_MQTT_PORT => self.mqtt.port = parse(KEY_MQTT_PORT, value)?,
+            KEY_MQTT_CLIENT => self.mqtt.client_id = value.to_string(),
+            KEY_MQTT_USER => self.mqtt.username = optional(value),
+            KEY_MQTT_PASS => self.mqtt.password = optional(value),
+            KEY_MQTT_KEEPALIVE => self.mqtt.keepalive = parse(KEY_MQTT_KEEPALIVE, value)?,
+            KEY_MQTT_CLEAN => self.mqtt.clean_session = parse_bool(KEY_MQTT_CLEAN, value)?,
+            KEY_TOPIC_PREFIX => self.topic_prefix = value.to_string(),
+            KEY_PUBLISH_TEMPLATE => self.publish_template = value.to_string(),
+            KEY_SUBSCRIBE_TEMPLATE => self.subscribe_template = value.to_string(),
+            KEY_PUBLISH_OVERFLOW => {
+                self.publish_overflow = OverflowPolicy::from_str(value.trim())
+                    .ok_or(SettingsError::Invalid(KEY_PUBLISH_OVERFLOW))?
+            }
+            KEY_MEASUREMENT_FORMAT => {
+                self.measurement_format = MeasurementFormat::from_str(value.trim())
+                    .ok_or(SettingsError::Invalid(KEY_MEASUREMENT_FORMAT))?
+            }
+            KEY_PZEM_ADDR => self.pzem_address = parse(KEY_PZEM_ADDR, value)?,
+            KEY_PZEM_THRESHOLD => self.pzem_threshold = parse(KEY_PZEM_THRESHOLD, value)?,
+            KEY_RELAY_DELAY => self.relay_change_delay = parse(KEY_RELAY_DELAY, value)?,
+            KEY_RELAY_RESTORE => {
+                self.relay_restore = RestorePolicy::from_str(value.trim())
+                    .ok_or(SettingsError::Invalid(KEY_RELAY_RESTORE))?
+            }
+            KEY_MAX_RUN => self.max_run = parse(KEY_MAX_RUN, value)?,
+            KEY_VERIFY_DELAY => self.verify_delay = parse(KEY_VERIFY_DELAY, value)?,
+            KEY_VERIFY_ON_CURRENT => self.verify_on_current = parse(KEY_VERIFY_ON_CURRENT, value)?,
+            KEY_VERIFY_OFF_CURRENT => {
+                self.verify_off_current = parse(KEY_VERIFY_OFF_CURRENT, value)?
+            }
+            KEY_TRIP_OVER_VOLTAGE => {
+                self.protection.over_voltage = parse(KEY_TRIP_OVER_VOLTAGE, value)?
+            }
+            KEY_TRIP_UNDER_VOLTAGE => {
+                self.protection.under_voltage = parse(KEY_TRIP_UNDER_VOLTAGE, value)?
+            }
+            KEY_TRIP_OVER_CURRENT => {
+                self.protection.over_current = parse(KEY_TRIP_OVER_CURRENT, value)?
+            }
+            KEY_TRIP_DRY_RUN => self.protection.dry_run_power = parse(KEY_TRIP_DRY_RUN, value)?,
+            KEY_TRIP_MIN_FREQUENCY => {
+                self.protection.min_frequency = parse(KEY_TRIP_MIN_FREQUENCY, value)?
+            }
+            KEY_TRIP_MAX_FREQUENCY => {
+                self.protection.max_frequency = parse(KEY_TRIP_MAX_FREQUENCY, value)?
+            }
+            KEY_TRIP_DEBOUNCE => self.protection.debounce = parse(KEY_TRIP_DEBOUNCE, value)?,
+            KEY_TRIP_RESTART => self.protection.restart_delay = parse(KEY_TRIP_RESTART, value)?,
+            KEY_AT_RETRIES => self.retry.max_retries = parse(KEY_AT_RETRIES, value)?,
+            KEY_AT_BACKOFF => self.retry.backoff = parse(KEY_AT_BACKOFF, value)?,
+            KEY_AT_MAX_BACKOFF => self.retry.max_backoff = parse(KEY_AT_MAX_BACKOFF, value)?,
+            KEY_STATUS_INTERVAL => self.status_interval = parse(KEY_STATUS_INTERVAL, value)?,
+            KEY_READ_INTERVAL => self.read_interval = parse(KEY_READ_INTERVAL, value)?,
+            KEY_RESTART_INTERVAL => self.restart_interval = parse(KEY_RESTART_INTERVAL, value)?,
+            KEY_NTP_SERVER => self.ntp_server = value.trim().to_string(),
+            KEY_UTC_OFFSET => self.utc_offset = parse(KEY_UTC_OFFSET, value)?,
+            KEY_AUTH_SECRET => self.auth_secret = optional(value),
+            _ => return Err(SettingsError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Text form of one setting, the password and the auth secret are masked.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let value = match key {
+            KEY_MQTT_HOST => self.mqtt.host.clone(),
+            KEY_MQTT_PORT => self.mqtt.port.to_string(),
+            KEY_MQTT_CLIENT => self.mqtt.client_id.clone(),
+            KEY_MQTT_USER => self.mqtt.username.clone().unwrap_or_default(),
+            KEY_MQTT_PASS => self
+                .mqtt
+                .password
+                .as_ref()
+                .map_or("", |_| "***")
+                .to_string(),
+            KEY_MQTT_KEEPALIVE => self.mqtt.keepalive.to_string(),
+            KEY_MQTT_CLEAN => self.mqtt.clean_session.to_string(),
+            KEY_TOPIC_PREFIX => self.topic_prefix.clone(),
+            KEY_PUBLISH_TEMPLATE => self.publish_template.clone(),
+            KEY_SUBSCRIBE_TEMPLATE => self.subscribe_template.clone(),
+            KEY_PUBLISH_OVERFLOW => self.publish_overflow.as_str().to_string(),
+            KEY_MEASUREMENT_FORMAT => self.measurement_format.as_str().to_string(),
+            KEY_PZEM_ADDR => self.pzem_address.to_string(),
+            KEY_PZEM_THRESHOLD => self.pzem_threshold.to_string(),
+            KEY_RELAY_DELAY => self.relay_change_delay.to_string(),
+            KEY_RELAY_RESTORE => self.relay_restore.as_str().to_string(),
+            KEY_MAX_RUN => self.max_run.to_string(),
+            KEY_VERIFY_DELAY => self.verify_delay.to_string(),
+            KEY_VERIFY_ON_CURRENT => self.verify_on_current.to_string(),
+            KEY_VERIFY_OFF_CURRENT => self.verify_off_current.to_string(),
+            KEY_TRIP_OVER_VOLTAGE => self.protection.over_voltage.to_string(),
+            KEY_TRIP_UNDER_VOLTAGE => self.protection.under_voltage.to_string(),
+            KEY_TRIP_OVER_CURRENT => self.protection.over_current.to_string(),
+            KEY_TRIP_DRY_RUN => self.protection.dry_run_power.to_string(),
+            KEY_TRIP_MIN_FREQUENCY => self.protection.min_frequency.to_string(),
+            KEY_TRIP_MAX_FREQUENCY => self.protection.max_frequency.to_string(),
+            KEY_TRIP_DEBOUNCE => self.protection.debounce.to_string(),
+            KEY_TRIP_RESTART => self.protection.restart_delay.to_string(),
+            KEY_AT_RETRIES => self.retry.max_retries.to_string(),
+            KEY_AT_BACKOFF => self.retry.backoff.to_string(),
+            KEY_AT_MAX_BACKOFF => self.retry.max_backoff.to_string(),
+            KEY_STATUS_INTERVAL => self.status_interval.to_string(),
+            KEY_READ_INTERVAL => self.read_interval.to_string(),
+            KEY_RESTART_INTERVAL => self.restart_interval.to_string(),
+            KEY_NTP_SERVER => self.ntp_server.clone(),
+            KEY_UTC_OFFSET => self.utc_offset.to_string(),
+            KEY_AUTH_SECRET => self.auth_secret.as_ref().map_or("", |_| "***").to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if self.mqtt.validate().is_err() {
+            return Err(SettingsError::Invalid("mqtt"));
+        }
+        if self.topic_prefix.is_empty()
+            || self.topic_prefix.contains(['"', '+', '#'])
+            || self.topic_prefix.contains(char::is_control)
+            || self.topic_prefix.ends_with('/')
+        {
+            return Err(SettingsError::Invalid(KEY_TOPIC_PREFIX));
+        }
+        if !topics::is_valid_template(&self.publish_template) {
+            return Err(SettingsError::Invalid(KEY_PUBLISH_TEMPLATE));
+        }
+        if !topics::is_valid_template(&self.subscribe_template) {
+            return Err(SettingsError::Invalid(KEY_SUBSCRIBE_TEMPLATE));
+        }
+        if !emon::is_valid_address(self.pzem_address) {
+            return Err(SettingsError::Invalid(KEY_PZEM_ADDR));
+        }
+        if self.relay_change_delay == 0 {
+            return Err(SettingsError::Invalid(KEY_RELAY_DELAY));
+        }
+        if self.verify_delay > 0 && self.verify_off_current >= self.verify_on_current {
+            return Err(SettingsError::Invalid(KEY_VERIFY_OFF_CURRENT));
+        }
+        let protection = &self.protection;
+        if protection.over_voltage > 0 && protection.under_voltage >= protection.over_voltage {
+            return Err(SettingsError::Invalid(KEY_TRIP_UNDER_VOLTAGE));
+        }
+        if protection.max_frequency > 0 && protection.min_frequency >= protection.max_frequency {
+            return Err(SettingsError::Invalid(KEY_TRIP_MIN_FREQUENCY));
+        }
+        if self.retry.backoff == 0 {
+            return Err(SettingsError::Invalid(KEY_AT_BACKOFF));
+        }
+        if self.retry.max_backoff < self.retry.backoff {
+            return Err(SettingsError::Invalid(KEY_AT_MAX_BACKOFF));
+        }
+        if !ATSTATUS_RANGE.contains(&self.status_interval) {
+            return Err(SettingsError::Invalid(KEY_STATUS_INTERVAL));
+        }
+        if !ATREAD_RANGE.contains(&self.read_interval) {
+            return Err(SettingsError::Invalid(KEY_READ_INTERVAL));
+        }
+        if !ATRESTART_RANGE.contains(&self.restart_interval)
+            || self.restart_interval < self.status_interval
+        {
+            return Err(SettingsError::Invalid(KEY_RESTART_INTERVAL));
+        }
+        if self.ntp_server.contains(['"', ',', ' ']) || self.ntp_server.contains(char::is_control) {
+            return Err(SettingsError::Invalid(KEY_NTP_SERVER));
+        }
+        if !(-12 * 60..=14 * 60).contains(&self.utc_offset) {
+            return Err(SettingsError::Invalid(KEY_UTC_OFFSET));
+        }
+        if self
+            .auth_secret
+            .as_ref()
+            .is_some_and(|x| x.len() < MIN_AUTH_SECRET_LEN || x.contains(char::is_control))
+        {
+            return Err(SettingsError::Invalid(KEY_AUTH_SECRET));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changed() -> Settings {
+        let mut settings = Settings::default();
+        settings.set(KEY_MQTT_HOST, "broker.example.com").unwrap();
+        settings.set(KEY_MQTT_PASS, "secret").unwrap();
+        settings.set(KEY_RELAY_RESTORE, "last").unwrap();
+        settings.set(KEY_MAX_RUN, "45").unwrap();
+        settings.set(KEY_UTC_OFFSET, "330").unwrap();
+        settings
+    }
+
+    #[test]
+    fn blank_storage_loads_defaults() {
+        assert_eq!(Settings::load(&MemoryStorage::new()), Settings::default());
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut storage = MemoryStorage::new();
+        let settings = changed();
+        settings.save(&mut storage).unwrap();
+        assert_eq!(Settings::load(&storage), settings);
+        assert_eq!(read::<u16>(&storage, KEY_VERSION), Some(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn missing_keys_load_their_defaults() {
+        let mut storage = MemoryStorage::new();
+        write(&mut storage, KEY_VERSION, &1u16).unwrap();
+        write(&mut storage, KEY_MAX_RUN, &45u32).unwrap();
+
+        let settings = Settings::load(&storage);
+        assert_eq!(settings.max_run, 45);
+        assert_eq!(
+            Settings {
+                max_run: Settings::default().max_run,
+                ..settings
+            },
+            Settings::default()
+        );
+    }
+
+    #[test]
+    fn newer_schema_loads_defaults() {
+        let mut storage = MemoryStorage::new();
+        changed().save(&mut storage).unwrap();
+        write(&mut storage, KEY_VERSION, &(SCHEMA_VERSION + 1)).unwrap();
+        assert_eq!(Settings::load(&storage), Settings::default());
+    }
+
+    #[test]
+    fn malformed_value_falls_back_to_its_default() {
+        let mut storage = MemoryStorage::new();
+        changed().save(&mut storage).unwrap();
+        storage.set(KEY_MAX_RUN, b"x").unwrap();
+        let settings = Settings::load(&storage);
+        assert_eq!(settings.max_run, Settings::default().max_run);
+        assert_eq!(settings.mqtt.host, "broker.example.com");
+    }
+
+    #[test]
+    fn invalid_settings_are_not_saved() {
+        let mut storage = MemoryStorage::new();
+        let mut settings = Settings::default();
+        settings.set(KEY_RELAY_DELAY, "0").unwrap();
+        assert_eq!(
+            settings.save(&mut storage),
+            Err(SettingsError::Invalid(KEY_RELAY_DELAY))
+        );
+        assert_eq!(read::<u16>(&storage, KEY_VERSION), None);
+    }
+
+    #[test]
+    fn intervals_out_of_range_are_rejected() {
+        for (key, value) in [
+            (KEY_STATUS_INTERVAL, "999"),
+            (KEY_STATUS_INTERVAL, "3600001"),
+            (KEY_READ_INTERVAL, "99"),
+            (KEY_READ_INTERVAL, "10001"),
+            (KEY_RESTART_INTERVAL, "299999"),
+            (KEY_RESTART_INTERVAL, "604800001"),
+        ] {
+            let mut settings = Settings::default();
+            settings.set(key, value).unwrap();
+            assert_eq!(
+                settings.validate(),
+                Err(SettingsError::Invalid(key)),
+                "{}",
+                value
+            );
+        }
+
+        let mut settings = Settings::default();
+        settings.set(KEY_STATUS_INTERVAL, "1000").unwrap();
+        settings.set(KEY_READ_INTERVAL, "100").unwrap();
+        settings.set(KEY_RESTART_INTERVAL, "300000").unwrap();
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_stored_settings_load_defaults() {
+        let mut storage = MemoryStorage::new();
+        changed().save(&mut storage).unwrap();
+        write(&mut storage, KEY_RELAY_DELAY, &0u32).unwrap();
+        assert_eq!(Settings::load(&storage), Settings::default());
+    }
+
+    #[test]
+    fn reset_loads_defaults() {
+        let mut storage = MemoryStorage::new();
+        changed().save(&mut storage).unwrap();
+        Settings::reset(&mut storage).unwrap();
+        assert_eq!(Settings::load(&storage), Settings::default());
+    }
+
+    #[test]
+    fn rejects_line_breaks_and_control_characters() {
+        let keys = [
+            KEY_MQTT_HOST,
+            KEY_MQTT_CLIENT,
+            KEY_MQTT_USER,
+            KEY_MQTT_PASS,
+            KEY_TOPIC_PREFIX,
+            KEY_PUBLISH_TEMPLATE,
+            KEY_SUBSCRIBE_TEMPLATE,
+            KEY_NTP_SERVER,
+            KEY_AUTH_SECRET,
+        ];
+        for key in keys {
+            for bad in ["\r\nAT+QPOWD=1", "\n", "\u{7}"] {
+                let mut settings = Settings::default();
+                let value = match key {
+                    KEY_PUBLISH_TEMPLATE | KEY_SUBSCRIBE_TEMPLATE => format!("{{name}}{}x", bad),
+                    KEY_AUTH_SECRET => format!("0123456789abcdef{}x", bad),
+                    _ => format!("x{}y", bad),
+                };
+                settings.set(key, &value).unwrap();
+                assert!(settings.validate().is_err(), "{} accepted {:?}", key, value);
+            }
+        }
+    }
+
+    #[test]
+    fn set_and_get_by_key() {
+        let settings = changed();
+        assert_eq!(settings.get(KEY_MAX_RUN).as_deref(), Some("45"));
+        assert_eq!(settings.get(KEY_MQTT_PASS).as_deref(), Some("***"));
+        assert_eq!(settings.get("nope"), None);
+
+        let mut settings = settings;
+        assert_eq!(
+            settings.set("nope", "1"),
+            Err(SettingsError::UnknownKey("nope".to_string()))
+        );
+        assert_eq!(
+            settings.set(KEY_MAX_RUN, "many"),
+            Err(SettingsError::Invalid(KEY_MAX_RUN))
+        );
+    }
+
+    const PROVISIONED: &str = "provisioned-secret-0001";
+    const COMPILED: &str = "compiled-secret-0002";
+
+    #[test]
+    fn compiled_secret_wins_over_a_stored_empty_one() {
+        let mut storage = MemoryStorage::new();
+        write(&mut storage, KEY_AUTH_SECRET, &String::new()).unwrap();
+        Settings::default().save(&mut storage).unwrap();
+
+        assert_eq!(Settings::load_with_secret(&storage, None).auth_secret, None);
+        assert_eq!(
+            Settings::load_with_secret(&storage, Some(COMPILED)).auth_secret,
+            Some(COMPILED.to_string())
+        );
+    }
+
+    #[test]
+    fn provisioned_secret_is_used_without_other_settings() {
+        let mut storage = MemoryStorage::new();
+        write(&mut storage, KEY_AUTH_SECRET, &PROVISIONED.to_string()).unwrap();
+
+        let settings = Settings::load_with_secret(&storage, None);
+        assert_eq!(settings.auth_secret.as_deref(), Some(PROVISIONED));
+        assert_eq!(
+            Settings::load_with_secret(&storage, Some(COMPILED)).auth_secret,
+            Some(COMPILED.to_string())
+        );
+
+        // Saving under a compiled secret keeps the provisioned one for a later image.
+        let compiled = Settings::load_with_secret(&storage, Some(COMPILED));
+        compiled
+            .save_with_secret(&mut storage, Some(COMPILED))
+            .unwrap();
+        assert_eq!(
+            Settings::load_with_secret(&storage, None)
+                .auth_secret
+                .as_deref(),
+            Some(PROVISIONED)
+        );
+    }
+
+    #[test]
+    fn no_secret_is_not_stored_as_empty() {
+        let mut storage = MemoryStorage::new();
+        write(&mut storage, KEY_AUTH_SECRET, &PROVISIONED.to_string()).unwrap();
+        Settings::default()
+            .save_with_secret(&mut storage, None)
+            .unwrap();
+        assert_eq!(storage.get(KEY_AUTH_SECRET), Ok(None));
+    }
+}
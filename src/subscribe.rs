@@ -1,22 +1,255 @@
-use std::i32;
+use core::fmt;
 
 use log::info;
+use serde_json::Value;
+
+use crate::atres::ResponseHandlerResponse;
+use crate::auth::{AuthError, Authenticator};
+use crate::command::COMMAND_TOPIC;
+use crate::params::{Param, ParamError, Params};
+use crate::settings::SharedStorage;
+use crate::topics::Topics;
+
+/// Why a `+QMTRECV` URC could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecvError {
+    NotRecv,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+    Param(ParamError),
+    /// The payload between the quotes is not `<payload_len>` bytes long.
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::NotRecv => write!(f, "Not a +QMTRECV"),
+            RecvError::MissingField(field) => write!(f, "Missing {}", field),
+            RecvError::InvalidField(field) => write!(f, "Invalid {}", field),
+            RecvError::Param(e) => write!(f, "{}", e),
+            RecvError::LengthMismatch { expected, actual } => {
+                write!(f, "Payload is {} bytes, expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
 
 #[derive(Debug, Clone, Copy)]
 pub enum NextControlCommand {
     STATUSUPDATE,
     POWEROFF,
     POWERON,
+    /// `POWERON` for the given number of minutes.
+    POWERONFOR(u32),
+    /// A `SUBONE/start` with an invalid `minutes`, the payload is the reason.
+    POWERONREJECTED,
+    CONFIG,
+    /// A `SUBONE/cmd` payload, see [`crate::command::ControlRequest`].
+    COMMAND,
+    /// A message that failed authentication, the payload is `<topic>,<reason>`.
+    REJECTED,
+    SCHEDULEADD,
+    SCHEDULELIST,
+    SCHEDULEDELETE,
     NOOP,
 }
 
+/// Decides what a message received on a routed topic does.
+pub type MessageHandler = fn(&SubMessage) -> ResponseHandlerResponse;
+
+/// `true` if `topic` matches the MQTT topic `filter`, where `+` matches one level and a trailing
+/// `#` the remaining levels (including none).
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Minutes of a timed run, `{"minutes":30}`. An empty or non-JSON payload, or an object without
+/// `minutes`, starts the load untimed; a JSON payload that cannot be read or a `minutes` that
+/// is not a positive whole number is an error, so a typo never becomes an untimed run.
+pub fn run_minutes(payload: &str) -> Result<Option<u32>, &'static str> {
+    let payload = payload.trim();
+    if !payload.starts_with('{') {
+        return Ok(None);
+    }
+    let object = serde_json::from_str::<Value>(payload).map_err(|_| "Malformed JSON payload")?;
+    match object.get("minutes") {
+        None | Some(Value::Null) => Ok(None),
+        Some(minutes) => match minutes.as_u64().and_then(|x| u32::try_from(x).ok()) {
+            Some(minutes) if minutes > 0 => Ok(Some(minutes)),
+            _ => Err("minutes must be a positive whole number"),
+        },
+    }
+}
+
+/// Dispatches received messages to the handler registered for their topic.
+///
+/// Routes are registered by name relative to the subscribe template (`start`, `schedule/+`), so
+/// they follow the device topic namespace. The routes together are covered by one
+/// `AT+QMTSUB`, see [`TopicRouter::filters`].
+#[derive(Debug, Clone)]
+pub struct TopicRouter {
+    routes: Vec<(String, MessageHandler)>,
+    /// Verifies the payloads, every message is rejected until a secret is set.
+    auth: Option<Authenticator>,
+}
+
+impl TopicRouter {
+    pub fn new() -> Self {
+        TopicRouter {
+            routes: Vec::new(),
+            auth: None,
+        }
+    }
+
+    /// Verifies the payloads with `secret` from now on, keeping the accepted timestamps in
+    /// `storage`; `None` rejects them all. The remembered nonces are kept while the secret stays
+    /// the same.
+    pub fn set_secret(&mut self, secret: Option<&str>, storage: &SharedStorage) {
+        match secret {
+            Some(secret) if !self.auth.as_ref().is_some_and(|x| x.has_secret(secret)) => {
+                info!("Control payloads must be signed");
+                self.auth = Some(Authenticator::persistent(secret, storage.clone()));
+            }
+            Some(_) => {}
+            None => {
+                info!("No auth secret provisioned, rejecting control payloads");
+                self.auth = None;
+            }
+        }
+    }
+
+    pub fn register(&mut self, name: &str, handler: MessageHandler) {
+        self.routes.push((name.to_string(), handler));
+    }
+
+    /// Topic filters to subscribe to: a single `#` under the template when the name is its last
+    /// level, otherwise one filter per route.
+    pub fn filters(&self, topics: &Topics) -> Vec<String> {
+        if let Some(wildcard) = topics.wildcard() {
+            return vec![wildcard];
+        }
+        self.routes
+            .iter()
+            .map(|(name, _)| topics.subscribe(name))
+            .collect()
+    }
+
+    /// Authenticates `message` and hands it, with the signature removed from the payload, to the
+    /// handler of its topic.
+    pub fn dispatch(&self, message: &SubMessage, topics: &Topics) -> ResponseHandlerResponse {
+        let handler = match self.route(message.topic, topics) {
+            Some(handler) => handler,
+            None => {
+                info!("No route for {}", message.topic);
+                return ResponseHandlerResponse::noop();
+            }
+        };
+
+        let verified = match &self.auth {
+            Some(auth) => auth.verify(message.topic, message.payload.unwrap_or_default()),
+            None => Err(AuthError::Unprovisioned),
+        };
+        match verified {
+            Ok(body) => {
+                let message = SubMessage::new(
+                    message.client_id,
+                    message.msg_id,
+                    message.topic,
+                    Some(body.len() as i32),
+                    Some(body),
+                );
+                handler(&message)
+            }
+            Err(e) => {
+                info!("Rejecting message on {}: {}", message.topic, e);
+                let mut response = ResponseHandlerResponse::control(NextControlCommand::REJECTED);
+                response.payload = Some(format!("{},{}", message.topic, e.as_str()));
+                response
+            }
+        }
+    }
+
+    /// The handler of the first route matching `topic`.
+    pub fn route(&self, topic: &str, topics: &Topics) -> Option<MessageHandler> {
+        self.routes
+            .iter()
+            .find(|(name, _)| topic_matches(&topics.subscribe(name), topic))
+            .map(|(_, handler)| *handler)
+    }
+}
+
+impl Default for TopicRouter {
+    /// The relay, configuration and schedule commands.
+    fn default() -> Self {
+        let mut router = TopicRouter::new();
+        router.register("start", |message| {
+            match run_minutes(message.payload.unwrap_or_default()) {
+                Ok(Some(minutes)) => {
+                    ResponseHandlerResponse::control(NextControlCommand::POWERONFOR(minutes))
+                }
+                Ok(None) => ResponseHandlerResponse::control(NextControlCommand::POWERON),
+                Err(e) => {
+                    let mut response =
+                        ResponseHandlerResponse::control(NextControlCommand::POWERONREJECTED);
+                    response.payload = Some(e.to_string());
+                    response
+                }
+            }
+        });
+        router.register("end", |_| {
+            ResponseHandlerResponse::control(NextControlCommand::POWEROFF)
+        });
+        router.register("status", |_| {
+            ResponseHandlerResponse::control(NextControlCommand::STATUSUPDATE)
+        });
+        router.register("config", |message| {
+            let mut response = ResponseHandlerResponse::control(NextControlCommand::CONFIG);
+            response.payload = message.payload.map(|x| x.to_string());
+            response
+        });
+        router.register(COMMAND_TOPIC, |message| {
+            let mut response = ResponseHandlerResponse::control(NextControlCommand::COMMAND);
+            response.payload = Some(message.payload.unwrap_or_default().to_string());
+            response
+        });
+        router.register("schedule/add", |message| {
+            let mut response = ResponseHandlerResponse::control(NextControlCommand::SCHEDULEADD);
+            response.payload = message.payload.map(|x| x.to_string());
+            response
+        });
+        router.register("schedule/list", |_| {
+            ResponseHandlerResponse::control(NextControlCommand::SCHEDULELIST)
+        });
+        router.register("schedule/delete", |message| {
+            let mut response = ResponseHandlerResponse::control(NextControlCommand::SCHEDULEDELETE);
+            response.payload = message.payload.map(|x| x.to_string());
+            response
+        });
+        router
+    }
+}
+
 pub struct SubMessage<'a> {
     pub client_id: i32,
     pub msg_id: i32,
     pub topic: &'a str,
     pub payload_len: Option<i32>,
     pub payload: Option<&'a str>,
-    pub next_control_command: NextControlCommand,
 }
 
 impl<'a> SubMessage<'a> {
@@ -26,7 +259,6 @@ impl<'a> SubMessage<'a> {
         topic: &'a str,
         payload_len: Option<i32>,
         payload: Option<&'a str>,
-        control_command: NextControlCommand,
     ) -> Self {
         SubMessage {
             client_id,
@@ -34,83 +266,202 @@ impl<'a> SubMessage<'a> {
             topic,
             payload_len,
             payload,
-            next_control_command: control_command,
         }
     }
 
-    pub fn process_received_message(message: &[&'a str]) -> Result<Self, bool> {
-        let updated_message = message
-            .iter()
-            .filter(|x| !x.contains("MANA"))
-            .collect::<Vec<&&str>>();
+    /// Parses a `+QMTRECV` URC, `recv/mode` `0,0,1`:
+    ///
+    /// `+QMTRECV: <client_idx>,<msgID>,"<topic>",<payload_len>,"<payload>"`
+    ///
+    /// Exactly `<payload_len>` bytes are taken as the payload, so it may hold quotes, commas or
+    /// line breaks (see [`crate::framer::LineFramer`]). Without the length field (`0,0,0`) the
+    /// payload runs to the last quote. Topic and payload borrow from `urc`.
+    pub fn parse(urc: &'a str) -> Result<Self, RecvError> {
+        let (name, mut params) = Params::of(urc).ok_or(RecvError::NotRecv)?;
+        if name.trim() != "+QMTRECV" {
+            return Err(RecvError::NotRecv);
+        }
 
-        info!("Processing Received Message: {:?}", message);
-        let recv_split = match updated_message.first() {
-            Some(first_message) => first_message.split(':').collect::<Vec<&str>>(),
-            None => {
-                return Err(false);
-            }
+        let mut next = |field: &'static str| match params.next_param() {
+            Ok(Some(param)) => Ok(param),
+            Ok(None) => Err(RecvError::MissingField(field)),
+            Err(e) => Err(RecvError::Param(e)),
+        };
+        let client_id = next("client_idx")?
+            .parse::<i32>()
+            .ok_or(RecvError::InvalidField("client_idx"))?;
+        let msg_id = next("msgID")?
+            .parse::<i32>()
+            .ok_or(RecvError::InvalidField("msgID"))?;
+        let topic = match next("topic")? {
+            Param::Quoted(topic) => topic,
+            Param::Unquoted(_) => return Err(RecvError::InvalidField("topic")),
         };
 
-        if recv_split.len() == 0 {
-            return Err(false);
+        let rest = params.rest();
+        if rest.starts_with('"') {
+            // No length field, the payload is quoted up to the last quote.
+            let payload = rest
+                .trim_end()
+                .strip_prefix('"')
+                .and_then(|x| x.strip_suffix('"'))
+                .ok_or(RecvError::Param(ParamError::UnterminatedQuote))?;
+            return Ok(SubMessage::new(
+                client_id,
+                msg_id,
+                topic,
+                Some(payload.len() as i32),
+                Some(payload),
+            ));
         }
 
-        let message_parts = recv_split[1]
-            .split(",")
-            .map(|x| x.trim())
-            .collect::<Vec<&str>>();
-
-        if message_parts.len() < 3 {
-            info!("Invalid Message");
-            return Err(false);
+        let (len, rest) = rest
+            .split_once(',')
+            .ok_or(RecvError::MissingField("payload"))?;
+        let len = len
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| RecvError::InvalidField("payload_len"))?;
+        let rest = rest
+            .trim_start_matches(' ')
+            .strip_prefix('"')
+            .ok_or(RecvError::InvalidField("payload"))?;
+        // Compared in bytes, so a length ending inside a multibyte character is a mismatch too.
+        let payload = rest
+            .trim_end()
+            .strip_suffix('"')
+            .ok_or(RecvError::Param(ParamError::UnterminatedQuote))?;
+        if payload.len() != len {
+            return Err(RecvError::LengthMismatch {
+                expected: len,
+                actual: payload.len(),
+            });
         }
 
-        let client_id: i32 = match message_parts[0].parse::<i32>() {
-            Ok(id) => id,
-            Err(_) => return Err(false),
-        };
+        info!(
+            "Client ID: {}, Message ID: {}, Topic: {}, Payload Length: {}, Payload: {}",
+            client_id, msg_id, topic, len, payload
+        );
 
-        let msg_id: i32 = match message_parts[1].parse::<i32>() {
-            Ok(id) => id,
-            Err(_) => return Err(false),
-        };
+        Ok(SubMessage::new(
+            client_id,
+            msg_id,
+            topic,
+            Some(len as i32),
+            Some(payload),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framer::{Frame, LineFramer};
 
-        let topic = message_parts[2];
+    fn parsed(urc: &str) -> (i32, i32, &str, Option<i32>, Option<&str>) {
+        let message = SubMessage::parse(urc).unwrap();
+        (
+            message.client_id,
+            message.msg_id,
+            message.topic,
+            message.payload_len,
+            message.payload,
+        )
+    }
 
-        let next_command = match topic.replace("\"", "").as_str() {
-            "SUBONE/start" => NextControlCommand::POWERON,
-            "SUBONE/end" => NextControlCommand::POWEROFF,
-            "SUBONE/status" => NextControlCommand::STATUSUPDATE,
-            _ => NextControlCommand::NOOP,
+    #[test]
+    fn payload_with_separators_and_quotes() {
+        assert_eq!(
+            parsed(r#"+QMTRECV: 0,5,"SUBONE/config",15,"a,b:c="d",e:"f"""#),
+            (0, 5, "SUBONE/config", Some(15), Some(r#"a,b:c="d",e:"f""#))
+        );
+    }
+
+    #[test]
+    fn payload_with_line_break_split_across_pushes() {
+        let mut framer = LineFramer::new();
+        framer.push(b"\r\n+QMTRECV: 0,2,\"SUBONE/config\",25,\"status_ms=5");
+        assert_eq!(framer.next_frame(), None);
+        framer.push(b"000\r\nread_ms");
+        assert_eq!(framer.next_frame(), None);
+        framer.push(b"=1\"\r\n");
+        let line = match framer.next_frame() {
+            Some(Frame::Line(line)) => line,
+            frame => panic!("{:?}", frame),
         };
+        assert_eq!(
+            parsed(&line),
+            (
+                0,
+                2,
+                "SUBONE/config",
+                Some(25),
+                Some("status_ms=5000\r\nread_ms=1")
+            )
+        );
+    }
 
-        let mut message = SubMessage::new(client_id, msg_id, topic, None, None, next_command);
+    #[test]
+    fn payload_length_mismatch() {
+        assert_eq!(
+            SubMessage::parse(r#"+QMTRECV: 0,1,"SUBONE/start",5,"on""#).err(),
+            Some(RecvError::LengthMismatch {
+                expected: 5,
+                actual: 2
+            })
+        );
+    }
 
-        if message_parts.len() < 3 {
-            info!("Invalid Message");
-            return Err(false);
-        }
+    #[test]
+    fn payload_without_length_field() {
+        assert_eq!(
+            parsed(r#"+QMTRECV: 0,0,"SUBONE/config","a,b""#),
+            (0, 0, "SUBONE/config", Some(3), Some("a,b"))
+        );
+    }
 
-        if message_parts.len() == 3 {
-            info!(
-                "Client ID: {}, Message ID: {}, Topic: {}",
-                client_id, msg_id, topic
-            );
-            return Err(false);
+    #[test]
+    fn unterminated_quotes() {
+        let unterminated = Some(RecvError::Param(ParamError::UnterminatedQuote));
+        for urc in [
+            r#"+QMTRECV: 0,1,"SUBONE/start"#,
+            r#"+QMTRECV: 0,1,"SUBONE/start",2,"on"#,
+            r#"+QMTRECV: 0,1,"SUBONE/start","on"#,
+        ] {
+            assert_eq!(SubMessage::parse(urc).err(), unterminated, "{}", urc);
         }
+    }
 
-        let payload_lenght = message_parts[3];
-        let payload = message_parts[4];
-
-        info!(
-            "Client ID: {}, Message ID: {}, Topic: {}, Payload Length: {}, Payload: {}, next_control_command: {:?}",
-            client_id, msg_id, topic, payload_lenght, payload, next_command
+    #[test]
+    fn multibyte_payload_counted_in_bytes() {
+        assert_eq!(
+            parsed(r#"+QMTRECV: 0,3,"SUBONE/config",10,"name=café""#).4,
+            Some("name=café")
         );
+        // The length ends inside the `é`.
+        assert_eq!(
+            SubMessage::parse(r#"+QMTRECV: 0,3,"SUBONE/config",9,"name=café""#).err(),
+            Some(RecvError::LengthMismatch {
+                expected: 9,
+                actual: 10
+            })
+        );
+    }
 
-        message.payload_len = Some(payload_lenght.parse().unwrap());
-        message.payload = Some(payload);
-
-        return Ok(message);
+    #[test]
+    fn run_minutes_of_the_start_payload() {
+        assert_eq!(run_minutes(""), Ok(None));
+        assert_eq!(run_minutes("start"), Ok(None));
+        assert_eq!(run_minutes("{}"), Ok(None));
+        assert_eq!(run_minutes(r#" {"minutes":30} "#), Ok(Some(30)));
+        for payload in [
+            r#"{"minutes":0}"#,
+            r#"{"minutes":"30"}"#,
+            r#"{"minutes":-5}"#,
+            r#"{"minutes":1.5}"#,
+            r#"{"minutes":30"#,
+        ] {
+            assert!(run_minutes(payload).is_err(), "{}", payload);
+        }
     }
 }
@@ -0,0 +1,179 @@
+use crate::settings::Settings;
+
+pub const DEFAULT_PUBLISH_TEMPLATE: &str = "{prefix}/{name}";
+pub const DEFAULT_SUBSCRIBE_TEMPLATE: &str = "SUBONE/{name}";
+
+/// Rendered in place of `{imei}`/`{iccid}` until the module has reported them.
+const UNKNOWN_IDENTITY: &str = "unknown";
+
+const PLACEHOLDERS: [&str; 5] = ["{prefix}", "{imei}", "{iccid}", "{client}", "{name}"];
+
+/// Identifiers read from the module during the status sequence.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceIdentity {
+    /// `AT+GSN`
+    pub imei: Option<String>,
+    /// `AT+QCCID`
+    pub iccid: Option<String>,
+}
+
+/// `true` if `template` renders to a usable topic: it names the topic through `{name}`, uses
+/// only known placeholders and nothing that would break the quoted AT parameter or act as a
+/// wildcard.
+pub fn is_valid_template(template: &str) -> bool {
+    if !template.contains("{name}")
+        || template.contains(['"', '+', '#'])
+        || template.contains(char::is_control)
+    {
+        return false;
+    }
+
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let placeholder = match rest[start..].find('}') {
+            Some(end) => &rest[start..=start + end],
+            None => return false,
+        };
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return false;
+        }
+        rest = &rest[start + placeholder.len()..];
+    }
+    !rest.contains('}')
+}
+
+/// Topic names of this device, rendered from the templates in [`Settings`].
+///
+/// A template such as `{prefix}/{imei}/cmd/{name}` keeps devices sharing a broker apart. The
+/// defaults reproduce the fixed `SUBONE/<name>` and `RTONE/<name>` topics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topics {
+    prefix: String,
+    publish_template: String,
+    subscribe_template: String,
+    client_id: String,
+    identity: DeviceIdentity,
+}
+
+impl Topics {
+    pub fn new(settings: &Settings, identity: &DeviceIdentity) -> Self {
+        Topics {
+            prefix: settings.topic_prefix.clone(),
+            publish_template: settings.publish_template.clone(),
+            subscribe_template: settings.subscribe_template.clone(),
+            client_id: settings.mqtt.client_id.clone(),
+            identity: identity.clone(),
+        }
+    }
+
+    /// Topic the reply `name` (`status`, `Power`, ...) is published on.
+    pub fn publish(&self, name: &str) -> String {
+        self.render(&self.publish_template, name)
+    }
+
+    /// Topic the command `name` (`start`, `end`, ...) is received on.
+    pub fn subscribe(&self, name: &str) -> String {
+        self.render(&self.subscribe_template, name)
+    }
+
+    /// `<...>/#` covering every command topic, when `{name}` is the last level of the subscribe
+    /// template.
+    pub fn wildcard(&self) -> Option<String> {
+        let template = &self.subscribe_template;
+        if template.ends_with("/{name}") && template.matches("{name}").count() == 1 {
+            return Some(self.subscribe("#"));
+        }
+        None
+    }
+
+    fn render(&self, template: &str, name: &str) -> String {
+        template
+            .replace("{prefix}", &self.prefix)
+            .replace(
+                "{imei}",
+                self.identity.imei.as_deref().unwrap_or(UNKNOWN_IDENTITY),
+            )
+            .replace(
+                "{iccid}",
+                self.identity.iccid.as_deref().unwrap_or(UNKNOWN_IDENTITY),
+            )
+            .replace("{client}", &self.client_id)
+            .replace("{name}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::MqttConfig;
+
+    fn topics(publish: &str, subscribe: &str, identity: DeviceIdentity) -> Topics {
+        let settings = Settings {
+            topic_prefix: "PUMP1".to_string(),
+            publish_template: publish.to_string(),
+            subscribe_template: subscribe.to_string(),
+            mqtt: MqttConfig {
+                client_id: "farm".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        Topics::new(&settings, &identity)
+    }
+
+    #[test]
+    fn renders_every_placeholder() {
+        let identity = DeviceIdentity {
+            imei: Some("864792041234567".to_string()),
+            iccid: Some("89918580400012345678".to_string()),
+        };
+        let topics = topics(
+            "{prefix}/{imei}/{name}",
+            "{client}/{iccid}/{name}",
+            identity,
+        );
+        assert_eq!(topics.publish("status"), "PUMP1/864792041234567/status");
+        assert_eq!(topics.subscribe("start"), "farm/89918580400012345678/start");
+        assert_eq!(
+            topics.wildcard().as_deref(),
+            Some("farm/89918580400012345678/#")
+        );
+    }
+
+    #[test]
+    fn defaults_keep_the_fixed_topics() {
+        let topics = topics(
+            DEFAULT_PUBLISH_TEMPLATE,
+            DEFAULT_SUBSCRIBE_TEMPLATE,
+            DeviceIdentity::default(),
+        );
+        assert_eq!(topics.publish("Power"), "PUMP1/Power");
+        assert_eq!(topics.subscribe("end"), "SUBONE/end");
+    }
+
+    #[test]
+    fn identity_is_unknown_until_reported() {
+        let topics = topics("{imei}/{name}", "{name}/{iccid}", DeviceIdentity::default());
+        assert_eq!(topics.publish("status"), "unknown/status");
+        assert_eq!(topics.subscribe("start"), "start/unknown");
+        assert_eq!(topics.wildcard(), None);
+    }
+
+    #[test]
+    fn rejects_wildcards_and_broken_templates() {
+        assert!(is_valid_template("{prefix}/{imei}/{name}"));
+        for template in [
+            "+/{name}",
+            "{prefix}/#/{name}",
+            "{prefix}/{name}/#",
+            "{prefix}/\"{name}",
+            "{prefix}/\r\n{name}",
+            "{prefix}/status",
+            "{prefix}/{serial}/{name}",
+            "{prefix}/{name",
+            "{prefix}/name}",
+        ] {
+            assert!(!is_valid_template(template), "{}", template);
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use core::fmt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::atcommands::AtCommand;
+use crate::framer::{Frame, LineKind};
+
+pub type Line = String;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AtError {
+    /// Plain `ERROR` final result code.
+    Error,
+    /// `+CME ERROR: <err>`.
+    CmeError(u16),
+}
+
+impl fmt::Display for AtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AtError::Error => write!(f, "Module returned ERROR"),
+            AtError::CmeError(code) => write!(f, "Module returned +CME ERROR: {}", code),
+        }
+    }
+}
+
+impl std::error::Error for AtError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// Waiting for `OK`/`ERROR`.
+    Final,
+    /// `OK` received, waiting for the URC with this prefix.
+    Urc(&'static str),
+}
+
+#[derive(Debug, Clone)]
+struct PendingCommand {
+    command: AtCommand,
+    lines: Vec<Line>,
+    phase: Phase,
+    started: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The command in flight finished, with its information lines on success.
+    Completed(AtCommand, Result<Vec<Line>, AtError>),
+    /// The module asked for the data of the command in flight.
+    Prompt(AtCommand),
+}
+
+/// Matches incoming frames to the command that is actually waiting for them.
+///
+/// Every command sent gets a pending slot through [`Transactions::begin`]. Information lines for
+/// that command are collected until its final result code (or, for the MQTT commands, the URC
+/// carrying the real result) and then handed back as one [`Event::Completed`]. Anything else is
+/// queued as a URC and read with [`Transactions::next_urc`], so a `+QMTRECV` arriving between a
+/// command and its `OK` can no longer be mistaken for the reply.
+#[derive(Debug, Clone, Default)]
+pub struct Transactions {
+    pending: Option<PendingCommand>,
+    urcs: VecDeque<Line>,
+}
+
+impl Transactions {
+    pub fn new() -> Self {
+        Transactions {
+            pending: None,
+            urcs: VecDeque::new(),
+        }
+    }
+
+    /// Opens the slot for `command`, returning the command it replaced if one was still pending.
+    pub fn begin(&mut self, command: AtCommand) -> Option<AtCommand> {
+        let replaced = self.cancel();
+        if let Some(replaced) = replaced {
+            info!("Dropping pending {:?} for {:?}", replaced, command);
+        }
+        self.pending = Some(PendingCommand {
+            command,
+            lines: Vec::new(),
+            phase: Phase::Final,
+            started: Instant::now(),
+        });
+        replaced
+    }
+
+    pub fn cancel(&mut self) -> Option<AtCommand> {
+        self.pending.take().map(|x| x.command)
+    }
+
+    pub fn in_flight(&self) -> Option<AtCommand> {
+        self.pending.as_ref().map(|x| x.command)
+    }
+
+    /// Clears and returns the command in flight if it has been waiting longer than its
+    /// [`AtCommand::timeout`].
+    pub fn expired(&mut self) -> Option<AtCommand> {
+        let pending = self.pending.as_ref()?;
+        let timeout = pending.command.timeout();
+        if timeout == 0 || pending.started.elapsed() < Duration::from_millis(timeout) {
+            return None;
+        }
+        self.cancel()
+    }
+
+    pub fn next_urc(&mut self) -> Option<Line> {
+        self.urcs.pop_front()
+    }
+
+    pub fn feed(&mut self, frame: Frame) -> Option<Event> {
+        let line = match frame {
+            Frame::Prompt => {
+                return match &self.pending {
+                    Some(pending) => Some(Event::Prompt(pending.command)),
+                    None => {
+                        info!("Prompt without a command in flight");
+                        None
+                    }
+                };
+            }
+            Frame::Line(line) => line,
+        };
+
+        let pending = match self.pending.as_mut() {
+            Some(pending) => pending,
+            None => {
+                if LineKind::of(&line) == LineKind::Final {
+                    info!("Result code without a command in flight: {}", line);
+                } else {
+                    self.urcs.push_back(line);
+                }
+                return None;
+            }
+        };
+
+        match pending.phase {
+            Phase::Final => match LineKind::of(&line) {
+                LineKind::Final => {
+                    let result = match line.trim() {
+                        "OK" => Ok(()),
+                        "ERROR" => Err(AtError::Error),
+                        r => Err(AtError::CmeError(
+                            r.rsplit(':')
+                                .next()
+                                .unwrap_or("")
+                                .trim()
+                                .parse()
+                                .unwrap_or(0),
+                        )),
+                    };
+
+                    match (result, pending.command.result_urc()) {
+                        (Ok(()), Some(prefix)) => {
+                            pending.phase = Phase::Urc(prefix);
+                            None
+                        }
+                        (Ok(()), None) => self.complete(Ok(())),
+                        (Err(e), _) => self.complete(Err(e)),
+                    }
+                }
+                _ if pending
+                    .command
+                    .response_prefix()
+                    .is_some_and(|prefix| line.starts_with(prefix)) =>
+                {
+                    pending.lines.push(line);
+                    None
+                }
+                _ if line.starts_with('+') => {
+                    self.urcs.push_back(line);
+                    None
+                }
+                _ => {
+                    pending.lines.push(line);
+                    None
+                }
+            },
+            Phase::Urc(prefix) => {
+                if line.starts_with(prefix) {
+                    pending.lines.push(line);
+                    return self.complete(Ok(()));
+                }
+                self.urcs.push_back(line);
+                None
+            }
+        }
+    }
+
+    fn complete(&mut self, result: Result<(), AtError>) -> Option<Event> {
+        self.pending
+            .take()
+            .map(|pending| Event::Completed(pending.command, result.map(|_| pending.lines)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(line: &str) -> Frame {
+        Frame::Line(line.to_string())
+    }
+
+    #[test]
+    fn urc_between_echo_and_ok_is_queued() {
+        let mut transactions = Transactions::new();
+        transactions.begin(AtCommand::NetworkStrengthQuery);
+        assert_eq!(transactions.feed(line("AT+CSQ")), None);
+        assert_eq!(
+            transactions.feed(line("+QMTRECV: 0,1,\"a/b\",2,\"on\"")),
+            None
+        );
+        assert_eq!(transactions.feed(line("+CSQ: 24,99")), None);
+
+        assert_eq!(
+            transactions.feed(line("OK")),
+            Some(Event::Completed(
+                AtCommand::NetworkStrengthQuery,
+                Ok(vec!["AT+CSQ".to_string(), "+CSQ: 24,99".to_string()])
+            ))
+        );
+        assert_eq!(
+            transactions.next_urc().as_deref(),
+            Some("+QMTRECV: 0,1,\"a/b\",2,\"on\"")
+        );
+        assert_eq!(transactions.next_urc(), None);
+    }
+
+    #[test]
+    fn cme_error_ends_the_command_in_flight() {
+        let mut transactions = Transactions::new();
+        transactions.begin(AtCommand::QMTCONN);
+        assert_eq!(
+            transactions.feed(line("+CME ERROR: 30")),
+            Some(Event::Completed(
+                AtCommand::QMTCONN,
+                Err(AtError::CmeError(30))
+            ))
+        );
+        assert_eq!(transactions.in_flight(), None);
+    }
+
+    #[test]
+    fn result_urc_completes_after_ok() {
+        let mut transactions = Transactions::new();
+        transactions.begin(AtCommand::QMTOPEN);
+        assert_eq!(transactions.feed(line("OK")), None);
+        assert_eq!(transactions.feed(line("+QMTSTAT: 0,1")), None);
+        assert_eq!(transactions.in_flight(), Some(AtCommand::QMTOPEN));
+
+        assert_eq!(
+            transactions.feed(line("+QMTOPEN: 0,0")),
+            Some(Event::Completed(
+                AtCommand::QMTOPEN,
+                Ok(vec!["+QMTOPEN: 0,0".to_string()])
+            ))
+        );
+        assert_eq!(transactions.next_urc().as_deref(), Some("+QMTSTAT: 0,1"));
+    }
+
+    #[test]
+    fn expired_clears_the_command_after_its_timeout() {
+        let mut transactions = Transactions::new();
+        transactions.begin(AtCommand::NetworkStrengthQuery);
+        assert_eq!(transactions.expired(), None);
+
+        let timeout = Duration::from_millis(AtCommand::NetworkStrengthQuery.timeout());
+        transactions.pending.as_mut().unwrap().started -= timeout;
+        assert_eq!(
+            transactions.expired(),
+            Some(AtCommand::NetworkStrengthQuery)
+        );
+        assert_eq!(transactions.in_flight(), None);
+        assert_eq!(transactions.expired(), None);
+    }
+}
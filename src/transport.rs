@@ -0,0 +1,162 @@
+use core::fmt;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::uart::{AsyncUartDriver, UartDriver};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::EspError;
+
+/// Byte oriented link to the AT module.
+///
+/// `AT` only talks to the modem through this trait, so the same AT/MQTT logic can run on the
+/// ESP32 UART or on anything else that moves bytes (an in-memory pipe on a host build).
+#[allow(async_fn_in_trait)]
+pub trait AtTransport {
+    type Error: fmt::Debug;
+
+    /// Writes `bytes`, returning how many were accepted.
+    async fn write(&self, bytes: &[u8]) -> Result<usize, Self::Error>;
+
+    /// Reads whatever is available into `buffer`, waiting until at least one byte arrives.
+    /// `Ok(0)` means the other end is gone.
+    async fn read(&self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Waits until everything written so far has left the transmitter.
+    async fn wait_tx_done(&self) -> Result<(), Self::Error>;
+
+    /// Discards any received bytes that have not been read yet.
+    async fn flush(&self) -> Result<(), Self::Error>;
+}
+
+#[cfg(target_os = "espidf")]
+impl<'a> AtTransport for AsyncUartDriver<'a, UartDriver<'a>> {
+    type Error = EspError;
+
+    async fn write(&self, bytes: &[u8]) -> Result<usize, EspError> {
+        AsyncUartDriver::write(self, bytes).await
+    }
+
+    async fn read(&self, buffer: &mut [u8]) -> Result<usize, EspError> {
+        AsyncUartDriver::read(self, buffer).await
+    }
+
+    async fn wait_tx_done(&self) -> Result<(), EspError> {
+        AsyncUartDriver::wait_tx_done(self).await
+    }
+
+    async fn flush(&self) -> Result<(), EspError> {
+        self.driver().clear_rx()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipeError {
+    Closed,
+}
+
+impl fmt::Display for PipeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipeError::Closed => write!(f, "The other end of the pipe is closed"),
+        }
+    }
+}
+
+impl std::error::Error for PipeError {}
+
+#[derive(Debug, Default)]
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// One end of an in-memory, full duplex byte pipe.
+///
+/// Created in pairs with [`PipeTransport::pair`]: bytes written on one end are read on the other.
+/// Dropping an end closes it, after which the peer reads `Ok(0)` and writes fail.
+#[derive(Debug)]
+pub struct PipeTransport {
+    rx: Arc<Mutex<PipeBuffer>>,
+    tx: Arc<Mutex<PipeBuffer>>,
+}
+
+impl PipeTransport {
+    pub fn pair() -> (PipeTransport, PipeTransport) {
+        let a = Arc::new(Mutex::new(PipeBuffer::default()));
+        let b = Arc::new(Mutex::new(PipeBuffer::default()));
+        (
+            PipeTransport {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            PipeTransport { rx: b, tx: a },
+        )
+    }
+
+    /// Number of bytes waiting to be read on this end.
+    pub fn available(&self) -> usize {
+        self.rx.lock().unwrap().data.len()
+    }
+}
+
+impl AtTransport for PipeTransport {
+    type Error = PipeError;
+
+    async fn write(&self, bytes: &[u8]) -> Result<usize, PipeError> {
+        let mut tx = self.tx.lock().unwrap();
+        if tx.closed {
+            return Err(PipeError::Closed);
+        }
+        tx.data.extend(bytes);
+        if let Some(waker) = tx.waker.take() {
+            waker.wake();
+        }
+        Ok(bytes.len())
+    }
+
+    async fn read(&self, buffer: &mut [u8]) -> Result<usize, PipeError> {
+        poll_fn(|cx| {
+            let mut rx = self.rx.lock().unwrap();
+            if rx.data.is_empty() {
+                if rx.closed || buffer.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                rx.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let len = buffer.len().min(rx.data.len());
+            for (slot, byte) in buffer.iter_mut().zip(rx.data.drain(..len)) {
+                *slot = byte;
+            }
+            Poll::Ready(Ok(len))
+        })
+        .await
+    }
+
+    async fn wait_tx_done(&self) -> Result<(), PipeError> {
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), PipeError> {
+        self.rx.lock().unwrap().data.clear();
+        Ok(())
+    }
+}
+
+impl Drop for PipeTransport {
+    fn drop(&mut self) {
+        for side in [&self.rx, &self.tx] {
+            if let Ok(mut buffer) = side.lock() {
+                buffer.closed = true;
+                if let Some(waker) = buffer.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}